@@ -0,0 +1,109 @@
+extern crate csaparser;
+extern crate usiagent;
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::process;
+
+use usiagent::shogi::*;
+use usiagent::rule::BANMEN_START_POS;
+
+use csaparser::CsaData;
+use csaparser::CsaFileStream;
+use csaparser::CsaMoves;
+use csaparser::CsaParser;
+use csaparser::KifuInfo;
+use csaparser::TimeSettings;
+use csaparser::server::CsaServer;
+use csaparser::writer::CsaWriter;
+
+fn usage() -> ! {
+	eprintln!("usage: csaserver [-b address] [-p port] [-i initial.csa] [-t total+byoyomi+increment] [-m max_moves] [-n games] [-o output.csa]");
+	process::exit(2);
+}
+fn fail<E: std::fmt::Display>(e:E) -> ! {
+	eprintln!("{}",e);
+	process::exit(1);
+}
+fn main() {
+	let mut address = String::from("127.0.0.1");
+	let mut port = 4081;
+	let mut input = None;
+	let mut time = None;
+	let mut max_moves = None;
+	let mut games = 1;
+	let mut output = None;
+
+	let mut args = env::args().skip(1);
+
+	while let Some(a) = args.next() {
+		let value = args.next().unwrap_or_else(|| usage());
+
+		match a.as_str() {
+			"-b" => address = value,
+			"-p" => port = value.parse().unwrap_or_else(|_| usage()),
+			"-i" => input = Some(value),
+			"-t" => {
+				let mut t = TimeSettings::default();
+				t.parse(&value).unwrap_or_else(|e| fail(e));
+				time = Some(t);
+			},
+			"-m" => max_moves = Some(value.parse().unwrap_or_else(|_| usage())),
+			"-n" => games = value.parse().unwrap_or_else(|_| usage()),
+			"-o" => output = Some(value),
+			_ => usage(),
+		}
+	}
+
+	let mut data = match input {
+		Some(ref path) => {
+			let stream = CsaFileStream::new(path).unwrap_or_else(|e| fail(e));
+
+			CsaParser::new(stream).parse().unwrap_or_else(|e| fail(e)).into_iter().next().unwrap_or_else(|| {
+				fail("The input file contains no game.")
+			})
+		},
+		None => {
+			CsaData::new(Some(String::from("3.0")),
+						 None,
+						 Teban::Sente,
+						 BANMEN_START_POS.clone(),
+						 MochigomaCollections::Empty,
+						 CsaMoves::new(),None,Vec::new())
+		}
+	};
+
+	if time.is_some() || max_moves.is_some() {
+		let mut info = data.kifu_info.take().unwrap_or_else(KifuInfo::new);
+
+		info.time = time.or(info.time);
+		info.max_moves = max_moves.or(info.max_moves);
+
+		data.kifu_info = Some(info);
+	}
+
+	let mut server = CsaServer::bind((address.as_str(),port),data).unwrap_or_else(|e| fail(e));
+
+	eprintln!("listening on {}",server.local_addr().unwrap_or_else(|e| fail(e)));
+
+	let mut writer:Box<dyn Write> = match output {
+		Some(ref path) => {
+			Box::new(OpenOptions::new().create(true).write(true).truncate(true).open(path).unwrap_or_else(|e| fail(e)))
+		},
+		None => Box::new(io::stdout()),
+	};
+
+	for i in 0..games {
+		let record = server.run_game().unwrap_or_else(|e| fail(e));
+
+		// 複数の対局は/で区切って一つのファイルに書き出す
+		if i > 0 {
+			writeln!(writer,"/").unwrap_or_else(|e| fail(e));
+		}
+
+		CsaWriter::new(&mut writer).write(&record).unwrap_or_else(|e| fail(e));
+		writer.flush().unwrap_or_else(|e| fail(e));
+	}
+}
@@ -0,0 +1,778 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use usiagent::shogi::*;
+
+use CsaData;
+use CsaMovesParser;
+use CsaPositionParser;
+use EndState;
+use EngineComment;
+use EngineScore;
+use KifuInfo;
+use TimeSettings;
+use TryFromCsa;
+use parse_duration;
+use error::*;
+use writer::apply_move;
+use writer::move_to_csa;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct GameSummary {
+	pub protocol_version:Option<String>,
+	pub protocol_mode:Option<String>,
+	pub format:Option<String>,
+	pub declaration:Option<String>,
+	pub game_id:String,
+	pub your_turn:Teban,
+	pub to_move:Teban,
+	pub rematch_on_draw:bool,
+	pub sente_time:Option<TimeSettings>, // BEGIN Time+で先手にだけ指定された持ち時間
+	pub gote_time:Option<TimeSettings>, // BEGIN Time-で後手にだけ指定された持ち時間
+	pub data:CsaData, // 対局者名、共通の持ち時間、開始局面と途中までの指し手
+}
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum StartResult {
+	Started, // START:<Game_ID>
+	Rejected(String), // REJECT:<Game_ID> by <name>
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum GameEndReason {
+	Resign, // #RESIGN
+	TimeUp, // #TIME_UP
+	IllegalMove, // #ILLEGAL_MOVE
+	IllegalAction, // #ILLEGAL_ACTION
+	Sennichite, // #SENNICHITE
+	OuteSennichite, // #OUTE_SENNICHITE
+	Jishogi, // #JISHOGI
+	MaxMoves, // #MAX_MOVES
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum GameOutcome {
+	Win, // #WIN
+	Lose, // #LOSE
+	Draw, // #DRAW
+	Censored, // #CENSORED
+	Chudan, // #CHUDAN
+}
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum GameEvent {
+	Move(Teban,Move,Option<Duration>,Option<EngineComment>), // 自分の手も含めサーバーが確定させた指し手
+	Special(EndState,Option<Duration>), // %TORYO、%KACHI等
+	Reason(GameEndReason),
+	End(GameOutcome),
+}
+struct GameState {
+	game_id:String,
+	your_turn:Teban,
+	kinds:[[KomaKind; 9]; 9],
+	mc:MochigomaCollections,
+	teban:Teban,
+	time_unit:Duration, // 指し手のTの値の単位
+}
+pub struct CsaClient<R,W> where R: BufRead, W: Write {
+	reader:R,
+	writer:W,
+	pending:Option<GameState>,
+	game:Option<GameState>,
+}
+impl CsaClient<BufReader<TcpStream>,TcpStream> {
+	pub fn connect<A: ToSocketAddrs>(addr:A) -> Result<CsaClient<BufReader<TcpStream>,TcpStream>,CsaClientError> {
+		let stream = TcpStream::connect(addr)?;
+
+		stream.set_nodelay(true)?;
+
+		let reader = BufReader::new(stream.try_clone()?);
+
+		Ok(CsaClient::new(reader,stream))
+	}
+}
+impl<R,W> CsaClient<R,W> where R: BufRead, W: Write {
+	pub fn new(reader:R,writer:W) -> CsaClient<R,W> {
+		CsaClient {
+			reader,
+			writer,
+			pending:None,
+			game:None,
+		}
+	}
+
+	fn send(&mut self,line:&str) -> Result<(),CsaClientError> {
+		writeln!(self.writer,"{}",line)?;
+		self.writer.flush()?;
+
+		Ok(())
+	}
+
+	fn read_line(&mut self) -> Result<String,CsaClientError> {
+		let mut buf = String::new();
+
+		if self.reader.read_line(&mut buf)? == 0 {
+			return Err(CsaClientError::ConnectionClosed);
+		}
+
+		Ok(buf.trim_end_matches(['\r','\n']).to_string())
+	}
+
+	// 空行はサーバーからのキープアライブなので読み飛ばす
+	fn read_message(&mut self) -> Result<String,CsaClientError> {
+		loop {
+			let line = self.read_line()?;
+
+			if !line.is_empty() {
+				return Ok(line);
+			}
+		}
+	}
+
+	pub fn login(&mut self,name:&str,password:&str) -> Result<(),CsaClientError> {
+		self.send(&format!("LOGIN {} {}",name,password))?;
+
+		let line = self.read_message()?;
+
+		if line == format!("LOGIN:{} OK",name) {
+			Ok(())
+		} else if line.starts_with("LOGIN:") {
+			Err(CsaClientError::LoginFailed(line))
+		} else {
+			Err(CsaClientError::UnexpectedMessage(line))
+		}
+	}
+
+	pub fn logout(&mut self) -> Result<(),CsaClientError> {
+		self.send("LOGOUT")?;
+
+		loop {
+			match self.read_line() {
+				Ok(ref line) if line == "LOGOUT:completed" => {
+					return Ok(());
+				},
+				Ok(_) => (),
+				// LOGOUT:completedを送らずに接続を切るサーバーもある
+				Err(CsaClientError::ConnectionClosed) => {
+					return Ok(());
+				},
+				Err(e) => {
+					return Err(e);
+				}
+			}
+		}
+	}
+
+	pub fn send_keep_alive(&mut self) -> Result<(),CsaClientError> {
+		self.send("")
+	}
+
+	pub fn read_game_summary(&mut self) -> Result<GameSummary,CsaClientError> {
+		let line = self.read_message()?;
+
+		if line != "BEGIN Game_Summary" {
+			return Err(CsaClientError::UnexpectedMessage(line));
+		}
+
+		let mut protocol_version = None;
+		let mut protocol_mode = None;
+		let mut format = None;
+		let mut declaration = None;
+		let mut game_id = None;
+		let mut your_turn = None;
+		let mut to_move = None;
+		let mut rematch_on_draw = false;
+		let mut info = KifuInfo::new();
+		let mut sente_time = None;
+		let mut gote_time = None;
+		let mut position = None;
+
+		loop {
+			let line = self.read_message()?;
+
+			if line == "END Game_Summary" {
+				break;
+			} else if line == "BEGIN Time" {
+				info.time = Some(self.read_time("END Time")?);
+			} else if line == "BEGIN Time+" {
+				sente_time = Some(self.read_time("END Time+")?);
+			} else if line == "BEGIN Time-" {
+				gote_time = Some(self.read_time("END Time-")?);
+			} else if line == "BEGIN Position" {
+				position = Some(self.read_position_lines()?);
+			} else {
+				let (key,value) = match line.find(':') {
+					Some(p) => (&line[0..p],&line[(p+1)..]),
+					None => {
+						return Err(CsaClientError::UnexpectedMessage(line));
+					}
+				};
+
+				match key {
+					"Protocol_Version" => protocol_version = Some(value.to_string()),
+					"Protocol_Mode" => protocol_mode = Some(value.to_string()),
+					"Format" => format = Some(value.to_string()),
+					"Declaration" => declaration = Some(value.to_string()),
+					"Game_ID" => game_id = Some(value.to_string()),
+					"Name+" => info.sente_name = Some(value.to_string()),
+					"Name-" => info.gote_name = Some(value.to_string()),
+					"Your_Turn" => your_turn = Some(parse_teban(&line,value)?),
+					"To_Move" => to_move = Some(parse_teban(&line,value)?),
+					"Rematch_On_Draw" => rematch_on_draw = value == "YES",
+					"Max_Moves" => {
+						info.max_moves = Some(value.parse().map_err(|_| {
+							CsaClientError::UnexpectedMessage(line.clone())
+						})?);
+					},
+					// サーバー独自の拡張項目は無視する
+					_ => (),
+				}
+			}
+		}
+
+		let create_error = |s:&str| CsaClientError::InvalidStateError(format!("{} is missing in the game summary.",s));
+
+		let game_id = game_id.ok_or_else(|| create_error("Game_ID"))?;
+		let your_turn = your_turn.ok_or_else(|| create_error("Your_Turn"))?;
+
+		let time_unit = info.time.or(sente_time).or(gote_time).map(|t| t.time_unit).unwrap_or(Duration::from_secs(1));
+
+		// 局面中の消費時間はTime_Unitの単位なので、時間の設定を全て読み込んでから解釈する
+		let (position_lines,move_lines) = position.ok_or_else(|| create_error("Position"))?;
+		let mut data = self.parse_position(&position_lines,&move_lines,time_unit)?;
+		let to_move = to_move.unwrap_or(data.teban_at_start);
+
+		let (kinds,mc,teban) = data.position_at(data.moves.len() as u32).map(|p| {
+			let Banmen(kinds) = p.banmen;
+
+			(kinds,p.mochigoma,p.teban)
+		}).ok_or_else(|| CsaClientError::InvalidStateError(String::from("The position of the game could not be restored.")))?;
+
+		self.pending = Some(GameState {
+			game_id:game_id.clone(),
+			your_turn,
+			kinds,
+			mc,
+			teban,
+			time_unit,
+		});
+
+		data.kifu_info = Some(info);
+
+		Ok(GameSummary {
+			protocol_version,
+			protocol_mode,
+			format,
+			declaration,
+			game_id,
+			your_turn,
+			to_move,
+			rematch_on_draw,
+			sente_time,
+			gote_time,
+			data,
+		})
+	}
+
+	fn read_time(&mut self,end:&str) -> Result<TimeSettings,CsaClientError> {
+		let mut time = TimeSettings::default();
+
+		loop {
+			let line = self.read_message()?;
+
+			if line == end {
+				return Ok(time);
+			}
+
+			time.parse(&line)?;
+		}
+	}
+
+	fn read_position_lines(&mut self) -> Result<(Vec<String>,Vec<String>),CsaClientError> {
+		let mut position_lines = Vec::new();
+		let mut move_lines = Vec::new();
+
+		loop {
+			let line = self.read_message()?;
+
+			if line == "END Position" {
+				break;
+			} else if line.starts_with('\'') {
+				continue;
+			} else if line.starts_with('P') && move_lines.is_empty() {
+				position_lines.push(line);
+			} else {
+				move_lines.extend(line.split(',').map(|s| s.to_string()));
+			}
+		}
+
+		Ok((position_lines,move_lines))
+	}
+
+	fn parse_position(&self,position_lines:&[String],move_lines:&[String],time_unit:Duration) -> Result<CsaData,CsaClientError> {
+		if position_lines.is_empty() || move_lines.is_empty() {
+			return Err(CsaClientError::InvalidStateError(String::from("The position in the game summary is incomplete.")));
+		}
+
+		let (banmen,mc) = CsaPositionParser::new().parse(position_lines)?;
+		let (teban,mvs,end_state,_) = CsaMovesParser::new(true,false).time_unit(time_unit).parse(move_lines,&banmen,&mc)?;
+
+		Ok(CsaData::new(None,None,teban,banmen,mc,mvs,end_state,Vec::new()))
+	}
+
+	fn respond_to_summary(&mut self,command:&str) -> Result<StartResult,CsaClientError> {
+		let game_id = match self.pending {
+			Some(ref g) => g.game_id.clone(),
+			None => {
+				return Err(CsaClientError::InvalidStateError(String::from("No game summary has been received.")));
+			}
+		};
+
+		self.send(&format!("{} {}",command,game_id))?;
+
+		let line = self.read_message()?;
+
+		if line == format!("START:{}",game_id) {
+			self.game = self.pending.take();
+
+			Ok(StartResult::Started)
+		} else if line.starts_with(&format!("REJECT:{} by ",game_id)) {
+			self.pending = None;
+
+			Ok(StartResult::Rejected(line[(game_id.len() + 11)..].to_string()))
+		} else {
+			Err(CsaClientError::UnexpectedMessage(line))
+		}
+	}
+
+	pub fn agree(&mut self) -> Result<StartResult,CsaClientError> {
+		self.respond_to_summary("AGREE")
+	}
+
+	pub fn reject(&mut self) -> Result<(),CsaClientError> {
+		match self.respond_to_summary("REJECT")? {
+			StartResult::Rejected(_) => Ok(()),
+			StartResult::Started => {
+				Err(CsaClientError::InvalidStateError(String::from("The game started after the rejection.")))
+			}
+		}
+	}
+
+	fn game(&self) -> Result<&GameState,CsaClientError> {
+		self.game.as_ref().ok_or_else(|| CsaClientError::InvalidStateError(String::from("The game has not started.")))
+	}
+
+	pub fn send_move(&mut self,m:&Move,comment:Option<&EngineComment>) -> Result<(),CsaClientError> {
+		let line = {
+			let game = self.game()?;
+
+			if game.teban != game.your_turn {
+				return Err(CsaClientError::InvalidStateError(String::from("It is not your turn.")));
+			}
+
+			let mut line = move_to_csa(&game.kinds,game.teban,m)?;
+
+			// 評価値と読み筋は拡張モードのコメントとして送る
+			if let Some(c) = comment {
+				let mut kinds = game.kinds;
+				apply_move(&mut kinds,game.teban,m);
+
+				line.push_str(&format!(",'* {}",format_score(&c.score)));
+
+				let mut teban = game.teban.opposite();
+
+				for m in &c.pv {
+					line.push(' ');
+					line.push_str(&move_to_csa(&kinds,teban,m)?);
+					apply_move(&mut kinds,teban,m);
+					teban = teban.opposite();
+				}
+			}
+
+			line
+		};
+
+		self.send(&line)
+	}
+
+	pub fn resign(&mut self) -> Result<(),CsaClientError> {
+		self.game()?;
+		self.send("%TORYO")
+	}
+
+	pub fn declare_win(&mut self) -> Result<(),CsaClientError> {
+		self.game()?;
+		self.send("%KACHI")
+	}
+
+	pub fn chudan(&mut self) -> Result<(),CsaClientError> {
+		self.game()?;
+		self.send("%CHUDAN")
+	}
+
+	pub fn receive(&mut self) -> Result<GameEvent,CsaClientError> {
+		let time_unit = self.game()?.time_unit;
+
+		let line = self.read_message()?;
+
+		if let Some(result) = line.strip_prefix('#') {
+			let outcome = match result {
+				"WIN" => GameOutcome::Win,
+				"LOSE" => GameOutcome::Lose,
+				"DRAW" => GameOutcome::Draw,
+				"CENSORED" => GameOutcome::Censored,
+				"CHUDAN" => GameOutcome::Chudan,
+				_ => {
+					return GameEndReason::from_csa(result).map(GameEvent::Reason).ok_or(CsaClientError::UnexpectedMessage(line));
+				}
+			};
+
+			self.game = None;
+
+			return Ok(GameEvent::End(outcome));
+		}
+
+		let mut items = line.split(',');
+		let statement = items.next().unwrap_or("").to_string();
+
+		let mut elapsed = None;
+		let mut comment = None;
+
+		for item in items {
+			if let Some(t) = item.strip_prefix('T') {
+				elapsed = Some(parse_duration(t,time_unit)?);
+			} else if let Some(c) = item.strip_prefix('\'') {
+				comment = Some(c.to_string());
+			} else {
+				return Err(CsaClientError::UnexpectedMessage(line));
+			}
+		}
+
+		if statement.starts_with('%') {
+			return Ok(GameEvent::Special(EndState::try_from_csa(&statement)?,elapsed));
+		}
+
+		if !statement.starts_with('+') && !statement.starts_with('-') {
+			return Err(CsaClientError::UnexpectedMessage(line));
+		}
+
+		let game = self.game.as_mut().ok_or_else(|| {
+			CsaClientError::InvalidStateError(String::from("The game has not started."))
+		})?;
+
+		let teban = game.teban;
+		let m = CsaMovesParser::new(true,false).parse_move(&statement,teban,&game.kinds,true)?;
+
+		CsaMovesParser::apply_move(&mut game.kinds,&mut game.mc,teban,&m);
+		game.teban = teban.opposite();
+
+		// 評価値と読み筋のコメントは自由形式なので、解釈できないものは無視する
+		let comment = comment.and_then(|c| EngineComment::parse(&c,game.teban,&Banmen(game.kinds),&game.mc).ok());
+
+		Ok(GameEvent::Move(teban,m,elapsed,comment))
+	}
+}
+impl GameEndReason {
+	fn from_csa(s:&str) -> Option<GameEndReason> {
+		Some(match s {
+			"RESIGN" => GameEndReason::Resign,
+			"TIME_UP" => GameEndReason::TimeUp,
+			"ILLEGAL_MOVE" => GameEndReason::IllegalMove,
+			"ILLEGAL_ACTION" => GameEndReason::IllegalAction,
+			"SENNICHITE" => GameEndReason::Sennichite,
+			"OUTE_SENNICHITE" => GameEndReason::OuteSennichite,
+			"JISHOGI" => GameEndReason::Jishogi,
+			"MAX_MOVES" => GameEndReason::MaxMoves,
+			_ => {
+				return None;
+			}
+		})
+	}
+}
+fn parse_teban(line:&str,value:&str) -> Result<Teban,CsaClientError> {
+	match value {
+		"+" => Ok(Teban::Sente),
+		"-" => Ok(Teban::Gote),
+		_ => Err(CsaClientError::UnexpectedMessage(line.to_string()))
+	}
+}
+fn format_score(score:&EngineScore) -> String {
+	match *score {
+		EngineScore::Value(v) => format!("{}",v),
+		EngineScore::Mate(n) if n < 0 => format!("-#{}",-n),
+		EngineScore::Mate(n) => format!("#{}",n),
+	}
+}
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+	use std::net::TcpListener;
+	use std::thread;
+	use std::thread::JoinHandle;
+
+	use super::*;
+	use CsaMove;
+
+	const SUMMARY_WITH_TIME_UNIT:&str = concat!(
+		"BEGIN Game_Summary\n",
+		"Protocol_Version:1.2\n",
+		"Game_ID:test\n",
+		"Name+:sente\n",
+		"Name-:gote\n",
+		"Your_Turn:+\n",
+		"To_Move:+\n",
+		"BEGIN Position\n",
+		"PI\n",
+		"+\n",
+		"+7776FU,T1500\n",
+		"-3334FU,T250\n",
+		"END Position\n",
+		"BEGIN Time\n",
+		"Time_Unit:1msec\n",
+		"Total_Time:600000\n",
+		"END Time\n",
+		"END Game_Summary\n"
+	);
+
+	#[test]
+	fn position_times_follow_time_unit() {
+		let mut client = CsaClient::new(Cursor::new(SUMMARY_WITH_TIME_UNIT.as_bytes()),Vec::new());
+
+		let summary = client.read_game_summary().unwrap();
+
+		let elapsed = summary.data.moves.iter().map(|m| match *m {
+			CsaMove::Move(_,t) => t,
+			_ => None,
+		}).collect::<Vec<Option<Duration>>>();
+
+		assert_eq!(elapsed,vec![Some(Duration::from_millis(1500)),Some(Duration::from_millis(250))]);
+		assert_eq!(summary.to_move,Teban::Sente);
+	}
+	#[test]
+	fn receive_without_a_game_is_an_error() {
+		let mut client = CsaClient::new(Cursor::new("+7776FU,T1\n".as_bytes()),Vec::new());
+
+		match client.receive() {
+			Err(CsaClientError::InvalidStateError(_)) => (),
+			r => panic!("unexpected result {:?}",r),
+		}
+	}
+	const SUMMARY:&str = concat!(
+		"BEGIN Game_Summary\n",
+		"Protocol_Version:1.2\n",
+		"Protocol_Mode:Server\n",
+		"Format:Shogi 1.0\n",
+		"Game_ID:test\n",
+		"Name+:sente\n",
+		"Name-:gote\n",
+		"Your_Turn:+\n",
+		"To_Move:+\n",
+		"BEGIN Time\n",
+		"Time_Unit:1sec\n",
+		"Total_Time:600\n",
+		"Byoyomi:10\n",
+		"END Time\n",
+		"BEGIN Position\n",
+		"PI\n",
+		"+\n",
+		"END Position\n",
+		"END Game_Summary\n"
+	);
+
+	// 決められた順にメッセージをやり取りするだけのサーバー
+	fn mock_server(script:Vec<(&'static str,&'static str)>) -> (String,JoinHandle<()>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap().to_string();
+
+		let handle = thread::spawn(move || {
+			let (mut stream,_) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+			for (expected,response) in script {
+				if !expected.is_empty() {
+					let mut line = String::new();
+
+					reader.read_line(&mut line).unwrap();
+
+					assert_eq!(line.trim_end(),expected);
+				}
+
+				stream.write_all(response.as_bytes()).unwrap();
+			}
+		});
+
+		(addr,handle)
+	}
+
+	fn start(addr:&str) -> CsaClient<BufReader<TcpStream>,TcpStream> {
+		let mut client = CsaClient::connect(addr).unwrap();
+
+		client.login("sente","pass").unwrap();
+
+		let summary = client.read_game_summary().unwrap();
+
+		assert_eq!(summary.game_id,"test");
+		assert_eq!(summary.your_turn,Teban::Sente);
+		assert_eq!(summary.data.kifu_info.as_ref().and_then(|i| i.sente_name.clone()),Some(String::from("sente")));
+		assert_eq!(summary.data.kifu_info.as_ref().and_then(|i| i.time).map(|t| t.total_time),Some(Duration::from_secs(600)));
+
+		assert_eq!(client.agree().unwrap(),StartResult::Started);
+
+		client
+	}
+
+	#[test]
+	fn loopback_game_lost_by_resignation() {
+		let (addr,server) = mock_server(vec![
+			("LOGIN sente pass","LOGIN:sente OK\n"),
+			("",SUMMARY),
+			("AGREE test","START:test\n"),
+			("+7776FU","+7776FU,T1\n\n-3334FU,T2\n"),
+			("%TORYO","%TORYO,T3\n#RESIGN\n#LOSE\n"),
+			("LOGOUT","LOGOUT:completed\n"),
+		]);
+
+		let mut client = start(&addr);
+		let m = Move::To(KomaSrcPosition(7,7),KomaDstToPosition(7,6,false));
+
+		client.send_move(&m,None).unwrap();
+
+		assert_eq!(client.receive().unwrap(),GameEvent::Move(Teban::Sente,m,Some(Duration::from_secs(1)),None));
+
+		match client.receive().unwrap() {
+			GameEvent::Move(Teban::Gote,Move::To(KomaSrcPosition(3,3),KomaDstToPosition(3,4,false)),Some(t),None) => {
+				assert_eq!(t,Duration::from_secs(2));
+			},
+			e => panic!("unexpected event {:?}",e),
+		}
+
+		client.resign().unwrap();
+
+		assert_eq!(client.receive().unwrap(),GameEvent::Special(EndState::Toryo,Some(Duration::from_secs(3))));
+		assert_eq!(client.receive().unwrap(),GameEvent::Reason(GameEndReason::Resign));
+		assert_eq!(client.receive().unwrap(),GameEvent::End(GameOutcome::Lose));
+
+		client.logout().unwrap();
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn loopback_game_won_on_time() {
+		let (addr,server) = mock_server(vec![
+			("LOGIN sente pass","LOGIN:sente OK\n"),
+			("",SUMMARY),
+			("AGREE test","START:test\n"),
+			("+7776FU,'* 120 -3334FU","+7776FU,T1,'* 120 -3334FU\n#TIME_UP\n#WIN\n"),
+			("LOGOUT",""),
+		]);
+
+		let mut client = start(&addr);
+		let m = Move::To(KomaSrcPosition(7,7),KomaDstToPosition(7,6,false));
+		let pv = Move::To(KomaSrcPosition(3,3),KomaDstToPosition(3,4,false));
+		let comment = EngineComment {
+			score:EngineScore::Value(120),
+			pv:vec![pv],
+		};
+
+		client.send_move(&m,Some(&comment)).unwrap();
+
+		assert_eq!(client.receive().unwrap(),GameEvent::Move(Teban::Sente,m,Some(Duration::from_secs(1)),Some(comment)));
+		assert_eq!(client.receive().unwrap(),GameEvent::Reason(GameEndReason::TimeUp));
+		assert_eq!(client.receive().unwrap(),GameEvent::End(GameOutcome::Win));
+
+		// LOGOUT:completedを返さずに切断するサーバーでもログアウトできる
+		client.logout().unwrap();
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn loopback_login_failure() {
+		let (addr,server) = mock_server(vec![
+			("LOGIN sente pass","LOGIN:incorrect\n"),
+		]);
+
+		let mut client = CsaClient::connect(addr.as_str()).unwrap();
+
+		match client.login("sente","pass") {
+			Err(CsaClientError::LoginFailed(s)) => assert_eq!(s,"LOGIN:incorrect"),
+			r => panic!("unexpected result {:?}",r),
+		}
+
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn summary_with_times_for_each_side() {
+		let summary = concat!(
+			"BEGIN Game_Summary\n",
+			"Game_ID:test\n",
+			"Your_Turn:-\n",
+			"To_Move:-\n",
+			"Rematch_On_Draw:YES\n",
+			"Max_Moves:256\n",
+			"X_Unknown:ignored\n",
+			"BEGIN Time+\n",
+			"Total_Time:600\n",
+			"END Time+\n",
+			"BEGIN Time-\n",
+			"Total_Time:300\n",
+			"END Time-\n",
+			"BEGIN Position\n",
+			"PI82HI\n",
+			"-\n",
+			"END Position\n",
+			"END Game_Summary\n",
+			"REJECT:test by sente\n"
+		);
+
+		let mut client = CsaClient::new(Cursor::new(summary.as_bytes()),Vec::new());
+
+		let summary = client.read_game_summary().unwrap();
+
+		assert_eq!(summary.your_turn,Teban::Gote);
+		assert!(summary.rematch_on_draw);
+		assert_eq!(summary.sente_time.map(|t| t.total_time),Some(Duration::from_secs(600)));
+		assert_eq!(summary.gote_time.map(|t| t.total_time),Some(Duration::from_secs(300)));
+		assert_eq!(summary.data.kifu_info.as_ref().and_then(|i| i.max_moves),Some(256));
+		assert_eq!(summary.data.teban_at_start,Teban::Gote);
+
+		client.reject().unwrap();
+
+		assert_eq!(String::from_utf8(client.writer.clone()).unwrap(),"REJECT test\n");
+	}
+
+	#[test]
+	fn summary_without_game_id_is_an_error() {
+		let mut client = CsaClient::new(Cursor::new("BEGIN Game_Summary\nYour_Turn:+\nEND Game_Summary\n".as_bytes()),Vec::new());
+
+		match client.read_game_summary() {
+			Err(CsaClientError::InvalidStateError(_)) => (),
+			r => panic!("unexpected result {:?}",r),
+		}
+
+		let mut client = CsaClient::new(Cursor::new("Game_ID:test\n".as_bytes()),Vec::new());
+
+		match client.read_game_summary() {
+			Err(CsaClientError::UnexpectedMessage(s)) => assert_eq!(s,"Game_ID:test"),
+			r => panic!("unexpected result {:?}",r),
+		}
+	}
+
+	#[test]
+	fn moves_are_only_sent_on_your_turn() {
+		let input = format!("{}START:test\n",SUMMARY.replace("Your_Turn:+","Your_Turn:-"));
+
+		let mut client = CsaClient::new(Cursor::new(input.into_bytes()),Vec::new());
+
+		client.read_game_summary().unwrap();
+
+		assert_eq!(client.agree().unwrap(),StartResult::Started);
+
+		let m = Move::To(KomaSrcPosition(3,3),KomaDstToPosition(3,4,false));
+
+		match client.send_move(&m,None) {
+			Err(CsaClientError::InvalidStateError(_)) => (),
+			r => panic!("unexpected result {:?}",r),
+		}
+	}
+}
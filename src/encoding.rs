@@ -0,0 +1,159 @@
+use encoding_rs::Encoding;
+use encoding_rs::UTF_8;
+use encoding_rs::SHIFT_JIS;
+
+use error::*;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CsaEncoding {
+	Auto, // 'encoding=の指定に従い、無ければUTF-8として正しいかとバージョンから判定する
+	Utf8,
+	ShiftJis,
+}
+// 物理行単位でCSA形式の入力を文字列に変換する
+pub(crate) struct LineDecoder {
+	fixed:Option<&'static Encoding>,
+	declared:Option<&'static Encoding>,
+	default:&'static Encoding,
+	first:bool,
+}
+impl LineDecoder {
+	pub fn new(encoding:CsaEncoding) -> LineDecoder {
+		LineDecoder {
+			fixed:match encoding {
+				CsaEncoding::Auto => None,
+				CsaEncoding::Utf8 => Some(UTF_8),
+				CsaEncoding::ShiftJis => Some(SHIFT_JIS),
+			},
+			declared:None,
+			// バージョンが分かるまではV2.xとして扱う
+			default:SHIFT_JIS,
+			first:true,
+		}
+	}
+
+	pub fn decode(&mut self,bytes:&[u8]) -> Result<String,CsaStreamReadError> {
+		let bytes = if self.first && bytes.starts_with(&[0xEF,0xBB,0xBF]) {
+			if self.fixed.is_none() {
+				self.declared = Some(UTF_8);
+			}
+
+			&bytes[3..]
+		} else {
+			bytes
+		};
+
+		self.first = false;
+
+		// 指定が無ければUTF-8として正しいものはUTF-8、それ以外はバージョンから判定した文字コードで読む
+		let encoding = match self.fixed.or(self.declared) {
+			Some(encoding) => encoding,
+			None if std::str::from_utf8(bytes).is_ok() => UTF_8,
+			None => self.default,
+		};
+
+		let line = encoding.decode_without_bom_handling_and_without_replacement(bytes).ok_or_else(|| {
+			CsaStreamReadError::DecodeError(encoding.name().to_string())
+		})?.into_owned();
+
+		let statement = line.split(',').next().unwrap_or("").trim_end();
+
+		if let Some(label) = statement.strip_prefix("'encoding=") {
+			let encoding = Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| {
+				CsaStreamReadError::UnknownEncoding(label.trim().to_string())
+			})?;
+
+			if self.fixed.is_none() {
+				self.declared = Some(encoding);
+			}
+		} else if statement.starts_with("V3") {
+			self.default = UTF_8;
+		} else if statement.starts_with('V') {
+			self.default = SHIFT_JIS;
+		}
+
+		Ok(line)
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use CsaBytesStream;
+	use CsaParser;
+
+	fn sjis(s:&str) -> Vec<u8> {
+		SHIFT_JIS.encode(s).0.into_owned()
+	}
+
+	#[test]
+	fn undeclared_lines_are_detected() {
+		let mut d = LineDecoder::new(CsaEncoding::Auto);
+
+		assert_eq!(d.decode("\u{feff}'先手".as_bytes()).unwrap(),"'先手");
+
+		let mut d = LineDecoder::new(CsaEncoding::Auto);
+
+		assert_eq!(d.decode(&sjis("'先手")).unwrap(),"'先手");
+		assert_eq!(d.decode("'先手".as_bytes()).unwrap(),"'先手");
+
+		// V3.0以降はUTF-8として正しくない行を置き換えずに誤りとする
+		d.decode(b"V3.0").unwrap();
+
+		match d.decode(&sjis("N+先手")) {
+			Err(CsaStreamReadError::DecodeError(_)) => (),
+			r => panic!("unexpected result {:?}",r),
+		}
+	}
+
+	#[test]
+	fn declared_encoding_is_used_for_following_lines() {
+		let mut d = LineDecoder::new(CsaEncoding::Auto);
+
+		assert_eq!(d.decode(b"'encoding=Shift_JIS").unwrap(),"'encoding=Shift_JIS");
+		assert_eq!(d.decode(&sjis("N+先手")).unwrap(),"N+先手");
+
+		let mut d = LineDecoder::new(CsaEncoding::Auto);
+
+		d.decode(b"'encoding=UTF-8").unwrap();
+
+		match d.decode(&sjis("N+先手")) {
+			Err(CsaStreamReadError::DecodeError(_)) => (),
+			r => panic!("unexpected result {:?}",r),
+		}
+
+		match LineDecoder::new(CsaEncoding::Auto).decode(b"'encoding=unknown") {
+			Err(CsaStreamReadError::UnknownEncoding(ref label)) if label == "unknown" => (),
+			r => panic!("unexpected result {:?}",r),
+		}
+	}
+
+	#[test]
+	fn caller_choice_overrides_the_declaration() {
+		let mut d = LineDecoder::new(CsaEncoding::Utf8);
+
+		d.decode(b"'encoding=Shift_JIS").unwrap();
+
+		assert_eq!(d.decode("N+先手".as_bytes()).unwrap(),"N+先手");
+	}
+
+	#[test]
+	fn parse_shift_jis_input() {
+		let mut bytes = b"V2.2\r\n".to_vec();
+
+		bytes.extend(sjis("N+先手\r\nN-後手\r\nPI\r\n+\r\n"));
+
+		let data = CsaParser::new(CsaBytesStream::new(bytes)).parse().unwrap().remove(0);
+		let info = data.kifu_info.unwrap();
+
+		assert_eq!(info.sente_name,Some(String::from("先手")));
+		assert_eq!(info.gote_name,Some(String::from("後手")));
+
+		let mut bytes = b"'encoding=Shift_JIS\nV3.0\n".to_vec();
+
+		bytes.extend(sjis("N+先手\nPI\n+\n"));
+
+		let data = CsaParser::new(CsaBytesStream::new(bytes)).parse().unwrap().remove(0);
+
+		assert_eq!(data.kifu_info.unwrap().sente_name,Some(String::from("先手")));
+	}
+}
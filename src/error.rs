@@ -1,111 +1,436 @@
-use std::io;
-use std::fmt;
-use std::error;
-use std::num::ParseIntError;
-
-#[derive(Debug)]
-pub enum CsaStreamReadError {
-	IOError(io::Error),
-}
-impl fmt::Display for CsaStreamReadError {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match *self {
-			CsaStreamReadError::IOError(_) => write!(f, "Error occurred in file I/O."),
-		}
-	}
-}
-impl error::Error for CsaStreamReadError {
-	fn description(&self) -> &str {
-		match *self {
-			CsaStreamReadError::IOError(_) => "Error occurred in file I/O.",
-		}
-	}
-
-	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-		match *self {
-			CsaStreamReadError::IOError(ref e) => Some(e),
-		}
-	}
-}
-impl From<io::Error> for CsaStreamReadError {
-	fn from(err: io::Error) -> CsaStreamReadError {
-		CsaStreamReadError::IOError(err)
-	}
-}
-#[derive(Debug)]
-pub enum CsaParserError {
-	StreamReadError(CsaStreamReadError),
-	FormatError(String),
-	ParseIntError(ParseIntError),
-	InvalidStateError(String),
-}
-impl fmt::Display for CsaParserError {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match *self {
-			CsaParserError::StreamReadError(_) => write!(f, "There was an error loading the stream."),
-			CsaParserError::FormatError(ref s) => write!(f, "Invalid format. ({})",s),
-			CsaParserError::ParseIntError(ref e) => write!(f, "parse int error. ({})",e),
-			CsaParserError::InvalidStateError (ref s) => write!(f, "Invalid read state. ({})",s),
-		}
-	}
-}
-impl error::Error for CsaParserError {
-	fn description(&self) -> &str {
-		match *self {
-			CsaParserError::StreamReadError(_) => "There was an error loading the stream.",
-			CsaParserError::FormatError(_) => "Invalid format.",
-			CsaParserError::ParseIntError(_) => "parse int error.",
-			CsaParserError::InvalidStateError(_) => "Invalid read state.",
-		}
-	}
-
-	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-		match *self {
-			CsaParserError::StreamReadError(ref e) => Some(e),
-			CsaParserError::FormatError(_) => None,
-			CsaParserError::ParseIntError(ref e) => Some(e),
-			CsaParserError::InvalidStateError(_) => None,
-		}
-	}
-}
-impl From<CsaStreamReadError> for CsaParserError {
-	fn from(err: CsaStreamReadError) -> CsaParserError {
-		CsaParserError::StreamReadError(err)
-	}
-}
-impl From<ParseIntError> for CsaParserError {
-	fn from(err: ParseIntError) -> CsaParserError {
-		CsaParserError::ParseIntError(err)
-	}
-}
-impl From<CsaStateError> for CsaParserError {
-	fn from(err: CsaStateError) -> CsaParserError {
-		match err {
-			CsaStateError::InvalidStateError(s) => CsaParserError::InvalidStateError(s)
-		}
-	}
-}
-#[derive(Debug)]
-pub enum CsaStateError {
-	InvalidStateError(String),
-}
-impl fmt::Display for CsaStateError {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match *self {
-			CsaStateError::InvalidStateError (ref s) => write!(f,"Invalid read state. ({})",s),
-		}
-	}
-}
-impl error::Error for CsaStateError {
-	fn description(&self) -> &str {
-		match *self {
-			CsaStateError::InvalidStateError(_) => "Invalid read state.",
-		}
-	}
-
-	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-		match *self {
-			CsaStateError::InvalidStateError(_) => None,
-		}
-	}
-}
+use std::io;
+use std::fmt;
+use std::error;
+use std::num::ParseIntError;
+
+use Stage;
+
+#[derive(Debug)]
+pub enum CsaStreamReadError {
+	IOError(io::Error),
+	DecodeError(String), // 指定された文字コードとして正しくない入力
+	UnknownEncoding(String), // 'encoding=で未知の文字コードが指定された
+}
+impl fmt::Display for CsaStreamReadError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaStreamReadError::IOError(_) => write!(f, "Error occurred in file I/O."),
+			CsaStreamReadError::DecodeError(ref s) => write!(f, "The input is not valid {}.",s),
+			CsaStreamReadError::UnknownEncoding(ref s) => write!(f, "Unknown encoding ({}).",s),
+		}
+	}
+}
+impl error::Error for CsaStreamReadError {
+	fn description(&self) -> &str {
+		match *self {
+			CsaStreamReadError::IOError(_) => "Error occurred in file I/O.",
+			CsaStreamReadError::DecodeError(_) => "The input could not be decoded.",
+			CsaStreamReadError::UnknownEncoding(_) => "Unknown encoding.",
+		}
+	}
+
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			CsaStreamReadError::IOError(ref e) => Some(e),
+			CsaStreamReadError::DecodeError(_) | CsaStreamReadError::UnknownEncoding(_) => None,
+		}
+	}
+}
+impl From<io::Error> for CsaStreamReadError {
+	fn from(err: io::Error) -> CsaStreamReadError {
+		CsaStreamReadError::IOError(err)
+	}
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SourceLocation {
+	pub line:u32, // 物理行の行番号(1 origin)
+	pub statement:u32, // 行内の','で区切られた文の番号(1 origin)
+	pub column:u32, // 文の開始位置の桁(1 origin)
+}
+impl SourceLocation {
+	pub fn new(line:u32,statement:u32,column:u32) -> SourceLocation {
+		SourceLocation {
+			line,
+			statement,
+			column,
+		}
+	}
+}
+impl fmt::Display for SourceLocation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}, statement {}, column {}",self.line,self.statement,self.column)
+	}
+}
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SourceStatement {
+	pub location:Option<SourceLocation>,
+	pub text:String,
+}
+impl SourceStatement {
+	pub fn new(location:Option<SourceLocation>,text:String) -> SourceStatement {
+		SourceStatement {
+			location,
+			text,
+		}
+	}
+}
+impl fmt::Display for SourceStatement {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.location {
+			Some(ref l) => write!(f, "at {}: \"{}\"",l,self.text),
+			None => write!(f, "in \"{}\"",self.text),
+		}
+	}
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CsaFormatErrorKind {
+	UnexpectedStatement { stage:Stage }, // 現在の読み込み段階で現れてはいけない文
+	UnexpectedEndOfInput { stage:Stage }, // 局面が定義される前に入力が終わった
+	UnexpectedEndOfStatement, // 文が途中で終わっている
+	UnknownInfoKey, // 未知の棋譜情報
+	BadTimeLimit, // $TIME_LIMITの書式が不正
+	BadTime, // $TIMEの書式が不正
+	UnknownPieceCode, // 未知の駒の種別
+	UnknownEndState, // 未知の終局状態
+	BadSquare, // 升目の指定が不正
+	BadBoardRow, // P1～P9の行が不正
+	SquareOccupied, // 駒が既に置かれている升目への配置
+	NoPieceToRemove, // 平手の初期配置に存在しない駒を落とそうとした
+	DuplicateKing, // 玉が複数配置された
+	PieceCountExceeded, // 駒の数が上限を超えた
+	MisplacedAll, // 00ALが最後の行以外に現れた
+	WrongSideToMove, // 手番と指し手の符号が一致しない
+	NoPieceToMove, // 移動元に手番側の駒が無い
+	PieceMismatch, // 移動後の駒の種類が移動元の駒と一致しない
+	BadEngineComment, // 評価値と読み筋のコメントの書式が不正
+	IllegalMove { ply:u32, reason:IllegalMoveReason }, // 厳密モードで検出した非合法手
+	BadSfen, // SFENの書式が不正
+	BadMoveNotation, // 指し手の表記が不正
+	UnknownHandicap, // 未知の手合割
+	BadJkf, // JKFの書式が不正
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum IllegalMoveReason {
+	NoPieceToMove, // 移動元に手番側の駒が無い
+	PieceMismatch, // 移動後の駒の種類が移動元の駒と一致しない
+	SquareOccupied, // 移動先に自分の駒がある、または打つ升目に駒がある
+	CannotReach, // 駒の動きで移動先に到達できない
+	CannotPromote, // 成れない駒または位置での成り
+	MustPromote, // 行き所の無い駒になる不成
+	NotInHand, // 持ち駒に無い駒を打った
+	DeadPiece, // 行き所の無い升目への駒打ち
+	Nifu, // 二歩
+	DropPawnMate, // 打ち歩詰め
+	LeftInCheck, // 自玉に王手がかかったままになる
+}
+impl fmt::Display for IllegalMoveReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			IllegalMoveReason::NoPieceToMove => write!(f, "there is no piece of the side to move at the source square"),
+			IllegalMoveReason::PieceMismatch => write!(f, "the piece does not match the piece at the source square"),
+			IllegalMoveReason::SquareOccupied => write!(f, "the destination square is occupied"),
+			IllegalMoveReason::CannotReach => write!(f, "the piece cannot reach the destination square"),
+			IllegalMoveReason::CannotPromote => write!(f, "the piece cannot promote"),
+			IllegalMoveReason::MustPromote => write!(f, "the piece must promote"),
+			IllegalMoveReason::NotInHand => write!(f, "the piece is not in hand"),
+			IllegalMoveReason::DeadPiece => write!(f, "the piece cannot be dropped on a square where it has no legal move"),
+			IllegalMoveReason::Nifu => write!(f, "two unpromoted pawns on the same file"),
+			IllegalMoveReason::DropPawnMate => write!(f, "checkmate by a pawn drop"),
+			IllegalMoveReason::LeftInCheck => write!(f, "the king is left in check"),
+		}
+	}
+}
+impl fmt::Display for CsaFormatErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaFormatErrorKind::UnexpectedStatement { stage } => {
+				write!(f, "Unexpected statement in the {:?} stage.",stage)
+			},
+			CsaFormatErrorKind::UnexpectedEndOfInput { stage } => {
+				write!(f, "Unexpected end of input in the {:?} stage.",stage)
+			},
+			CsaFormatErrorKind::UnexpectedEndOfStatement => write!(f, "Could not read the specified length."),
+			CsaFormatErrorKind::UnknownInfoKey => write!(f, "Unknown kifu information."),
+			CsaFormatErrorKind::BadTimeLimit => write!(f, "Invalid format of time limit."),
+			CsaFormatErrorKind::BadTime => write!(f, "Invalid format of time."),
+			CsaFormatErrorKind::UnknownPieceCode => write!(f, "Unknown piece code."),
+			CsaFormatErrorKind::UnknownEndState => write!(f, "Unknown end state."),
+			CsaFormatErrorKind::BadSquare => write!(f, "Invalid square."),
+			CsaFormatErrorKind::BadBoardRow => write!(f, "Invalid row of the board."),
+			CsaFormatErrorKind::SquareOccupied => write!(f, "The square is already occupied."),
+			CsaFormatErrorKind::NoPieceToRemove => write!(f, "There is no piece to remove from the initial position."),
+			CsaFormatErrorKind::DuplicateKing => write!(f, "The king is placed more than once."),
+			CsaFormatErrorKind::PieceCountExceeded => write!(f, "The number of pieces exceeds the limit."),
+			CsaFormatErrorKind::MisplacedAll => write!(f, "00AL must be in the last line of the position."),
+			CsaFormatErrorKind::WrongSideToMove => write!(f, "The side of the move does not match the side to move."),
+			CsaFormatErrorKind::NoPieceToMove => write!(f, "There is no piece of the side to move at the source square."),
+			CsaFormatErrorKind::PieceMismatch => write!(f, "The piece after the move does not match the piece at the source square."),
+			CsaFormatErrorKind::BadEngineComment => write!(f, "Invalid format of the engine comment."),
+			CsaFormatErrorKind::IllegalMove { ply, reason } => write!(f, "Illegal move at ply {}: {}.",ply,reason),
+			CsaFormatErrorKind::BadSfen => write!(f, "Invalid format of the SFEN string."),
+			CsaFormatErrorKind::BadMoveNotation => write!(f, "Invalid notation of the move."),
+			CsaFormatErrorKind::UnknownHandicap => write!(f, "Unknown handicap."),
+			CsaFormatErrorKind::BadJkf => write!(f, "Invalid format of the JKF data."),
+		}
+	}
+}
+#[derive(Debug)]
+pub enum CsaParserError {
+	StreamReadError(CsaStreamReadError,Option<SourceStatement>),
+	FormatError(CsaFormatErrorKind,Option<SourceStatement>),
+	ParseIntError(ParseIntError,Option<SourceStatement>),
+	InvalidStateError(String,Option<SourceStatement>),
+}
+impl CsaParserError {
+	pub fn statement(&self) -> Option<&SourceStatement> {
+		match *self {
+			CsaParserError::StreamReadError(_,ref s) |
+			CsaParserError::FormatError(_,ref s) |
+			CsaParserError::ParseIntError(_,ref s) |
+			CsaParserError::InvalidStateError(_,ref s) => s.as_ref(),
+		}
+	}
+
+	pub fn location(&self) -> Option<SourceLocation> {
+		self.statement().and_then(|s| s.location)
+	}
+
+	pub fn with_statement(self,statement:SourceStatement) -> CsaParserError {
+		match self {
+			CsaParserError::StreamReadError(e,None) => {
+				CsaParserError::StreamReadError(e,Some(statement))
+			},
+			CsaParserError::FormatError(e,None) => {
+				CsaParserError::FormatError(e,Some(statement))
+			},
+			CsaParserError::ParseIntError(e,None) => {
+				CsaParserError::ParseIntError(e,Some(statement))
+			},
+			CsaParserError::InvalidStateError(e,None) => {
+				CsaParserError::InvalidStateError(e,Some(statement))
+			},
+			e => e,
+		}
+	}
+}
+impl fmt::Display for CsaParserError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaParserError::StreamReadError(_,_) => write!(f, "There was an error loading the stream.")?,
+			CsaParserError::FormatError(ref k,_) => write!(f, "Invalid format. ({})",k)?,
+			CsaParserError::ParseIntError(ref e,_) => write!(f, "parse int error. ({})",e)?,
+			CsaParserError::InvalidStateError (ref s,_) => write!(f, "Invalid read state. ({})",s)?,
+		}
+
+		match self.statement() {
+			Some(s) => write!(f, " {}",s),
+			None => Ok(()),
+		}
+	}
+}
+impl error::Error for CsaParserError {
+	fn description(&self) -> &str {
+		match *self {
+			CsaParserError::StreamReadError(_,_) => "There was an error loading the stream.",
+			CsaParserError::FormatError(_,_) => "Invalid format.",
+			CsaParserError::ParseIntError(_,_) => "parse int error.",
+			CsaParserError::InvalidStateError(_,_) => "Invalid read state.",
+		}
+	}
+
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			CsaParserError::StreamReadError(ref e,_) => Some(e),
+			CsaParserError::FormatError(_,_) => None,
+			CsaParserError::ParseIntError(ref e,_) => Some(e),
+			CsaParserError::InvalidStateError(_,_) => None,
+		}
+	}
+}
+impl From<CsaStreamReadError> for CsaParserError {
+	fn from(err: CsaStreamReadError) -> CsaParserError {
+		CsaParserError::StreamReadError(err,None)
+	}
+}
+impl From<ParseIntError> for CsaParserError {
+	fn from(err: ParseIntError) -> CsaParserError {
+		CsaParserError::ParseIntError(err,None)
+	}
+}
+impl From<CsaStateError> for CsaParserError {
+	fn from(err: CsaStateError) -> CsaParserError {
+		match err {
+			CsaStateError::InvalidStateError(s) => CsaParserError::InvalidStateError(s,None)
+		}
+	}
+}
+#[derive(Debug)]
+pub enum CsaStateError {
+	InvalidStateError(String),
+}
+impl fmt::Display for CsaStateError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaStateError::InvalidStateError (ref s) => write!(f,"Invalid read state. ({})",s),
+		}
+	}
+}
+impl error::Error for CsaStateError {
+	fn description(&self) -> &str {
+		match *self {
+			CsaStateError::InvalidStateError(_) => "Invalid read state.",
+		}
+	}
+
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			CsaStateError::InvalidStateError(_) => None,
+		}
+	}
+}
+#[derive(Debug)]
+pub enum CsaWriterError {
+	IOError(io::Error),
+	InvalidStateError(String),
+}
+impl fmt::Display for CsaWriterError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaWriterError::IOError(_) => write!(f, "Error occurred in file I/O."),
+			CsaWriterError::InvalidStateError(ref s) => write!(f, "Invalid write state. ({})",s),
+		}
+	}
+}
+impl error::Error for CsaWriterError {
+	fn description(&self) -> &str {
+		match *self {
+			CsaWriterError::IOError(_) => "Error occurred in file I/O.",
+			CsaWriterError::InvalidStateError(_) => "Invalid write state.",
+		}
+	}
+
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			CsaWriterError::IOError(ref e) => Some(e),
+			CsaWriterError::InvalidStateError(_) => None,
+		}
+	}
+}
+impl From<io::Error> for CsaWriterError {
+	fn from(err: io::Error) -> CsaWriterError {
+		CsaWriterError::IOError(err)
+	}
+}
+#[derive(Debug)]
+pub enum CsaClientError {
+	IOError(io::Error),
+	ParserError(CsaParserError),
+	WriterError(CsaWriterError),
+	LoginFailed(String), // LOGIN:incorrect等、ログインが拒否された
+	UnexpectedMessage(String), // 現在の状態で受け取るはずの無いメッセージ
+	ConnectionClosed, // サーバーとの接続が切れた
+	InvalidStateError(String),
+}
+impl fmt::Display for CsaClientError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaClientError::IOError(_) => write!(f, "Error occurred in network I/O."),
+			CsaClientError::ParserError(ref e) => write!(f, "{}",e),
+			CsaClientError::WriterError(ref e) => write!(f, "{}",e),
+			CsaClientError::LoginFailed(ref s) => write!(f, "Login failed. ({})",s),
+			CsaClientError::UnexpectedMessage(ref s) => write!(f, "Unexpected message from the server. (\"{}\")",s),
+			CsaClientError::ConnectionClosed => write!(f, "The connection was closed by the server."),
+			CsaClientError::InvalidStateError(ref s) => write!(f, "Invalid client state. ({})",s),
+		}
+	}
+}
+impl error::Error for CsaClientError {
+	fn description(&self) -> &str {
+		match *self {
+			CsaClientError::IOError(_) => "Error occurred in network I/O.",
+			CsaClientError::ParserError(_) => "An error occurred while parsing the message from the server.",
+			CsaClientError::WriterError(_) => "An error occurred while formatting the message to the server.",
+			CsaClientError::LoginFailed(_) => "Login failed.",
+			CsaClientError::UnexpectedMessage(_) => "Unexpected message from the server.",
+			CsaClientError::ConnectionClosed => "The connection was closed by the server.",
+			CsaClientError::InvalidStateError(_) => "Invalid client state.",
+		}
+	}
+
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			CsaClientError::IOError(ref e) => Some(e),
+			CsaClientError::ParserError(ref e) => Some(e),
+			CsaClientError::WriterError(ref e) => Some(e),
+			CsaClientError::LoginFailed(_) |
+			CsaClientError::UnexpectedMessage(_) |
+			CsaClientError::ConnectionClosed |
+			CsaClientError::InvalidStateError(_) => None,
+		}
+	}
+}
+impl From<io::Error> for CsaClientError {
+	fn from(err: io::Error) -> CsaClientError {
+		CsaClientError::IOError(err)
+	}
+}
+impl From<CsaParserError> for CsaClientError {
+	fn from(err: CsaParserError) -> CsaClientError {
+		CsaClientError::ParserError(err)
+	}
+}
+impl From<CsaWriterError> for CsaClientError {
+	fn from(err: CsaWriterError) -> CsaClientError {
+		CsaClientError::WriterError(err)
+	}
+}
+#[derive(Debug)]
+pub enum CsaServerError {
+	IOError(io::Error),
+	WriterError(CsaWriterError),
+	ConnectionClosed, // 対局開始前に対局者との接続が切れた
+	GameRejected(String), // 対局者が対局条件を拒否した
+	InvalidStateError(String),
+}
+impl fmt::Display for CsaServerError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CsaServerError::IOError(_) => write!(f, "Error occurred in network I/O."),
+			CsaServerError::WriterError(ref e) => write!(f, "{}",e),
+			CsaServerError::ConnectionClosed => write!(f, "The connection was closed by the player."),
+			CsaServerError::GameRejected(ref s) => write!(f, "The game was rejected by {}.",s),
+			CsaServerError::InvalidStateError(ref s) => write!(f, "Invalid server state. ({})",s),
+		}
+	}
+}
+impl error::Error for CsaServerError {
+	fn description(&self) -> &str {
+		match *self {
+			CsaServerError::IOError(_) => "Error occurred in network I/O.",
+			CsaServerError::WriterError(_) => "An error occurred while formatting the message to the player.",
+			CsaServerError::ConnectionClosed => "The connection was closed by the player.",
+			CsaServerError::GameRejected(_) => "The game was rejected.",
+			CsaServerError::InvalidStateError(_) => "Invalid server state.",
+		}
+	}
+
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			CsaServerError::IOError(ref e) => Some(e),
+			CsaServerError::WriterError(ref e) => Some(e),
+			CsaServerError::ConnectionClosed |
+			CsaServerError::GameRejected(_) |
+			CsaServerError::InvalidStateError(_) => None,
+		}
+	}
+}
+impl From<io::Error> for CsaServerError {
+	fn from(err: io::Error) -> CsaServerError {
+		CsaServerError::IOError(err)
+	}
+}
+impl From<CsaWriterError> for CsaServerError {
+	fn from(err: CsaWriterError) -> CsaServerError {
+		CsaServerError::WriterError(err)
+	}
+}
@@ -0,0 +1,204 @@
+use usiagent::shogi::*;
+use usiagent::rule::BANMEN_START_POS;
+
+use CsaData;
+use writer::ToCsa;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Handicap {
+	Hirate, // 平手
+	Kyou, // 香落ち
+	RightKyou, // 右香落ち
+	Kaku, // 角落ち
+	Hisha, // 飛車落ち
+	HishaKyou, // 飛香落ち
+	TwoPieces, // 二枚落ち
+	ThreePieces, // 三枚落ち
+	FourPieces, // 四枚落ち
+	FivePieces, // 五枚落ち
+	LeftFivePieces, // 左五枚落ち
+	SixPieces, // 六枚落ち
+	LeftSevenPieces, // 左七枚落ち
+	RightSevenPieces, // 右七枚落ち
+	EightPieces, // 八枚落ち
+	TenPieces, // 十枚落ち
+}
+pub const HANDICAPS:[Handicap; 16] = [
+	Handicap::Hirate,
+	Handicap::Kyou,
+	Handicap::RightKyou,
+	Handicap::Kaku,
+	Handicap::Hisha,
+	Handicap::HishaKyou,
+	Handicap::TwoPieces,
+	Handicap::ThreePieces,
+	Handicap::FourPieces,
+	Handicap::FivePieces,
+	Handicap::LeftFivePieces,
+	Handicap::SixPieces,
+	Handicap::LeftSevenPieces,
+	Handicap::RightSevenPieces,
+	Handicap::EightPieces,
+	Handicap::TenPieces,
+];
+impl Handicap {
+	// KIF/KI2の手合割の名前
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Handicap::Hirate => "平手",
+			Handicap::Kyou => "香落ち",
+			Handicap::RightKyou => "右香落ち",
+			Handicap::Kaku => "角落ち",
+			Handicap::Hisha => "飛車落ち",
+			Handicap::HishaKyou => "飛香落ち",
+			Handicap::TwoPieces => "二枚落ち",
+			Handicap::ThreePieces => "三枚落ち",
+			Handicap::FourPieces => "四枚落ち",
+			Handicap::FivePieces => "五枚落ち",
+			Handicap::LeftFivePieces => "左五枚落ち",
+			Handicap::SixPieces => "六枚落ち",
+			Handicap::LeftSevenPieces => "左七枚落ち",
+			Handicap::RightSevenPieces => "右七枚落ち",
+			Handicap::EightPieces => "八枚落ち",
+			Handicap::TenPieces => "十枚落ち",
+		}
+	}
+
+	pub fn from_name(name:&str) -> Option<Handicap> {
+		HANDICAPS.iter().find(|h| h.name() == name).cloned()
+	}
+
+	// 平手の初期局面から駒を落とす位置
+	fn squares(&self) -> &'static [(u32,u32)] {
+		match *self {
+			Handicap::Hirate => &[],
+			Handicap::Kyou => &[(1,1)],
+			Handicap::RightKyou => &[(9,1)],
+			Handicap::Kaku => &[(2,2)],
+			Handicap::Hisha => &[(8,2)],
+			Handicap::HishaKyou => &[(8,2),(1,1)],
+			Handicap::TwoPieces => &[(8,2),(2,2)],
+			Handicap::ThreePieces => &[(8,2),(2,2),(1,1)],
+			Handicap::FourPieces => &[(8,2),(2,2),(1,1),(9,1)],
+			Handicap::FivePieces => &[(8,2),(2,2),(1,1),(9,1),(8,1)],
+			Handicap::LeftFivePieces => &[(8,2),(2,2),(1,1),(9,1),(2,1)],
+			Handicap::SixPieces => &[(8,2),(2,2),(1,1),(9,1),(2,1),(8,1)],
+			Handicap::LeftSevenPieces => &[(8,2),(2,2),(1,1),(9,1),(2,1),(8,1),(3,1)],
+			Handicap::RightSevenPieces => &[(8,2),(2,2),(1,1),(9,1),(2,1),(8,1),(7,1)],
+			Handicap::EightPieces => &[(8,2),(2,2),(1,1),(9,1),(2,1),(8,1),(3,1),(7,1)],
+			Handicap::TenPieces => &[(8,2),(2,2),(1,1),(9,1),(2,1),(8,1),(3,1),(7,1),(4,1),(6,1)],
+		}
+	}
+
+	// 平手の初期局面から落とす駒(PI行の書式)
+	pub fn removed(&self) -> String {
+		let Banmen(kinds) = BANMEN_START_POS;
+
+		self.squares().iter().map(|&(x,y)| {
+			format!("{}{}{}",x,y,&kinds[y as usize - 1][9 - x as usize].to_csa()[1..])
+		}).collect()
+	}
+
+	pub fn to_csa(&self) -> String {
+		format!("PI{}",self.removed())
+	}
+
+	pub fn banmen(&self) -> Banmen {
+		let Banmen(mut kinds) = BANMEN_START_POS;
+
+		for &(x,y) in self.squares() {
+			kinds[y as usize - 1][9 - x as usize] = KomaKind::Blank;
+		}
+
+		Banmen(kinds)
+	}
+
+	// 駒落ちは上手(後手)から指す
+	pub fn teban(&self) -> Teban {
+		match *self {
+			Handicap::Hirate => Teban::Sente,
+			_ => Teban::Gote,
+		}
+	}
+
+	pub fn detect(banmen:&Banmen) -> Option<Handicap> {
+		HANDICAPS.iter().find(|h| h.banmen() == *banmen).cloned()
+	}
+}
+impl CsaData {
+	pub fn handicap(&self) -> Option<Handicap> {
+		if !self.initial_mochigoma.is_empty() {
+			return None;
+		}
+
+		Handicap::detect(&self.initial_position)
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use CsaParser;
+
+	#[test]
+	fn names_round_trip() {
+		for h in HANDICAPS.iter() {
+			assert_eq!(Handicap::from_name(h.name()),Some(*h));
+		}
+
+		assert_eq!(Handicap::from_name("九枚落ち"),None);
+	}
+
+	#[test]
+	fn presets_are_detected() {
+		assert_eq!(Handicap::Hirate.banmen(),BANMEN_START_POS.clone());
+
+		for h in HANDICAPS.iter() {
+			assert_eq!(Handicap::detect(&h.banmen()),Some(*h),"{}",h.name());
+		}
+
+		assert_eq!(Handicap::Hirate.teban(),Teban::Sente);
+		assert_eq!(Handicap::TenPieces.teban(),Teban::Gote);
+		assert_eq!(Handicap::TwoPieces.to_csa(),"PI82HI22KA");
+	}
+
+	#[test]
+	fn handicap_of_parsed_records() {
+		let handicap = |s:&str| CsaParser::parse_str(s).unwrap().remove(0).handicap();
+
+		assert_eq!(handicap("V2.2\nPI82HI22KA\n-\n"),Some(Handicap::TwoPieces));
+		assert_eq!(handicap("V2.2\nPI\n+\n"),Some(Handicap::Hirate));
+		assert_eq!(handicap("V2.2\nPI82HI22KA\nP+00HI\n-\n"),None);
+		assert_eq!(handicap("V2.2\nPI77FU\n+\n"),None);
+	}
+
+	#[test]
+	fn removed_pieces_of_each_preset() {
+		let removed = HANDICAPS.iter().map(|h| h.removed()).collect::<Vec<String>>();
+
+		assert_eq!(removed,vec![
+			"",
+			"11KY",
+			"91KY",
+			"22KA",
+			"82HI",
+			"82HI11KY",
+			"82HI22KA",
+			"82HI22KA11KY",
+			"82HI22KA11KY91KY",
+			"82HI22KA11KY91KY81KE",
+			"82HI22KA11KY91KY21KE",
+			"82HI22KA11KY91KY21KE81KE",
+			"82HI22KA11KY91KY21KE81KE31GI",
+			"82HI22KA11KY91KY21KE81KE71GI",
+			"82HI22KA11KY91KY21KE81KE31GI71GI",
+			"82HI22KA11KY91KY21KE81KE31GI71GI41KI61KI",
+		]);
+
+		// 落とした駒の位置は平手の初期局面から空になる
+		for h in HANDICAPS.iter() {
+			let parsed = CsaParser::parse_str(&format!("V2.2\n{}\n-\n",h.to_csa())).unwrap().remove(0);
+
+			assert_eq!(parsed.initial_position,h.banmen(),"{}",h.name());
+		}
+	}
+}
@@ -0,0 +1,771 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json;
+use serde_json::Map;
+use serde_json::Value;
+
+use usiagent::shogi::*;
+use usiagent::shogi::KomaKind::Blank;
+
+use CommentRouter;
+use CsaData;
+use CsaMove;
+use CsaMoves;
+use CsaMovesParser;
+use EndState;
+use handicap::Handicap;
+use moving_piece;
+use TryFromCsa;
+use kif::*;
+use writer::ToCsa;
+use error::*;
+
+// JKFのpresetと手合割の対応
+const PRESETS:[(&str,Handicap); 16] = [
+	("HIRATE",Handicap::Hirate),
+	("KY",Handicap::Kyou),
+	("KY_R",Handicap::RightKyou),
+	("KA",Handicap::Kaku),
+	("HI",Handicap::Hisha),
+	("HIKY",Handicap::HishaKyou),
+	("2",Handicap::TwoPieces),
+	("3",Handicap::ThreePieces),
+	("4",Handicap::FourPieces),
+	("5",Handicap::FivePieces),
+	("5_L",Handicap::LeftFivePieces),
+	("6",Handicap::SixPieces),
+	("7_L",Handicap::LeftSevenPieces),
+	("7_R",Handicap::RightSevenPieces),
+	("8",Handicap::EightPieces),
+	("10",Handicap::TenPieces),
+];
+const HAND_KINDS:[MochigomaKind; 7] = [
+	MochigomaKind::Fu,
+	MochigomaKind::Kyou,
+	MochigomaKind::Kei,
+	MochigomaKind::Gin,
+	MochigomaKind::Kin,
+	MochigomaKind::Kaku,
+	MochigomaKind::Hisha,
+];
+
+fn color(teban:Teban) -> u64 {
+	match teban {
+		Teban::Sente => 0,
+		Teban::Gote => 1,
+	}
+}
+// JKFの駒の種類はCSAの駒の種別から手番の符号を除いたもの
+fn piece_code(k:KomaKind) -> String {
+	k.to_csa()[1..].to_string()
+}
+fn bad_jkf(v:&Value) -> CsaParserError {
+	CsaParserError::FormatError(CsaFormatErrorKind::BadJkf,Some(SourceStatement::new(None,v.to_string())))
+}
+// 他の形式向けの解析処理のエラーの位置情報をJKFの要素に置き換える
+fn with_statement(e:CsaParserError,v:&Value) -> CsaParserError {
+	match e {
+		CsaParserError::FormatError(kind,_) => {
+			CsaParserError::FormatError(kind,Some(SourceStatement::new(None,v.to_string())))
+		},
+		e => e
+	}
+}
+fn number(v:&Value,key:&str) -> Result<u64,CsaParserError> {
+	v.get(key).and_then(Value::as_u64).ok_or_else(|| bad_jkf(v))
+}
+fn square(v:&Value) -> Result<(u32,u32),CsaParserError> {
+	let x = number(v,"x")?;
+	let y = number(v,"y")?;
+
+	if !(1..=9).contains(&x) || !(1..=9).contains(&y) {
+		return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadSquare,Some(SourceStatement::new(None,v.to_string()))));
+	}
+
+	Ok((x as u32,y as u32))
+}
+fn teban_of(v:&Value) -> Result<Teban,CsaParserError> {
+	match v.as_u64() {
+		Some(0) => Ok(Teban::Sente),
+		Some(1) => Ok(Teban::Gote),
+		_ => Err(bad_jkf(v)),
+	}
+}
+fn elapsed(v:&Value) -> Result<Option<Duration>,CsaParserError> {
+	match v.get("time") {
+		None => Ok(None),
+		Some(t) => {
+			let now = t.get("now").ok_or_else(|| bad_jkf(t))?;
+
+			let m = now.get("m").map(|_| number(now,"m")).unwrap_or(Ok(0))?;
+			let s = now.get("s").map(|_| number(now,"s")).unwrap_or(Ok(0))?;
+
+			Ok(Some(Duration::from_secs(m * 60 + s)))
+		}
+	}
+}
+fn time_value(t:Duration,total:Duration) -> Value {
+	let s = t.as_secs();
+	let total = total.as_secs();
+
+	json!({
+		"now":{ "m":s / 60, "s":s % 60 },
+		"total":{ "h":total / 3600, "m":total / 60 % 60, "s":total % 60 }
+	})
+}
+pub struct JkfParser {
+
+}
+impl JkfParser {
+	pub fn new() -> JkfParser {
+		JkfParser {
+
+		}
+	}
+
+	pub fn parse_str(s:&str) -> Result<CsaData,CsaParserError> {
+		JkfParser::new().parse(s)
+	}
+
+	pub fn parse_bytes(bytes:&[u8]) -> Result<CsaData,CsaParserError> {
+		JkfParser::new().parse(&KifParser::decode(bytes)?)
+	}
+
+	pub fn parse_file<P: AsRef<Path>>(path:P) -> Result<CsaData,CsaParserError> {
+		let mut bytes = Vec::new();
+
+		File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(CsaStreamReadError::from)?;
+
+		JkfParser::parse_bytes(&bytes)
+	}
+
+	pub fn parse(&mut self,s:&str) -> Result<CsaData,CsaParserError> {
+		let v = serde_json::from_str::<Value>(s).map_err(|e| {
+			let line = s.lines().nth(e.line().saturating_sub(1)).unwrap_or("");
+
+			CsaParserError::FormatError(CsaFormatErrorKind::BadJkf,Some(SourceStatement::new(
+				Some(SourceLocation::new(e.line() as u32,1,e.column() as u32)),line.to_string()
+			)))
+		})?;
+
+		self.parse_value(&v)
+	}
+
+	pub fn parse_value(&mut self,v:&Value) -> Result<CsaData,CsaParserError> {
+		let mut header = KifHeader::new();
+
+		if let Some(h) = v.get("header") {
+			let h = h.as_object().ok_or_else(|| bad_jkf(h))?;
+
+			for (key,value) in h {
+				let s = value.as_str().ok_or_else(|| bad_jkf(value))?;
+
+				header.parse_line(0,&format!("{}：{}",key,s)).map_err(|e| with_statement(e,value))?;
+			}
+		}
+
+		let (banmen,mc,teban) = match v.get("initial") {
+			None => header.position(0,"").map_err(|e| with_statement(e,v))?,
+			Some(initial) => {
+				let preset = initial.get("preset").and_then(Value::as_str).ok_or_else(|| bad_jkf(initial))?;
+
+				match initial.get("data") {
+					Some(data) => self.parse_initial_data(data)?,
+					None => {
+						let h = PRESETS.iter().find(|&&(p,_)| p == preset).map(|&(_,h)| h).ok_or_else(|| {
+							CsaParserError::FormatError(CsaFormatErrorKind::UnknownHandicap,Some(SourceStatement::new(None,initial.to_string())))
+						})?;
+
+						(h.banmen(),MochigomaCollections::Empty,h.teban())
+					}
+				}
+			}
+		};
+
+		let Banmen(mut kinds) = banmen.clone();
+		let mut hands = mc.clone();
+		let mut current = teban;
+		let mut prev = None;
+		let mut comments = CommentRouter::new();
+		let mut mvs = CsaMoves::new();
+		let mut end_state = None;
+		let parser = CsaMovesParser::new(false,false);
+
+		let entries = match v.get("moves") {
+			None => Vec::new(),
+			Some(m) => m.as_array().ok_or_else(|| bad_jkf(m))?.clone(),
+		};
+
+		for (i,entry) in entries.iter().enumerate() {
+			let texts = match entry.get("comments") {
+				None => Vec::new(),
+				Some(c) => {
+					c.as_array().ok_or_else(|| bad_jkf(c))?.iter().map(|c| {
+						c.as_str().map(|c| c.to_string()).ok_or_else(|| bad_jkf(c))
+					}).collect::<Result<Vec<String>,CsaParserError>>()?
+				}
+			};
+
+			// 先頭の要素は開始局面
+			if i == 0 {
+				for c in texts {
+					comments.push(c,false,&mut mvs,false);
+				}
+				continue;
+			}
+
+			if let Some(m) = entry.get("move") {
+				if let Some(c) = m.get("color") {
+					if teban_of(c)? != current {
+						return Err(CsaParserError::FormatError(CsaFormatErrorKind::WrongSideToMove,
+																Some(SourceStatement::new(None,m.to_string()))));
+					}
+				}
+
+				let (dx,dy) = match m.get("to") {
+					Some(to) => square(to)?,
+					None if m.get("same").and_then(Value::as_bool) == Some(true) => prev.ok_or_else(|| bad_jkf(m))?,
+					None => {
+						return Err(bad_jkf(m));
+					}
+				};
+
+				let piece = m.get("piece").and_then(Value::as_str).ok_or_else(|| bad_jkf(m))?.to_string();
+
+				let line = match m.get("from") {
+					Some(from) => {
+						let (sx,sy) = square(from)?;
+
+						let k = KomaKind::try_from_csa((current,&piece)).map_err(|e| with_statement(e,m))?;
+
+						let k = if m.get("promote").and_then(Value::as_bool) == Some(true) {
+							k.to_nari()
+						} else {
+							k
+						};
+
+						format!("{}{}{}{}{}{}",current.to_csa(),sx,sy,dx,dy,piece_code(k))
+					},
+					None => format!("{}00{}{}{}",current.to_csa(),dx,dy,piece),
+				};
+
+				let mv = parser.parse_move(&line,current,&kinds,true).map_err(|e| with_statement(e,m))?;
+
+				CsaMovesParser::apply_move(&mut kinds,&mut hands,current,&mv);
+
+				mvs.push(CsaMove::Move(mv,elapsed(entry)?))?;
+				prev = Some((dx,dy));
+				current = current.opposite();
+			} else if let Some(s) = entry.get("special") {
+				let name = s.as_str().ok_or_else(|| bad_jkf(s))?;
+
+				match name {
+					"KACHI" => {
+						mvs.push(CsaMove::Kachi(elapsed(entry)?))?;
+					},
+					"HIKIWAKE" => {
+						mvs.push(CsaMove::Hikiwake(elapsed(entry)?))?;
+					},
+					_ => {
+						end_state = Some(EndState::try_from_csa(&format!("%{}",name)).map_err(|e| with_statement(e,s))?);
+
+						// 終局状態のコメントは直前の指し手ではなく終局状態に割り当てる
+						for c in texts {
+							comments.push(c,false,&mut mvs,true);
+						}
+						break;
+					}
+				}
+			} else if entry.as_object().map(|o| o.keys().all(|k| k == "comments")).unwrap_or(false) {
+				// コメントのみの要素は直前の指し手(最初の指し手より前なら開始局面)のコメントとする
+			} else {
+				return Err(bad_jkf(entry));
+			}
+
+			for c in texts {
+				comments.push(c,false,&mut mvs,false);
+			}
+		}
+
+		let mut data = CsaData::new(None,header.info,teban,banmen,mc,mvs,end_state,Vec::new());
+
+		comments.assign(&mut data);
+
+		Ok(data)
+	}
+
+	fn parse_initial_data(&self,data:&Value) -> Result<(Banmen,MochigomaCollections,Teban),CsaParserError> {
+		let teban = teban_of(data.get("color").ok_or_else(|| bad_jkf(data))?)?;
+
+		let board = data.get("board").and_then(Value::as_array).ok_or_else(|| bad_jkf(data))?;
+
+		if board.len() != 9 {
+			return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadBoardRow,Some(SourceStatement::new(None,data.to_string()))));
+		}
+
+		let mut kinds = [[Blank; 9]; 9];
+
+		// boardは筋ごとの配列
+		for (x,file) in board.iter().enumerate() {
+			let file = file.as_array().ok_or_else(|| bad_jkf(file))?;
+
+			if file.len() != 9 {
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadBoardRow,Some(SourceStatement::new(None,data.to_string()))));
+			}
+
+			for (y,p) in file.iter().enumerate() {
+				if p.as_object().map(|p| p.is_empty()) == Some(true) {
+					continue;
+				}
+
+				let t = teban_of(p.get("color").ok_or_else(|| bad_jkf(p))?)?;
+				let kind = p.get("kind").and_then(Value::as_str).ok_or_else(|| bad_jkf(p))?.to_string();
+
+				kinds[y][8 - x] = KomaKind::try_from_csa((t,&kind)).map_err(|e| with_statement(e,p))?;
+			}
+		}
+
+		let mut pair = (Mochigoma::new(),Mochigoma::new());
+
+		if let Some(hands) = data.get("hands") {
+			let hands = hands.as_array().ok_or_else(|| bad_jkf(hands))?;
+
+			if hands.len() != 2 {
+				return Err(bad_jkf(data));
+			}
+
+			for (ms,h) in [&mut pair.0,&mut pair.1].iter_mut().zip(hands.iter()) {
+				let h = h.as_object().ok_or_else(|| bad_jkf(h))?;
+
+				for (kind,n) in h {
+					let n = n.as_u64().ok_or_else(|| bad_jkf(n))?;
+
+					if n > 0 {
+						let k = MochigomaKind::try_from_csa(kind).map_err(|e| with_statement(e,data))?;
+
+						ms.insert(k,n as usize);
+					}
+				}
+			}
+		}
+
+		Ok((Banmen(kinds),MochigomaCollections::new(pair.0,pair.1),teban))
+	}
+}
+impl Default for JkfParser {
+	fn default() -> JkfParser {
+		JkfParser::new()
+	}
+}
+pub struct JkfWriter<W> where W: Write {
+	writer:W,
+}
+impl<W> JkfWriter<W> where W: Write {
+	pub fn new(writer:W) -> JkfWriter<W> {
+		JkfWriter {
+			writer,
+		}
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	pub fn write(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		let v = data.to_jkf_value()?;
+
+		serde_json::to_writer_pretty(&mut self.writer,&v).map_err(io::Error::from)?;
+		writeln!(self.writer)?;
+
+		Ok(())
+	}
+}
+impl CsaData {
+	pub fn from_jkf(s:&str) -> Result<CsaData,CsaParserError> {
+		JkfParser::parse_str(s)
+	}
+
+	// %MAX_MOVESは持将棋、%+FOUL/%-FOULは反則行為として書き出すため、読み戻すと元の終局状態にはならない
+	pub fn to_jkf(&self) -> Result<String,CsaWriterError> {
+		let mut writer = JkfWriter::new(Vec::new());
+
+		writer.write(self)?;
+
+		String::from_utf8(writer.into_inner()).map_err(|_| {
+			CsaWriterError::InvalidStateError(String::from("The output is not valid utf-8."))
+		})
+	}
+
+	pub fn to_jkf_value(&self) -> Result<Value,CsaWriterError> {
+		let handicap = handicap_of(self);
+
+		let mut jkf = Map::new();
+
+		jkf.insert(String::from("header"),Value::Object(self.jkf_header(handicap)));
+
+		let initial = match handicap.and_then(|h| PRESETS.iter().find(|&&(_,p)| p == h)) {
+			Some(&(preset,_)) => json!({ "preset":preset }),
+			None => json!({ "preset":"OTHER", "data":self.jkf_initial_data() }),
+		};
+
+		jkf.insert(String::from("initial"),initial);
+		jkf.insert(String::from("moves"),Value::Array(self.jkf_moves()?));
+
+		Ok(Value::Object(jkf))
+	}
+
+	fn jkf_header(&self,handicap:Option<Handicap>) -> Map<String,Value> {
+		let info = self.kifu_info.clone().unwrap_or_default();
+
+		let (sente,gote) = match handicap {
+			Some(h) if h != Handicap::Hirate => ("下手","上手"),
+			_ => ("先手","後手"),
+		};
+
+		let mut header = Map::new();
+
+		let items = [
+			(sente,info.sente_name),
+			(gote,info.gote_name),
+			("棋戦",info.event),
+			("場所",info.site),
+			("開始日時",info.start_time),
+			("終了日時",info.end_time),
+			("持ち時間",info.time_limit.map(|(m,_)| time_limit_text(m))),
+			("秒読み",info.time_limit.and_then(|(_,s)| s).map(|s| format!("{}秒",s))),
+			("手合割",handicap.map(|h| h.name().to_string())),
+			("戦型",info.opening),
+		];
+
+		for (key,value) in items.iter() {
+			if let Some(ref value) = *value {
+				header.insert(key.to_string(),Value::String(value.clone()));
+			}
+		}
+
+		header
+	}
+
+	fn jkf_initial_data(&self) -> Value {
+		let Banmen(ref kinds) = self.initial_position;
+
+		let board = (0..9).map(|x| {
+			Value::Array((0..9).map(|y| {
+				let k = kinds[y][8 - x];
+
+				if k == Blank {
+					json!({})
+				} else {
+					let t = if k.to_csa().starts_with('+') {
+						Teban::Sente
+					} else {
+						Teban::Gote
+					};
+
+					json!({ "color":color(t), "kind":piece_code(k) })
+				}
+			}).collect())
+		}).collect::<Vec<Value>>();
+
+		let empty = Mochigoma::new();
+
+		let (ms,mg) = match self.initial_mochigoma {
+			MochigomaCollections::Pair(ref ms,ref mg) => (ms,mg),
+			MochigomaCollections::Empty => (&empty,&empty),
+		};
+
+		let hands = [ms,mg].iter().map(|m| {
+			Value::Object(HAND_KINDS.iter().map(|&k| {
+				(k.to_csa(),json!(m.get(k)))
+			}).collect())
+		}).collect::<Vec<Value>>();
+
+		json!({
+			"color":color(self.teban_at_start),
+			"board":board,
+			"hands":hands
+		})
+	}
+
+	fn jkf_moves(&self) -> Result<Vec<Value>,CsaWriterError> {
+		// JKFには棋譜情報の前のコメントを置く場所が無いので開始局面のコメントにまとめる
+		let initial_comments = self.header_comments.iter().chain(self.position_comments.iter()).cloned().collect::<Vec<String>>();
+
+		let mut first = Map::new();
+
+		if !initial_comments.is_empty() {
+			first.insert(String::from("comments"),json!(initial_comments));
+		}
+
+		let mut entries = vec![Value::Object(first)];
+
+		let Banmen(mut kinds) = self.initial_position.clone();
+		let mut mc = self.initial_mochigoma.clone();
+		let mut teban = self.teban_at_start;
+		let mut prev = None;
+		let mut totals = [Duration::from_secs(0); 2];
+
+		for (i,m) in self.moves.iter().enumerate() {
+			let mut entry = Map::new();
+
+			let t = match *m {
+				CsaMove::Move(ref m,t) => {
+					entry.insert(String::from("move"),self.jkf_move(&kinds,teban,m,prev)?);
+
+					prev = match *m {
+						Move::To(_,KomaDstToPosition(dx,dy,_)) => Some((dx,dy)),
+						Move::Put(_,KomaDstPutPosition(dx,dy)) => Some((dx,dy)),
+					};
+
+					CsaMovesParser::apply_move(&mut kinds,&mut mc,teban,m);
+
+					t
+				},
+				CsaMove::Kachi(t) => {
+					entry.insert(String::from("special"),json!("KACHI"));
+					t
+				},
+				CsaMove::Hikiwake(t) => {
+					entry.insert(String::from("special"),json!("HIKIWAKE"));
+					t
+				}
+			};
+
+			if let Some(t) = t {
+				let total = match teban {
+					Teban::Sente => &mut totals[0],
+					Teban::Gote => &mut totals[1],
+				};
+
+				*total += t;
+
+				entry.insert(String::from("time"),time_value(t,*total));
+			}
+
+			if !self.moves.comments(i).is_empty() {
+				entry.insert(String::from("comments"),json!(self.moves.comments(i)));
+			}
+
+			entries.push(Value::Object(entry));
+
+			teban = teban.opposite();
+		}
+
+		if let Some(s) = self.end_state {
+			let mut entry = Map::new();
+
+			// JKFに無い終局状態は最も近いものとして書き出す
+			let name = match s {
+				EndState::MaxMoves => String::from("JISHOGI"),
+				EndState::SFoul => String::from("+ILLEGAL_ACTION"),
+				EndState::GFoul => String::from("-ILLEGAL_ACTION"),
+				s => s.to_csa()[1..].to_string(),
+			};
+
+			entry.insert(String::from("special"),json!(name));
+
+			if !self.end_state_comments.is_empty() {
+				entry.insert(String::from("comments"),json!(self.end_state_comments));
+			}
+
+			entries.push(Value::Object(entry));
+		}
+
+		Ok(entries)
+	}
+
+	fn jkf_move(&self,kinds:&[[KomaKind; 9]; 9],teban:Teban,m:&Move,prev:Option<(u32,u32)>) -> Result<Value,CsaWriterError> {
+		let k = moving_piece(kinds,m)?.unwrap_or(Blank);
+
+		let mut v = Map::new();
+
+		v.insert(String::from("color"),json!(color(teban)));
+
+		match *m {
+			Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+				v.insert(String::from("from"),json!({ "x":sx, "y":sy }));
+				v.insert(String::from("to"),json!({ "x":dx, "y":dy }));
+				v.insert(String::from("piece"),json!(piece_code(k)));
+
+				if prev == Some((dx,dy)) {
+					v.insert(String::from("same"),json!(true));
+				}
+
+				let promotable = !k.is_nari() && k.to_nari() != k &&
+									(in_promotion_zone(teban,sy) || in_promotion_zone(teban,dy));
+
+				if promotable {
+					v.insert(String::from("promote"),json!(n));
+				}
+
+				let captured = kinds[dy as usize - 1][9 - dx as usize];
+
+				if captured != Blank {
+					v.insert(String::from("capture"),json!(piece_code(captured)));
+				}
+			},
+			Move::Put(k,KomaDstPutPosition(dx,dy)) => {
+				v.insert(String::from("to"),json!({ "x":dx, "y":dy }));
+				v.insert(String::from("piece"),json!(k.to_csa()));
+			}
+		}
+
+		Ok(Value::Object(v))
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use CsaParser;
+
+	#[test]
+	fn special_comments_before_any_move_belong_to_end_state() {
+		let data = CsaData::from_jkf(r#"{"header":{},"moves":[{},{"special":"CHUDAN","comments":["x"]}]}"#).unwrap();
+
+		assert_eq!(data.moves.len(),0);
+		assert_eq!(data.end_state,Some(EndState::Chudan));
+		assert_eq!(data.end_state_comments,vec![String::from("x")]);
+		assert!(data.position_comments.is_empty());
+		assert_eq!(data.comments(),vec![String::from("x")]);
+	}
+
+	#[test]
+	fn special_comments_are_not_attached_to_the_last_move() {
+		let data = CsaData::from_jkf(r#"{"header":{},"moves":[
+			{"comments":["start"]},
+			{"move":{"color":0,"from":{"x":7,"y":7},"to":{"x":7,"y":6},"piece":"FU"},"comments":["move"]},
+			{"special":"TORYO","comments":["end"]}
+		]}"#).unwrap();
+
+		assert_eq!(data.position_comments,vec![String::from("start")]);
+		assert_eq!(data.moves.comments(0),&[String::from("move")]);
+		assert_eq!(data.end_state_comments,vec![String::from("end")]);
+
+		let written = CsaData::from_jkf(&data.to_jkf().unwrap()).unwrap();
+
+		assert_eq!(written.end_state_comments,data.end_state_comments);
+		assert_eq!(written.moves.comments(0),data.moves.comments(0));
+	}
+
+	#[test]
+	fn comments_before_the_first_move_belong_to_the_position() {
+		let data = CsaData::from_jkf(r#"{"header":{},"moves":[{},{"comments":["x"]}]}"#).unwrap();
+
+		assert_eq!(data.position_comments,vec![String::from("x")]);
+	}
+
+	#[test]
+	fn comment_only_entry_belongs_to_the_previous_move() {
+		let data = CsaData::from_jkf(r#"{"header":{},"moves":[
+			{},
+			{"move":{"color":0,"from":{"x":7,"y":7},"to":{"x":7,"y":6},"piece":"FU"},"comments":["a"]},
+			{"comments":["b"]}
+		]}"#).unwrap();
+
+		assert_eq!(data.moves.len(),1);
+		assert_eq!(data.moves.comments(0),&["a","b"]);
+	}
+
+	#[test]
+	fn end_states_without_jkf_specials() {
+		let special = |s:&str| {
+			let data = CsaParser::parse_str(&format!("V3.0\nPI\n+\n+7776FU\n{}\n",s)).unwrap().remove(0);
+
+			data.to_jkf_value().unwrap()["moves"][2]["special"].clone()
+		};
+
+		assert_eq!(special("%MAX_MOVES"),json!("JISHOGI"));
+		assert_eq!(special("%+FOUL"),json!("+ILLEGAL_ACTION"));
+		assert_eq!(special("%-FOUL"),json!("-ILLEGAL_ACTION"));
+		assert_eq!(special("%TORYO"),json!("TORYO"));
+	}
+
+	#[test]
+	fn parse_jkf_document() {
+		let data = CsaData::from_jkf(r#"{
+			"header":{"先手":"sente","後手":"gote","棋戦":"test"},
+			"initial":{"preset":"HIRATE"},
+			"moves":[
+				{},
+				{"move":{"color":0,"from":{"x":7,"y":7},"to":{"x":7,"y":6},"piece":"FU"},"time":{"now":{"m":0,"s":3},"total":{"h":0,"m":0,"s":3}}},
+				{"move":{"color":1,"from":{"x":3,"y":3},"to":{"x":3,"y":4},"piece":"FU"}},
+				{"move":{"color":0,"from":{"x":8,"y":8},"to":{"x":2,"y":2},"piece":"KA","promote":true}},
+				{"move":{"color":1,"from":{"x":3,"y":1},"same":true,"piece":"GI"}},
+				{"move":{"color":0,"to":{"x":4,"y":5},"piece":"KA"}},
+				{"special":"KACHI"}
+			]
+		}"#).unwrap();
+
+		let csa = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU,T3\n-3334FU\n+8822UM\n-3122GI\n+0045KA\n%KACHI\n").unwrap().remove(0);
+
+		let info = data.kifu_info.as_ref().unwrap();
+
+		assert_eq!(info.sente_name,Some(String::from("sente")));
+		assert_eq!(info.gote_name,Some(String::from("gote")));
+		assert_eq!(info.event,Some(String::from("test")));
+		assert_eq!(data.initial_position,csa.initial_position);
+		assert_eq!(data.moves,csa.moves);
+		assert_eq!(data.end_state,None);
+	}
+
+	#[test]
+	fn handicap_and_board_round_trip() {
+		let handicap = CsaParser::parse_str("V2.2\nPI82HI22KA\n-\n-3334FU\n+7776FU\n%TORYO\n").unwrap().remove(0);
+
+		let board = CsaParser::parse_str(concat!(
+			"V2.2\n",
+			"P1 *  *  *  * -OU *  *  *  * \n",
+			"P2 *  *  *  *  *  *  *  *  * \n",
+			"P3 *  *  *  *  *  *  *  *  * \n",
+			"P4 *  *  *  *  *  *  *  *  * \n",
+			"P5 *  *  *  *  *  *  *  *  * \n",
+			"P6 *  *  *  *  *  *  *  *  * \n",
+			"P7 *  *  *  *  *  *  *  *  * \n",
+			"P8 *  *  *  *  *  *  *  *  * \n",
+			"P9 *  *  *  * +OU *  *  *  * \n",
+			"P+00KI00FU\n",
+			"P-00HI\n",
+			"+\n",
+			"+0052KI\n-5142OU\n"
+		)).unwrap().remove(0);
+
+		for data in &[handicap,board] {
+			let s = data.to_jkf().unwrap();
+			let parsed = CsaData::from_jkf(&s).unwrap();
+
+			assert_eq!(parsed.initial_position,data.initial_position,"{}",s);
+			assert_eq!(parsed.initial_mochigoma,data.initial_mochigoma,"{}",s);
+			assert_eq!(parsed.teban_at_start,data.teban_at_start,"{}",s);
+			assert_eq!(parsed.moves,data.moves,"{}",s);
+			assert_eq!(parsed.end_state,data.end_state,"{}",s);
+		}
+	}
+
+	#[test]
+	fn reject_invalid_documents() {
+		let error = |s:&str| match CsaData::from_jkf(s) {
+			Err(CsaParserError::FormatError(k,_)) => Some(k),
+			_ => None,
+		};
+
+		assert_eq!(error(r#"{"header":{},"moves":[{},{"move":{"color":1,"from":{"x":3,"y":3},"to":{"x":3,"y":4},"piece":"FU"}}]}"#),
+					Some(CsaFormatErrorKind::WrongSideToMove));
+		assert_eq!(error(r#"{"header":{},"moves":[{},{"special":"RESIGN"}]}"#),Some(CsaFormatErrorKind::UnknownEndState));
+		assert_eq!(error(r#"{"header":{},"moves":[{},{"move":{"color":0,"same":true,"piece":"FU"}}]}"#),Some(CsaFormatErrorKind::BadJkf));
+		assert_eq!(error(r#"{"header":{},"moves":{}}"#),Some(CsaFormatErrorKind::BadJkf));
+	}
+
+	#[test]
+	fn writer_rejects_moves_without_a_piece() {
+		let mut data = CsaParser::parse_str("V2.2\nPI\n+\n").unwrap().remove(0);
+
+		data.moves.push(CsaMove::Move(Move::To(KomaSrcPosition(5,5),KomaDstToPosition(5,4,false)),None)).unwrap();
+
+		assert!(data.to_jkf().is_err());
+	}
+}
@@ -0,0 +1,794 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::convert::TryFrom;
+
+use usiagent::shogi::*;
+use usiagent::shogi::KomaKind::{
+	SFu,
+	SKyou,
+	SKei,
+	SGin,
+	SKin,
+	SKaku,
+	SHisha,
+	SOu,
+	SFuN,
+	SKyouN,
+	SKeiN,
+	SGinN,
+	SKakuN,
+	SHishaN,
+	Blank
+};
+
+use CommentRouter;
+use CsaData;
+use CsaMove;
+use CsaMoves;
+use CsaMovesParser;
+use EndState;
+use handicap::Handicap;
+use moving_piece;
+use kif::*;
+use error::*;
+
+const DIAGONALS:[(i32,i32); 4] = [(1,1),(1,-1),(-1,1),(-1,-1)];
+const ORTHOGONALS:[(i32,i32); 4] = [(0,1),(0,-1),(1,0),(-1,0)];
+
+// 駒の動きだけで移動元から移動先へ行けるかを返す(自玉への王手は考慮しない)
+fn can_reach(kinds:&[[KomaKind; 9]; 9],k:KomaKind,teban:Teban,src:(u32,u32),dst:(u32,u32)) -> bool {
+	let f = match teban {
+		Teban::Sente => -1,
+		Teban::Gote => 1,
+	};
+
+	let vx = dst.0 as i32 - src.0 as i32;
+	let vy = dst.1 as i32 - src.1 as i32;
+
+	let steps:&[(i32,i32)] = match sided(k,Teban::Sente) {
+		SFu => &[(0,f)],
+		SKei => &[(-1,2 * f),(1,2 * f)],
+		SGin => &[(0,f),(-1,f),(1,f),(-1,-f),(1,-f)],
+		SKin | SFuN | SKyouN | SKeiN | SGinN => &[(0,f),(-1,f),(1,f),(-1,0),(1,0),(0,-f)],
+		SOu => &[(0,1),(0,-1),(1,0),(-1,0),(1,1),(1,-1),(-1,1),(-1,-1)],
+		SKakuN => &ORTHOGONALS,
+		SHishaN => &DIAGONALS,
+		_ => &[],
+	};
+
+	if steps.contains(&(vx,vy)) {
+		return true;
+	}
+
+	let slides:&[(i32,i32)] = match sided(k,Teban::Sente) {
+		SKyou => &[(0,f)],
+		SKaku | SKakuN => &DIAGONALS,
+		SHisha | SHishaN => &ORTHOGONALS,
+		_ => &[],
+	};
+
+	slides.iter().any(|&(ux,uy)| {
+		let n = vx.abs().max(vy.abs());
+
+		if n == 0 || (vx,vy) != (ux * n,uy * n) {
+			return false;
+		}
+
+		(1..n).all(|i| {
+			let x = src.0 as i32 + ux * i;
+			let y = src.1 as i32 + uy * i;
+
+			kinds[y as usize - 1][9 - x as usize] == Blank
+		})
+	})
+}
+// 移動先へ動ける同じ種類の駒の位置
+fn candidates(kinds:&[[KomaKind; 9]; 9],k:KomaKind,teban:Teban,dst:(u32,u32)) -> Vec<(u32,u32)> {
+	let k = sided(k,teban);
+	let mut cands = Vec::new();
+
+	for y in 1..10 {
+		for x in 1..10 {
+			if kinds[y as usize - 1][9 - x as usize] == k && can_reach(kinds,k,teban,(x,y),dst) {
+				cands.push((x,y));
+			}
+		}
+	}
+
+	cands
+}
+// 前に進む手は正、後ろに下がる手は負
+fn forward(teban:Teban,src:(u32,u32),dst:(u32,u32)) -> i32 {
+	match teban {
+		Teban::Sente => src.1 as i32 - dst.1 as i32,
+		Teban::Gote => dst.1 as i32 - src.1 as i32,
+	}
+}
+// 手番側から見て右にある駒ほど大きい
+fn rightness(teban:Teban,src:(u32,u32)) -> i32 {
+	match teban {
+		Teban::Sente => -(src.0 as i32),
+		Teban::Gote => src.0 as i32,
+	}
+}
+fn vertical(teban:Teban,src:(u32,u32),dst:(u32,u32)) -> char {
+	match forward(teban,src,dst) {
+		f if f > 0 => '上',
+		f if f < 0 => '引',
+		_ => '寄',
+	}
+}
+fn disambiguator(cands:&[(u32,u32)],k:KomaKind,teban:Teban,src:(u32,u32),dst:(u32,u32)) -> Option<String> {
+	if cands.len() <= 1 {
+		return Some(String::new());
+	}
+
+	let d = vertical(teban,src,dst);
+	let same_dir = cands.iter().cloned().filter(|&c| vertical(teban,c,dst) == d).collect::<Vec<(u32,u32)>>();
+
+	if same_dir.len() == 1 {
+		return Some(d.to_string());
+	}
+
+	let straight = |c:(u32,u32)| c.0 == dst.0 && forward(teban,c,dst) > 0;
+
+	if straight(src) && sided(k,Teban::Sente) != SKakuN && sided(k,Teban::Sente) != SHishaN &&
+		cands.iter().filter(|&&c| straight(c)).count() == 1 {
+		return Some(String::from("直"));
+	}
+
+	let side = |cands:&[(u32,u32)]| {
+		let r = rightness(teban,src);
+
+		if cands.iter().all(|&c| c == src || rightness(teban,c) < r) {
+			Some('右')
+		} else if cands.iter().all(|&c| c == src || rightness(teban,c) > r) {
+			Some('左')
+		} else {
+			None
+		}
+	};
+
+	if let Some(s) = side(cands) {
+		return Some(s.to_string());
+	}
+
+	side(&same_dir).map(|s| format!("{}{}",s,d))
+}
+fn side_of(s:&str) -> Option<Teban> {
+	match s {
+		"先手" | "下手" => Some(Teban::Sente),
+		"後手" | "上手" => Some(Teban::Gote),
+		_ => None,
+	}
+}
+fn illegal_action(teban:Teban) -> EndState {
+	match teban {
+		Teban::Sente => EndState::SIllegalAction,
+		Teban::Gote => EndState::GIllegalAction,
+	}
+}
+pub struct Ki2Parser {
+	line:u32,
+}
+impl Ki2Parser {
+	pub fn new() -> Ki2Parser {
+		Ki2Parser {
+			line:0,
+		}
+	}
+
+	pub fn parse_str(s:&str) -> Result<CsaData,CsaParserError> {
+		Ki2Parser::new().parse(s)
+	}
+
+	pub fn parse_bytes(bytes:&[u8]) -> Result<CsaData,CsaParserError> {
+		Ki2Parser::new().parse(&KifParser::decode(bytes)?)
+	}
+
+	pub fn parse_file<P: AsRef<Path>>(path:P) -> Result<CsaData,CsaParserError> {
+		let mut bytes = Vec::new();
+
+		File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(CsaStreamReadError::from)?;
+
+		Ki2Parser::parse_bytes(&bytes)
+	}
+
+	fn create_error(&self,kind:CsaFormatErrorKind,line:&str) -> CsaParserError {
+		format_error(kind,self.line,line)
+	}
+
+	fn parse_move(&self,text:&str,line:&str,kinds:&[[KomaKind; 9]; 9],mc:&MochigomaCollections,
+					teban:Teban,prev:Option<(u32,u32)>) -> Result<(Move,(u32,u32)),CsaParserError> {
+
+		let (dst,s) = if let Some(s) = text.strip_prefix('同') {
+			let dst = prev.ok_or_else(|| self.create_error(CsaFormatErrorKind::BadMoveNotation,line))?;
+
+			(dst,s.trim_start_matches(['　',' ']))
+		} else {
+			parse_square(text).ok_or_else(|| self.create_error(CsaFormatErrorKind::BadMoveNotation,line))?
+		};
+
+		if dst.0 < 1 || dst.0 > 9 || dst.1 < 1 || dst.1 > 9 {
+			return Err(self.create_error(CsaFormatErrorKind::BadSquare,line));
+		}
+
+		let (k,s) = parse_piece_name(s).ok_or_else(|| self.create_error(CsaFormatErrorKind::UnknownPieceCode,line))?;
+
+		let mut side = None;
+		let mut dir = None;
+		let mut nari = false;
+		let mut put = false;
+
+		let mut chars = s.chars();
+
+		while let Some(c) = chars.next() {
+			match c {
+				'右' | '左' if side.is_none() => side = Some(c),
+				'上' | '行' | '引' | '寄' | '直' if dir.is_none() => dir = Some(if c == '行' { '上' } else { c }),
+				'成' => nari = true,
+				'生' => nari = false,
+				'不' if chars.next() == Some('成') => nari = false,
+				'打' => put = true,
+				_ => {
+					return Err(self.create_error(CsaFormatErrorKind::BadMoveNotation,line));
+				}
+			}
+		}
+
+		let mut cands = if put {
+			Vec::new()
+		} else {
+			candidates(kinds,k,teban,dst)
+		};
+
+		if cands.is_empty() {
+			// 盤上に動ける駒が無い場合は打つ手とみなす
+			let in_hand = match (teban,mc) {
+				(_,&MochigomaCollections::Empty) => 0,
+				(Teban::Sente,MochigomaCollections::Pair(ms,_)) => MochigomaKind::try_from(k).map(|m| ms.get(m)).unwrap_or(0),
+				(Teban::Gote,MochigomaCollections::Pair(_,mg)) => MochigomaKind::try_from(k).map(|m| mg.get(m)).unwrap_or(0),
+			};
+
+			if k.is_nari() || nari || side.is_some() || dir.is_some() || in_hand == 0 {
+				return Err(self.create_error(CsaFormatErrorKind::NoPieceToMove,line));
+			}
+
+			let m = MochigomaKind::try_from(k).map_err(|_| self.create_error(CsaFormatErrorKind::BadMoveNotation,line))?;
+
+			return Ok((Move::Put(m,KomaDstPutPosition(dst.0,dst.1)),dst));
+		}
+
+		match dir {
+			Some('直') => cands.retain(|&c| c.0 == dst.0 && forward(teban,c,dst) > 0),
+			Some(d) => cands.retain(|&c| vertical(teban,c,dst) == d),
+			None => (),
+		}
+
+		if let Some(s) = side {
+			let r = cands.iter().map(|&c| rightness(teban,c));
+
+			let r = if s == '右' {
+				r.max()
+			} else {
+				r.min()
+			};
+
+			cands.retain(|&c| Some(rightness(teban,c)) == r);
+		}
+
+		if cands.len() != 1 {
+			return Err(self.create_error(CsaFormatErrorKind::BadMoveNotation,line));
+		}
+
+		let (sx,sy) = cands[0];
+
+		Ok((Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dst.0,dst.1,nari)),dst))
+	}
+
+	fn parse_result(&self,line:&str,teban:Teban) -> Result<(Option<EndState>,Option<CsaMove>),CsaParserError> {
+		let s = match line.find("手で") {
+			Some(i) => &line[i + "手で".len()..],
+			None => {
+				return Ok((None,None));
+			}
+		};
+
+		let side = s.find('の').and_then(|i| side_of(&s[..i]));
+
+		Ok(match s {
+			"中断" => (Some(EndState::Chudan),None),
+			"千日手" => (Some(EndState::Sennichite),None),
+			"持将棋" => (Some(EndState::Jishogi),None),
+			"引き分け" => (None,Some(CsaMove::Hikiwake(None))),
+			"詰み" => (Some(EndState::Tsumi),None),
+			"不詰" => (Some(EndState::Fuzumi),None),
+			s if s.ends_with("入玉勝ち") => (None,Some(CsaMove::Kachi(None))),
+			s if s.ends_with("反則勝ち") => {
+				let side = side.ok_or_else(|| self.create_error(CsaFormatErrorKind::UnknownEndState,line))?;
+
+				(Some(illegal_action(side.opposite())),None)
+			},
+			s if s.ends_with("反則負け") => {
+				let side = side.ok_or_else(|| self.create_error(CsaFormatErrorKind::UnknownEndState,line))?;
+
+				if side == teban {
+					(Some(EndState::IllegalMove),None)
+				} else {
+					(Some(illegal_action(side)),None)
+				}
+			},
+			s if s.ends_with("切れ負け") || s.starts_with("時間切れ") => (Some(EndState::TimeUp),None),
+			s if s.ends_with("勝ち") => (Some(EndState::Toryo),None),
+			_ => {
+				return Err(self.create_error(CsaFormatErrorKind::UnknownEndState,line));
+			}
+		})
+	}
+
+	pub fn parse(&mut self,s:&str) -> Result<CsaData,CsaParserError> {
+		let mut header = KifHeader::new();
+
+		let mut comments = CommentRouter::new();
+
+		let mut position:Option<(Banmen,MochigomaCollections,Teban)> = None;
+		let mut banmen = [[Blank; 9]; 9];
+		let mut mc = MochigomaCollections::Empty;
+		let mut teban = Teban::Sente;
+		let mut prev = None;
+		let mut mvs = CsaMoves::new();
+		let mut end_state = None;
+
+		for (n,line) in s.lines().enumerate() {
+			self.line = n as u32 + 1;
+
+			let line = line.trim_start_matches('\u{feff}').trim_end();
+
+			if line.is_empty() || line.starts_with('#') || line.starts_with('&') {
+				continue;
+			} else if let Some(c) = line.strip_prefix('*') {
+				comments.push(c.to_string(),position.is_none(),&mut mvs,end_state.is_some());
+				continue;
+			} else if line.starts_with("変化") {
+				// 変化手順には対応しない
+				break;
+			}
+
+			let is_move = line.starts_with(['▲','△','☗','☖','▽']);
+
+			if position.is_none() && (is_move || line.starts_with("まで")) {
+				let (b,m,t) = header.position(self.line,line)?;
+
+				let Banmen(kinds) = b;
+
+				banmen = kinds;
+				mc = m.clone();
+				teban = t;
+				position = Some((b,m,t));
+			}
+
+			if is_move {
+				let mut rest = line;
+
+				while let Some(c) = rest.chars().next() {
+					let t = match c {
+						'▲' | '☗' => Teban::Sente,
+						'△' | '☖' | '▽' => Teban::Gote,
+						_ => {
+							return Err(self.create_error(CsaFormatErrorKind::BadMoveNotation,line));
+						}
+					};
+
+					rest = &rest[c.len_utf8()..];
+
+					let end = rest.find(['▲','△','☗','☖','▽']).unwrap_or(rest.len());
+					let text = rest[..end].trim_matches(['　',' ','\t']);
+
+					rest = &rest[end..];
+
+					if t != teban {
+						return Err(self.create_error(CsaFormatErrorKind::WrongSideToMove,line));
+					}
+
+					let (m,dst) = self.parse_move(text,line,&banmen,&mc,teban,prev)?;
+
+					CsaMovesParser::apply_move(&mut banmen,&mut mc,teban,&m);
+
+					mvs.push(CsaMove::Move(m,None))?;
+					prev = Some(dst);
+					teban = teban.opposite();
+				}
+			} else if line.starts_with("まで") {
+				let (s,special) = self.parse_result(line,teban)?;
+
+				if let Some(m) = special {
+					mvs.push(m)?;
+				}
+
+				end_state = s;
+			} else {
+				// 解釈できない行は無視する
+				header.parse_line(self.line,line)?;
+			}
+		}
+
+		let (b,m,t) = match position {
+			Some(p) => p,
+			None => header.position(self.line,"")?,
+		};
+
+		let mut data = CsaData::new(None,header.info,t,b,m,mvs,end_state,Vec::new());
+
+		comments.assign(&mut data);
+
+		Ok(data)
+	}
+}
+impl Default for Ki2Parser {
+	fn default() -> Ki2Parser {
+		Ki2Parser::new()
+	}
+}
+pub struct Ki2Writer<W> where W: Write {
+	writer:W,
+}
+impl<W> Ki2Writer<W> where W: Write {
+	pub fn new(writer:W) -> Ki2Writer<W> {
+		Ki2Writer {
+			writer,
+		}
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	pub fn write(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		self.write_comments(&data.header_comments)?;
+
+		write_header(&mut self.writer,data)?;
+
+		self.write_comments(&data.position_comments)?;
+
+		self.write_moves(data)
+	}
+
+	fn write_comments(&mut self,comments:&[String]) -> Result<(),CsaWriterError> {
+		for c in comments {
+			writeln!(self.writer,"*{}",c)?;
+		}
+
+		Ok(())
+	}
+
+	fn move_text(&self,kinds:&[[KomaKind; 9]; 9],teban:Teban,m:&Move,prev:Option<(u32,u32)>) -> Result<String,CsaWriterError> {
+		let k = moving_piece(kinds,m)?.unwrap_or(Blank);
+
+		let (dst,k,suffix) = match *m {
+			Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+				let cands = candidates(kinds,k,teban,(dx,dy));
+
+				let d = disambiguator(&cands,k,teban,(sx,sy),(dx,dy)).ok_or_else(|| {
+					CsaWriterError::InvalidStateError(String::from("The move cannot be disambiguated."))
+				})?;
+
+				let promotable = !k.is_nari() && k.to_nari() != k &&
+									(in_promotion_zone(teban,sy) || in_promotion_zone(teban,dy));
+
+				let n = if n {
+					"成"
+				} else if promotable {
+					"不成"
+				} else {
+					""
+				};
+
+				((dx,dy),k,format!("{}{}",d,n))
+			},
+			Move::Put(k,KomaDstPutPosition(dx,dy)) => {
+				let k = KomaKind::from((teban,k));
+
+				// 盤上の駒も動ける場合だけ「打」を付ける
+				let suffix = if candidates(kinds,k,teban,(dx,dy)).is_empty() {
+					""
+				} else {
+					"打"
+				};
+
+				((dx,dy),k,suffix.to_string())
+			}
+		};
+
+		let mark = match teban {
+			Teban::Sente => '▲',
+			Teban::Gote => '△',
+		};
+
+		let name = piece_name(k);
+
+		let dst = if prev == Some(dst) && name.chars().count() == 1 {
+			String::from("同　")
+		} else if prev == Some(dst) {
+			String::from("同")
+		} else {
+			format!("{}{}",FILES[dst.0 as usize - 1],RANKS[dst.1 as usize - 1])
+		};
+
+		Ok(format!("{}{}{}{}",mark,dst,name,suffix))
+	}
+
+	fn write_moves(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		let Banmen(mut kinds) = data.initial_position.clone();
+		let mut mc = data.initial_mochigoma.clone();
+		let mut teban = data.teban_at_start;
+		let mut prev = None;
+		let mut line:Vec<String> = Vec::new();
+		let mut special = None;
+		let mut count = 0;
+
+		for (i,m) in data.moves.iter().enumerate() {
+			match *m {
+				CsaMove::Move(ref m,_) => {
+					line.push(self.move_text(&kinds,teban,m,prev)?);
+
+					prev = match *m {
+						Move::To(_,KomaDstToPosition(dx,dy,_)) => Some((dx,dy)),
+						Move::Put(_,KomaDstPutPosition(dx,dy)) => Some((dx,dy)),
+					};
+
+					CsaMovesParser::apply_move(&mut kinds,&mut mc,teban,m);
+
+					count += 1;
+				},
+				// 入玉勝ちと引き分けの宣言は終局の行で表す
+				CsaMove::Kachi(_) | CsaMove::Hikiwake(_) => {
+					special = Some((i,*m));
+					break;
+				}
+			}
+
+			let comments = data.moves.comments(i);
+
+			if line.len() == 6 || !comments.is_empty() {
+				self.write_line(&line)?;
+				line.clear();
+				self.write_comments(comments)?;
+			}
+
+			teban = teban.opposite();
+		}
+
+		if !line.is_empty() {
+			self.write_line(&line)?;
+		}
+
+		let (sente,gote) = match handicap_of(data) {
+			Some(h) if h != Handicap::Hirate => ("下手","上手"),
+			_ => ("先手","後手"),
+		};
+
+		let name = |t:Teban| match t {
+			Teban::Sente => sente,
+			Teban::Gote => gote,
+		};
+
+		let result = match special {
+			Some((_,CsaMove::Kachi(_))) => format!("{}の入玉勝ち",name(teban)),
+			Some((_,CsaMove::Hikiwake(_))) => String::from("引き分け"),
+			_ => match data.end_state {
+				None => {
+					return Ok(());
+				},
+				Some(EndState::Toryo) => format!("{}の勝ち",name(teban.opposite())),
+				Some(EndState::Kachi) => format!("{}の入玉勝ち",name(teban)),
+				Some(EndState::TimeUp) => format!("{}の切れ負け",name(teban)),
+				Some(EndState::IllegalMove) => format!("{}の反則負け",name(teban)),
+				Some(EndState::SIllegalAction) | Some(EndState::SFoul) => format!("{}の反則負け",name(Teban::Sente)),
+				Some(EndState::GIllegalAction) | Some(EndState::GFoul) => format!("{}の反則負け",name(Teban::Gote)),
+				Some(EndState::Sennichite) => String::from("千日手"),
+				Some(EndState::Jishogi) | Some(EndState::MaxMoves) => String::from("持将棋"),
+				Some(EndState::Hikiwake) => String::from("引き分け"),
+				Some(EndState::Tsumi) => String::from("詰み"),
+				Some(EndState::Fuzumi) => String::from("不詰"),
+				// KI2には対応する表記が無いので中断とする
+				Some(EndState::Chudan) | Some(EndState::Matta) | Some(EndState::Error) => String::from("中断"),
+			}
+		};
+
+		writeln!(self.writer,"まで{}手で{}",count,result)?;
+
+		if let Some((i,_)) = special {
+			self.write_comments(data.moves.comments(i))?;
+		}
+
+		self.write_comments(&data.end_state_comments)?;
+
+		Ok(())
+	}
+
+	fn write_line(&mut self,line:&[String]) -> Result<(),CsaWriterError> {
+		let len = line.len();
+
+		let mut s = String::new();
+
+		for (i,m) in line.iter().enumerate() {
+			s.push_str(m);
+
+			if i + 1 < len {
+				let width = m.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum::<usize>();
+				s.push_str(&" ".repeat(14usize.saturating_sub(width)));
+			}
+		}
+
+		writeln!(self.writer,"{}",s)?;
+
+		Ok(())
+	}
+}
+impl CsaData {
+	pub fn to_ki2(&self) -> Result<String,CsaWriterError> {
+		let mut writer = Ki2Writer::new(Vec::new());
+
+		writer.write(self)?;
+
+		String::from_utf8(writer.into_inner()).map_err(|_| {
+			CsaWriterError::InvalidStateError(String::from("The output is not valid utf-8."))
+		})
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use CsaParser;
+
+	// 駒を配置した局面から一手指した棋譜をKI2で書き出し、読み直しても同じ手になることを確かめる
+	fn write_move(pieces:&str,m:&str) -> String {
+		let data = CsaParser::parse_str(&format!("V2.2\n{}\n+\n{}\n",pieces,m)).unwrap().remove(0);
+		let s = data.to_ki2().unwrap();
+		let parsed = Ki2Parser::parse_str(&s).unwrap();
+
+		assert_eq!(parsed.moves,data.moves,"{}",s);
+
+		s
+	}
+
+	const KINGS:&str = "P+19OU\nP-51OU";
+
+	#[test]
+	fn right_and_left() {
+		let pieces = format!("{}\nP+69KI49KI",KINGS);
+
+		assert!(write_move(&pieces,"+4958KI").contains("▲５八金右"));
+		assert!(write_move(&pieces,"+6958KI").contains("▲５八金左"));
+	}
+
+	#[test]
+	fn right_and_left_of_the_same_direction() {
+		let pieces = format!("{}\nP+68KI48KI",KINGS);
+
+		assert!(write_move(&pieces,"+4857KI").contains("▲５七金右"));
+		assert!(write_move(&pieces,"+6857KI").contains("▲５七金左"));
+	}
+
+	#[test]
+	fn up_and_down() {
+		let pieces = format!("{}\nP+57KI49KI",KINGS);
+
+		assert!(write_move(&pieces,"+4958KI").contains("▲５八金上"));
+		assert!(write_move(&pieces,"+5758KI").contains("▲５八金引"));
+	}
+
+	#[test]
+	fn sideways() {
+		let pieces = format!("{}\nP+68KI49KI",KINGS);
+
+		assert!(write_move(&pieces,"+6858KI").contains("▲５八金寄"));
+		assert!(write_move(&pieces,"+4958KI").contains("▲５八金上"));
+	}
+
+	#[test]
+	fn straight() {
+		let pieces = format!("{}\nP+59KI49KI",KINGS);
+
+		assert!(write_move(&pieces,"+5958KI").contains("▲５八金直"));
+		assert!(write_move(&pieces,"+4958KI").contains("▲５八金右"));
+	}
+
+	#[test]
+	fn drop_next_to_a_movable_piece() {
+		let pieces = format!("{}\nP+49KI\nP+00AL",KINGS);
+
+		assert!(write_move(&pieces,"+0058KI").contains("▲５八金打"));
+
+		let s = write_move(&pieces,"+4958KI");
+
+		assert!(s.contains("▲５八金\n") || s.contains("▲５八金　"),"{}",s);
+
+		// 盤上に動ける駒が無ければ「打」を付けない
+		let pieces = format!("{}\nP+00AL",KINGS);
+
+		assert!(write_move(&pieces,"+0055KI").contains("▲５五金\n"));
+	}
+
+	#[test]
+	fn declarations_are_written_as_endings() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n%HIKIWAKE\n'hikiwake\n").unwrap().remove(0);
+		let s = data.to_ki2().unwrap();
+
+		assert!(s.contains("まで1手で引き分け"),"{}",s);
+
+		let parsed = Ki2Parser::parse_str(&s).unwrap();
+
+		assert_eq!(parsed.moves,data.moves);
+		assert_eq!(parsed.moves.comments(1),&[String::from("hikiwake")]);
+
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n%KACHI\n").unwrap().remove(0);
+
+		assert_eq!(Ki2Parser::parse_str(&data.to_ki2().unwrap()).unwrap().moves,data.moves);
+	}
+
+	#[test]
+	fn parse_ki2_text() {
+		let s = concat!(
+			"先手：先手の人\n",
+			"後手：後手の人\n",
+			"手合割：平手\n",
+			"▲７六歩    △３四歩    ▲２二角成\n",
+			"*三手目\n",
+			"△同　銀    ▲４五角\n",
+			"まで5手で先手の勝ち\n",
+			"*終局\n"
+		);
+
+		let data = Ki2Parser::parse_str(s).unwrap();
+
+		let csa = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n-3334FU\n+8822UM\n-3122GI\n+0045KA\n%TORYO\n").unwrap().remove(0);
+
+		assert_eq!(data.kifu_info.as_ref().and_then(|i| i.sente_name.clone()),Some(String::from("先手の人")));
+		assert!(data.moves.iter().eq(csa.moves.iter()));
+		assert_eq!(data.end_state,Some(EndState::Toryo));
+		assert_eq!(data.moves.comments(2),&["三手目"]);
+		assert_eq!(data.end_state_comments,vec!["終局"]);
+	}
+
+	#[test]
+	fn parse_result_lines() {
+		let result = |ending:&str| {
+			Ki2Parser::parse_str(&format!("▲７六歩\n{}\n",ending)).map(|d| d.end_state)
+		};
+
+		assert_eq!(result("まで1手で先手の勝ち").ok(),Some(Some(EndState::Toryo)));
+		assert_eq!(result("まで1手で千日手").ok(),Some(Some(EndState::Sennichite)));
+		assert_eq!(result("まで1手で時間切れにより先手の勝ち").ok(),Some(Some(EndState::TimeUp)));
+		assert_eq!(result("まで1手で後手の反則負け").ok(),Some(Some(EndState::IllegalMove)));
+		assert_eq!(result("まで1手で先手の反則負け").ok(),Some(Some(EndState::SIllegalAction)));
+		assert_eq!(result("まで1手で後手の反則勝ち").ok(),Some(Some(EndState::SIllegalAction)));
+		assert!(result("まで1手で終わり").is_err());
+	}
+
+	#[test]
+	fn reject_ambiguous_and_invalid_moves() {
+		let error = |s:&str| match Ki2Parser::parse_str(s) {
+			Err(CsaParserError::FormatError(k,_)) => Some(k),
+			_ => None,
+		};
+
+		// ５八に動ける金が二枚あるので区別が必要
+		assert_eq!(error("▲５八金\n"),Some(CsaFormatErrorKind::BadMoveNotation));
+		assert_eq!(error("▲７六歩    ▲２六歩\n"),Some(CsaFormatErrorKind::WrongSideToMove));
+		assert_eq!(error("▲５五金\n"),Some(CsaFormatErrorKind::NoPieceToMove));
+		assert_eq!(error("▲同　歩\n"),Some(CsaFormatErrorKind::BadMoveNotation));
+		assert!(Ki2Parser::parse_str("▲５八金右\n").is_ok());
+	}
+
+	#[test]
+	fn writer_rejects_moves_without_a_piece() {
+		let mut data = CsaParser::parse_str("V2.2\nPI\n+\n").unwrap().remove(0);
+
+		data.moves.push(CsaMove::Move(Move::To(KomaSrcPosition(5,5),KomaDstToPosition(5,4,false)),None)).unwrap();
+
+		assert!(data.to_ki2().is_err());
+	}
+}
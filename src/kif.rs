@@ -0,0 +1,1144 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use std::convert::TryFrom;
+
+use usiagent::shogi::*;
+use usiagent::shogi::KomaKind::{
+	SFu,
+	SKyou,
+	SKei,
+	SGin,
+	SKin,
+	SKaku,
+	SHisha,
+	SOu,
+	SFuN,
+	SKyouN,
+	SKeiN,
+	SGinN,
+	SKakuN,
+	SHishaN,
+	GFu,
+	Blank
+};
+
+use CommentRouter;
+use CsaData;
+use CsaMove;
+use CsaMoves;
+use CsaMovesParser;
+use EndState;
+use KifuInfo;
+use handicap::Handicap;
+use moving_piece;
+use encoding::CsaEncoding;
+use encoding::LineDecoder;
+use error::*;
+
+pub(crate) const FILES:[char; 9] = ['１','２','３','４','５','６','７','８','９'];
+pub(crate) const RANKS:[char; 9] = ['一','二','三','四','五','六','七','八','九'];
+const KANJI_NUMBERS:[char; 9] = ['一','二','三','四','五','六','七','八','九'];
+// 長い名前を先に照合する
+const PIECE_NAMES:[(&str,KomaKind); 19] = [
+	("成香",SKyouN),
+	("成桂",SKeiN),
+	("成銀",SGinN),
+	("歩",SFu),
+	("香",SKyou),
+	("桂",SKei),
+	("銀",SGin),
+	("金",SKin),
+	("角",SKaku),
+	("飛",SHisha),
+	("玉",SOu),
+	("王",SOu),
+	("と",SFuN),
+	("杏",SKyouN),
+	("圭",SKeiN),
+	("全",SGinN),
+	("馬",SKakuN),
+	("龍",SHishaN),
+	("竜",SHishaN),
+];
+const HAND_ORDER:[MochigomaKind; 7] = [
+	MochigomaKind::Hisha,
+	MochigomaKind::Kaku,
+	MochigomaKind::Kin,
+	MochigomaKind::Gin,
+	MochigomaKind::Kei,
+	MochigomaKind::Kyou,
+	MochigomaKind::Fu,
+];
+
+pub(crate) fn sided(k:KomaKind,teban:Teban) -> KomaKind {
+	match MochigomaKind::try_from(k) {
+		Ok(m) if k.is_nari() => KomaKind::from((teban,m)).to_nari(),
+		Ok(m) => KomaKind::from((teban,m)),
+		Err(_) if k == Blank => Blank,
+		Err(_) => {
+			match teban {
+				Teban::Sente => SOu,
+				Teban::Gote => KomaKind::GOu,
+			}
+		}
+	}
+}
+pub(crate) fn piece_name(k:KomaKind) -> &'static str {
+	match sided(k,Teban::Sente) {
+		SFu => "歩",
+		SKyou => "香",
+		SKei => "桂",
+		SGin => "銀",
+		SKin => "金",
+		SKaku => "角",
+		SHisha => "飛",
+		SOu => "玉",
+		SFuN => "と",
+		SKyouN => "成香",
+		SKeiN => "成桂",
+		SGinN => "成銀",
+		SKakuN => "馬",
+		SHishaN => "龍",
+		_ => "",
+	}
+}
+fn board_piece_name(k:KomaKind) -> &'static str {
+	match sided(k,Teban::Sente) {
+		SKyouN => "杏",
+		SKeiN => "圭",
+		SGinN => "全",
+		_ => piece_name(k),
+	}
+}
+pub(crate) fn parse_piece_name(s:&str) -> Option<(KomaKind,&str)> {
+	PIECE_NAMES.iter().find(|&&(name,_)| s.starts_with(name)).map(|&(name,k)| (k,&s[name.len()..]))
+}
+pub(crate) fn parse_square(s:&str) -> Option<((u32,u32),&str)> {
+	let mut chars = s.chars();
+
+	let x = chars.next().and_then(|c| {
+		FILES.iter().position(|&f| f == c).map(|x| x as u32 + 1).or_else(|| c.to_digit(10))
+	})?;
+	let y = chars.next().and_then(|c| {
+		RANKS.iter().position(|&r| r == c).map(|y| y as u32 + 1).or_else(|| {
+			FILES.iter().position(|&f| f == c).map(|y| y as u32 + 1)
+		}).or_else(|| c.to_digit(10))
+	})?;
+
+	if x < 1 || y < 1 {
+		return None;
+	}
+
+	Some(((x,y),chars.as_str()))
+}
+// 1から99までの数を漢数字で表す(二十、十八等)
+fn kanji_number(n:usize) -> String {
+	let mut s = String::new();
+
+	if n >= 20 {
+		s.push(KANJI_NUMBERS[n / 10 - 1]);
+	}
+
+	if n >= 10 {
+		s.push('十');
+	}
+
+	if !n.is_multiple_of(10) {
+		s.push(KANJI_NUMBERS[n % 10 - 1]);
+	}
+
+	s
+}
+fn parse_kanji_number(s:&str) -> Option<usize> {
+	if s.is_empty() {
+		return Some(1);
+	}
+
+	let digit = |c:char| KANJI_NUMBERS.iter().position(|&k| k == c).map(|n| n + 1);
+
+	let (tens,units) = match s.find('十') {
+		Some(0) => (1,&s['十'.len_utf8()..]),
+		Some(i) => {
+			let mut chars = s[..i].chars();
+
+			let tens = match (chars.next().and_then(digit),chars.next()) {
+				(Some(t),None) if t > 1 => t,
+				_ => {
+					return None;
+				}
+			};
+
+			(tens,&s[i + '十'.len_utf8()..])
+		},
+		None => (0,s),
+	};
+
+	let mut chars = units.chars();
+
+	let units = match (chars.next(),chars.next()) {
+		(None,_) if tens > 0 => 0,
+		(Some(c),None) => digit(c)?,
+		_ => {
+			return None;
+		}
+	};
+
+	Some(tens * 10 + units)
+}
+pub(crate) fn in_promotion_zone(teban:Teban,y:u32) -> bool {
+	match teban {
+		Teban::Sente => y <= 3,
+		Teban::Gote => y >= 7,
+	}
+}
+fn parse_time_limit(s:&str) -> Option<(u32,Option<u32>)> {
+	let s = s.trim().trim_start_matches('各');
+
+	let (main,byoyomi) = match s.find('+') {
+		Some(i) => (&s[..i],Some(&s[i+1..])),
+		None => (s,None),
+	};
+
+	let mut minutes = 0;
+	let mut n = String::new();
+
+	for c in main.chars() {
+		match c {
+			'0'..='9' => n.push(c),
+			'０'..='９' => {
+				n.push(std::char::from_digit(c as u32 - '０' as u32,10)?);
+			},
+			'時' => {
+				minutes += n.parse::<u32>().ok()? * 60;
+				n.clear();
+			},
+			'間' => (),
+			'分' => {
+				minutes += n.parse::<u32>().ok()?;
+				n.clear();
+			},
+			_ => {
+				return None;
+			}
+		}
+	}
+
+	if !n.is_empty() {
+		return None;
+	}
+
+	let byoyomi = match byoyomi {
+		Some(b) => Some(b.trim_end_matches('秒').parse::<u32>().ok()?),
+		None => None,
+	};
+
+	Some((minutes,byoyomi))
+}
+fn parse_elapsed(s:&str) -> Option<Duration> {
+	let s = s.trim().strip_prefix('(')?.trim_end_matches('+').trim_end().strip_suffix(')')?;
+	let s = s.split('/').next()?.trim();
+
+	let mut items = s.split(':');
+
+	let m = items.next()?.trim().parse::<u64>().ok()?;
+	let sec = items.next()?.trim().parse::<u64>().ok()?;
+
+	Some(Duration::from_secs(m * 60 + sec))
+}
+fn end_state_of(s:&str,teban:Teban) -> Option<EndState> {
+	Some(match s {
+		"投了" => EndState::Toryo,
+		"中断" => EndState::Chudan,
+		"千日手" => EndState::Sennichite,
+		"切れ負け" | "時間切れ" => EndState::TimeUp,
+		"反則負け" => EndState::IllegalMove,
+		// 手番側の勝ちなので、直前に指した側の反則
+		"反則勝ち" => {
+			match teban {
+				Teban::Sente => EndState::GIllegalAction,
+				Teban::Gote => EndState::SIllegalAction,
+			}
+		},
+		"持将棋" => EndState::Jishogi,
+		"待った" => EndState::Matta,
+		"詰み" => EndState::Tsumi,
+		"不詰" => EndState::Fuzumi,
+		_ => {
+			return None;
+		}
+	})
+}
+enum KifMove {
+	Move(Move,(u32,u32),Option<Duration>),
+	Kachi(Option<Duration>),
+	Hikiwake(Option<Duration>),
+	End(EndState),
+}
+pub(crate) fn format_error(kind:CsaFormatErrorKind,n:u32,line:&str) -> CsaParserError {
+	CsaParserError::FormatError(kind,Some(SourceStatement::new(
+		Some(SourceLocation::new(n,1,1)),line.to_string()
+	)))
+}
+// KIFとKI2で共通の棋譜情報と開始局面の記述
+pub(crate) struct KifHeader {
+	pub(crate) info:Option<KifuInfo>,
+	handicap:Option<Handicap>,
+	rows:Vec<[KomaKind; 9]>,
+	hands:(Option<Mochigoma>,Option<Mochigoma>),
+	teban:Option<Teban>,
+}
+impl KifHeader {
+	pub(crate) fn new() -> KifHeader {
+		KifHeader {
+			info:None,
+			handicap:None,
+			rows:Vec::new(),
+			hands:(None,None),
+			teban:None,
+		}
+	}
+
+	fn parse_board_row(&self,n:u32,line:&str) -> Result<[KomaKind; 9],CsaParserError> {
+		let mut row = [Blank; 9];
+		let mut chars = line.chars();
+		chars.next();
+
+		for k in row.iter_mut() {
+			let teban = match chars.next() {
+				Some('v') => Teban::Gote,
+				Some(' ') | Some('^') => Teban::Sente,
+				_ => {
+					return Err(format_error(CsaFormatErrorKind::BadBoardRow,n,line));
+				}
+			};
+
+			*k = match chars.next() {
+				Some('・') => Blank,
+				Some(c) => {
+					let mut b = [0; 4];
+
+					match parse_piece_name(c.encode_utf8(&mut b)) {
+						Some((p,_)) => sided(p,teban),
+						None => {
+							return Err(format_error(CsaFormatErrorKind::UnknownPieceCode,n,line));
+						}
+					}
+				},
+				None => {
+					return Err(format_error(CsaFormatErrorKind::BadBoardRow,n,line));
+				}
+			};
+		}
+
+		match chars.next() {
+			Some('|') => Ok(row),
+			_ => Err(format_error(CsaFormatErrorKind::BadBoardRow,n,line)),
+		}
+	}
+
+	fn parse_hand(&self,n:u32,value:&str,line:&str) -> Result<Mochigoma,CsaParserError> {
+		let mut m = Mochigoma::new();
+
+		for item in value.split(['　',' ']).filter(|s| !s.is_empty()) {
+			if item == "なし" {
+				continue;
+			}
+
+			let (k,rest) = parse_piece_name(item).ok_or_else(|| {
+				format_error(CsaFormatErrorKind::UnknownPieceCode,n,line)
+			})?;
+
+			let k = MochigomaKind::try_from(k).ok().filter(|_| !k.is_nari()).ok_or_else(|| {
+				format_error(CsaFormatErrorKind::UnknownPieceCode,n,line)
+			})?;
+
+			let c = parse_kanji_number(rest).ok_or_else(|| {
+				format_error(CsaFormatErrorKind::PieceCountExceeded,n,line)
+			})?;
+
+			m.insert(k,m.get(k) + c);
+		}
+
+		Ok(m)
+	}
+
+	// 棋譜情報もしくは局面の行として解釈できた場合はtrueを返す
+	pub(crate) fn parse_line(&mut self,n:u32,line:&str) -> Result<bool,CsaParserError> {
+		if line.starts_with('|') {
+			self.rows.push(self.parse_board_row(n,line)?);
+			return Ok(true);
+		} else if line.starts_with('+') || line.trim_start().starts_with('９') {
+			return Ok(true);
+		} else if line == "先手番" || line == "下手番" {
+			self.teban = Some(Teban::Sente);
+			return Ok(true);
+		} else if line == "後手番" || line == "上手番" {
+			self.teban = Some(Teban::Gote);
+			return Ok(true);
+		}
+
+		let (key,value) = match line.find('：').map(|i| (&line[..i],&line[i+'：'.len_utf8()..])).or_else(|| {
+			line.find(':').map(|i| (&line[..i],&line[i+1..]))
+		}) {
+			Some((k,v)) => (k.trim(),v.trim()),
+			None => {
+				return Ok(false);
+			}
+		};
+
+		match key {
+			"先手の持駒" | "下手の持駒" => {
+				self.hands.0 = Some(self.parse_hand(n,value,line)?);
+			},
+			"後手の持駒" | "上手の持駒" => {
+				self.hands.1 = Some(self.parse_hand(n,value,line)?);
+			},
+			"手合割" => {
+				self.handicap = match Handicap::from_name(value) {
+					Some(h) => Some(h),
+					None if value == "その他" => None,
+					None => {
+						return Err(format_error(CsaFormatErrorKind::UnknownHandicap,n,line));
+					}
+				};
+			},
+			"先手" | "下手" | "後手" | "上手" | "棋戦" | "場所" | "開始日時" | "終了日時" | "戦型" | "持ち時間" | "秒読み" => {
+				let info = self.info.get_or_insert_with(KifuInfo::new);
+				let v = Some(value.to_string());
+
+				match key {
+					"先手" | "下手" => info.sente_name = v,
+					"後手" | "上手" => info.gote_name = v,
+					"棋戦" => info.event = v,
+					"場所" => info.site = v,
+					"開始日時" => info.start_time = v,
+					"終了日時" => info.end_time = v,
+					"戦型" => info.opening = v,
+					"持ち時間" => {
+						if let Some((m,s)) = parse_time_limit(value) {
+							info.time_limit = Some((m,s.or(info.time_limit.and_then(|(_,s)| s))));
+						}
+					},
+					_ => {
+						if let Ok(s) = value.trim_end_matches('秒').parse::<u32>() {
+							info.time_limit = Some((info.time_limit.map(|(m,_)| m).unwrap_or(0),Some(s)));
+						}
+					}
+				}
+			},
+			_ => (),
+		}
+
+		Ok(true)
+	}
+
+	pub(crate) fn position(&self,n:u32,line:&str) -> Result<(Banmen,MochigomaCollections,Teban),CsaParserError> {
+		let (b,m) = if !self.rows.is_empty() {
+			if self.rows.len() != 9 {
+				return Err(format_error(CsaFormatErrorKind::BadBoardRow,n,line));
+			}
+
+			let mut kinds = [[Blank; 9]; 9];
+
+			for (y,r) in self.rows.iter().enumerate() {
+				kinds[y] = *r;
+			}
+
+			let m = match self.hands {
+				(None,None) => MochigomaCollections::Empty,
+				(ref ms,ref mg) => {
+					MochigomaCollections::Pair(ms.clone().unwrap_or_else(Mochigoma::new),
+												mg.clone().unwrap_or_else(Mochigoma::new))
+				}
+			};
+
+			(Banmen(kinds),m)
+		} else {
+			(self.handicap.unwrap_or(Handicap::Hirate).banmen(),MochigomaCollections::Empty)
+		};
+
+		let t = self.teban.unwrap_or(match self.handicap {
+			Some(h) if self.rows.is_empty() => h.teban(),
+			_ => Teban::Sente,
+		});
+
+		Ok((b,m,t))
+	}
+}
+pub struct KifParser {
+	line:u32,
+}
+impl KifParser {
+	pub fn new() -> KifParser {
+		KifParser {
+			line:0,
+		}
+	}
+
+	// 行ごとにUTF-8として正しいかを判定し、正しくない行はShift_JISとして読む
+	pub fn decode(bytes:&[u8]) -> Result<String,CsaStreamReadError> {
+		let mut decoder = LineDecoder::new(CsaEncoding::Auto);
+		let mut s = String::new();
+
+		for line in bytes.split_inclusive(|&b| b == b'\n') {
+			s.push_str(&decoder.decode(line)?);
+		}
+
+		Ok(s)
+	}
+
+	pub fn parse_str(s:&str) -> Result<CsaData,CsaParserError> {
+		KifParser::new().parse(s)
+	}
+
+	pub fn parse_bytes(bytes:&[u8]) -> Result<CsaData,CsaParserError> {
+		KifParser::new().parse(&KifParser::decode(bytes)?)
+	}
+
+	pub fn parse_file<P: AsRef<Path>>(path:P) -> Result<CsaData,CsaParserError> {
+		let mut bytes = Vec::new();
+
+		File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(CsaStreamReadError::from)?;
+
+		KifParser::parse_bytes(&bytes)
+	}
+
+	fn create_error(&self,kind:CsaFormatErrorKind,line:&str) -> CsaParserError {
+		format_error(kind,self.line,line)
+	}
+
+	fn parse_move(&self,line:&str,teban:Teban,prev:Option<(u32,u32)>) -> Result<KifMove,CsaParserError> {
+		let s = line.trim_start();
+		let s = s.trim_start_matches(|c:char| c.is_ascii_digit()).trim_start();
+
+		let (dst,s) = if let Some(s) = s.strip_prefix('同') {
+			let dst = prev.ok_or_else(|| self.create_error(CsaFormatErrorKind::BadMoveNotation,line))?;
+
+			(Some(dst),s.trim_start_matches(['　',' ']))
+		} else {
+			(None,s)
+		};
+
+		let (token,rest) = match s.find([' ','　','\t']) {
+			Some(i) => (&s[..i],&s[i..]),
+			None => (s,""),
+		};
+
+		let elapsed = parse_elapsed(rest);
+
+		if dst.is_none() {
+			if token == "入玉勝ち" {
+				return Ok(KifMove::Kachi(elapsed));
+			} else if token == "引き分け" {
+				return Ok(KifMove::Hikiwake(elapsed));
+			} else if let Some(s) = end_state_of(token,teban) {
+				return Ok(KifMove::End(s));
+			}
+		}
+
+		let (dst,token) = match dst {
+			Some(dst) => (dst,token),
+			None => parse_square(token).ok_or_else(|| self.create_error(CsaFormatErrorKind::BadMoveNotation,line))?,
+		};
+
+		let (dx,dy) = dst;
+
+		if !(1..=9).contains(&dx) || !(1..=9).contains(&dy) {
+			return Err(self.create_error(CsaFormatErrorKind::BadSquare,line));
+		}
+
+		let (kind,token) = parse_piece_name(token).ok_or_else(|| {
+			self.create_error(CsaFormatErrorKind::UnknownPieceCode,line)
+		})?;
+
+		let (nari,put,token) = if let Some(t) = token.strip_prefix("不成") {
+			(false,false,t)
+		} else if let Some(t) = token.strip_prefix('成') {
+			(true,false,t)
+		} else if let Some(t) = token.strip_prefix('打') {
+			(false,true,t)
+		} else {
+			(false,false,token)
+		};
+
+		let src = token.strip_prefix('(').and_then(|t| t.strip_suffix(')'));
+
+		let m = match src {
+			Some(src) if !put => {
+				let mut chars = src.chars();
+
+				let (sx,sy) = match (chars.next().and_then(|c| c.to_digit(10)),
+									 chars.next().and_then(|c| c.to_digit(10)),
+									 chars.next()) {
+					(Some(sx),Some(sy),None) if sx >= 1 && sy >= 1 => (sx,sy),
+					_ => {
+						return Err(self.create_error(CsaFormatErrorKind::BadSquare,line));
+					}
+				};
+
+				Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,nari))
+			},
+			// 移動元の無い指し手は打つ手とみなす
+			None if !nari && token.is_empty() => {
+				let k = MochigomaKind::try_from(kind).ok().filter(|_| !kind.is_nari()).ok_or_else(|| {
+					self.create_error(CsaFormatErrorKind::BadMoveNotation,line)
+				})?;
+
+				Move::Put(k,KomaDstPutPosition(dx,dy))
+			},
+			_ => {
+				return Err(self.create_error(CsaFormatErrorKind::BadMoveNotation,line));
+			}
+		};
+
+		Ok(KifMove::Move(m,dst,elapsed))
+	}
+
+	pub fn parse(&mut self,s:&str) -> Result<CsaData,CsaParserError> {
+		let mut header = KifHeader::new();
+
+		let mut comments = CommentRouter::new();
+
+		let mut position:Option<(Banmen,MochigomaCollections,Teban)> = None;
+		let mut banmen = [[Blank; 9]; 9];
+		let mut mc = MochigomaCollections::Empty;
+		let mut teban = Teban::Sente;
+		let mut prev = None;
+		let mut mvs = CsaMoves::new();
+		let mut end_state = None;
+		let mut finished = false;
+
+		for (n,line) in s.lines().enumerate() {
+			self.line = n as u32 + 1;
+
+			let line = line.trim_start_matches('\u{feff}').trim_end();
+
+			if line.is_empty() || line.starts_with('#') || line.starts_with('&') || line.starts_with("まで") {
+				continue;
+			} else if let Some(c) = line.strip_prefix('*') {
+				comments.push(c.to_string(),position.is_none(),&mut mvs,end_state.is_some());
+				continue;
+			} else if line.starts_with("変化") {
+				// 変化手順には対応しない
+				break;
+			}
+
+			let is_move = line.trim_start().starts_with(|c:char| c.is_ascii_digit());
+
+			if position.is_none() && (is_move || line.starts_with("手数")) {
+				let (b,m,t) = header.position(self.line,line)?;
+
+				let Banmen(kinds) = b;
+
+				banmen = kinds;
+				mc = m.clone();
+				teban = t;
+				position = Some((b,m,t));
+			}
+
+			if is_move {
+				if finished {
+					continue;
+				}
+
+				match self.parse_move(line,teban,prev)? {
+					KifMove::Move(m,dst,t) => {
+						CsaMovesParser::apply_move(&mut banmen,&mut mc,teban,&m);
+
+						mvs.push(CsaMove::Move(m,t))?;
+						prev = Some(dst);
+						teban = teban.opposite();
+					},
+					KifMove::Kachi(t) => {
+						mvs.push(CsaMove::Kachi(t))?;
+						finished = true;
+					},
+					KifMove::Hikiwake(t) => {
+						mvs.push(CsaMove::Hikiwake(t))?;
+						finished = true;
+					},
+					KifMove::End(s) => {
+						end_state = Some(s);
+						finished = true;
+					}
+				}
+			} else if !line.starts_with("手数") {
+				// 解釈できない行は無視する
+				header.parse_line(self.line,line)?;
+			}
+		}
+
+		let (b,m,t) = match position {
+			Some(p) => p,
+			None => header.position(self.line,"")?,
+		};
+
+		let mut data = CsaData::new(None,header.info,t,b,m,mvs,end_state,Vec::new());
+
+		comments.assign(&mut data);
+
+		Ok(data)
+	}
+}
+impl Default for KifParser {
+	fn default() -> KifParser {
+		KifParser::new()
+	}
+}
+pub(crate) fn handicap_of(data:&CsaData) -> Option<Handicap> {
+	// 手合割だけでは手番を表せないので、既定の手番と一致する場合に限る
+	data.handicap().filter(|h| h.teban() == data.teban_at_start)
+}
+fn hand_text(m:&Mochigoma) -> String {
+	let items = HAND_ORDER.iter().filter(|&&k| m.get(k) > 0).map(|&k| {
+		let n = m.get(k);
+		let name = piece_name(KomaKind::from((Teban::Sente,k)));
+
+		if n > 1 {
+			format!("{}{}",name,kanji_number(n))
+		} else {
+			name.to_string()
+		}
+	}).collect::<Vec<String>>();
+
+	if items.is_empty() {
+		String::from("なし")
+	} else {
+		items.join("　")
+	}
+}
+fn write_board<W: Write>(writer:&mut W,banmen:&Banmen,mc:&MochigomaCollections,teban:Teban) -> Result<(),CsaWriterError> {
+	let (ms,mg) = match *mc {
+		MochigomaCollections::Pair(ref ms,ref mg) => (ms.clone(),mg.clone()),
+		MochigomaCollections::Empty => (Mochigoma::new(),Mochigoma::new()),
+	};
+
+	writeln!(writer,"後手の持駒：{}",hand_text(&mg))?;
+	writeln!(writer,"  ９ ８ ７ ６ ５ ４ ３ ２ １")?;
+	writeln!(writer,"+---------------------------+")?;
+
+	let Banmen(ref kinds) = *banmen;
+
+	for (y,row) in kinds.iter().enumerate() {
+		let mut l = String::from("|");
+
+		for &k in row {
+			if k == Blank {
+				l.push_str(" ・");
+			} else if k < GFu {
+				l.push(' ');
+				l.push_str(board_piece_name(k));
+			} else {
+				l.push('v');
+				l.push_str(board_piece_name(k));
+			}
+		}
+
+		l.push('|');
+		l.push(RANKS[y]);
+
+		writeln!(writer,"{}",l)?;
+	}
+
+	writeln!(writer,"+---------------------------+")?;
+	writeln!(writer,"先手の持駒：{}",hand_text(&ms))?;
+
+	if teban == Teban::Gote {
+		writeln!(writer,"後手番")?;
+	}
+
+	Ok(())
+}
+// 持ち時間(分)をKIFの表記にする
+pub(crate) fn time_limit_text(m:u32) -> String {
+	if m >= 60 && m.is_multiple_of(60) {
+		format!("各{}時間",m / 60)
+	} else {
+		format!("各{}分",m)
+	}
+}
+// KIFとKI2で共通の棋譜情報と開始局面を書き出す
+pub(crate) fn write_header<W: Write>(writer:&mut W,data:&CsaData) -> Result<(),CsaWriterError> {
+	let info = data.kifu_info.clone().unwrap_or_default();
+
+	if let Some(ref s) = info.start_time {
+		writeln!(writer,"開始日時：{}",s)?;
+	}
+
+	if let Some(ref s) = info.end_time {
+		writeln!(writer,"終了日時：{}",s)?;
+	}
+
+	if let Some(ref s) = info.event {
+		writeln!(writer,"棋戦：{}",s)?;
+	}
+
+	if let Some(ref s) = info.site {
+		writeln!(writer,"場所：{}",s)?;
+	}
+
+	if let Some((m,s)) = info.time_limit {
+		writeln!(writer,"持ち時間：{}",time_limit_text(m))?;
+
+		if let Some(s) = s {
+			writeln!(writer,"秒読み：{}秒",s)?;
+		}
+	}
+
+	let handicap = handicap_of(data);
+
+	match handicap {
+		Some(h) => writeln!(writer,"手合割：{}",h.name())?,
+		None => write_board(writer,&data.initial_position,&data.initial_mochigoma,data.teban_at_start)?,
+	}
+
+	let (sente,gote) = match handicap {
+		Some(h) if h != Handicap::Hirate => ("下手","上手"),
+		_ => ("先手","後手"),
+	};
+
+	if let Some(ref s) = info.sente_name {
+		writeln!(writer,"{}：{}",sente,s)?;
+	}
+
+	if let Some(ref s) = info.gote_name {
+		writeln!(writer,"{}：{}",gote,s)?;
+	}
+
+	if let Some(ref s) = info.opening {
+		writeln!(writer,"戦型：{}",s)?;
+	}
+
+	Ok(())
+}
+pub struct KifWriter<W> where W: Write {
+	writer:W,
+}
+impl<W> KifWriter<W> where W: Write {
+	pub fn new(writer:W) -> KifWriter<W> {
+		KifWriter {
+			writer,
+		}
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	pub fn write(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		self.write_comments(&data.header_comments)?;
+
+		write_header(&mut self.writer,data)?;
+
+		writeln!(self.writer,"手数----指手---------消費時間--")?;
+
+		self.write_comments(&data.position_comments)?;
+
+		self.write_moves(data)
+	}
+
+	fn write_comments(&mut self,comments:&[String]) -> Result<(),CsaWriterError> {
+		for c in comments {
+			writeln!(self.writer,"*{}",c)?;
+		}
+
+		Ok(())
+	}
+
+	fn write_move_line(&mut self,ply:usize,text:&str,t:Option<Duration>,total:&mut Duration) -> Result<(),CsaWriterError> {
+		match t {
+			Some(t) => {
+				*total += t;
+
+				let width = text.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum::<usize>();
+				let padding = " ".repeat(14usize.saturating_sub(width));
+				let s = t.as_secs();
+				let total = total.as_secs();
+
+				writeln!(self.writer,"{:>4} {}{}({:>2}:{:02}/{:02}:{:02}:{:02})",
+							ply,text,padding,s / 60,s % 60,total / 3600,total / 60 % 60,total % 60)?;
+			},
+			None => {
+				writeln!(self.writer,"{:>4} {}",ply,text)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn write_moves(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		let Banmen(mut kinds) = data.initial_position.clone();
+		let mut mc = data.initial_mochigoma.clone();
+		let mut teban = data.teban_at_start;
+		let mut prev = None;
+		let mut totals = [Duration::from_secs(0); 2];
+
+		for (i,m) in data.moves.iter().enumerate() {
+			let total = match teban {
+				Teban::Sente => &mut totals[0],
+				Teban::Gote => &mut totals[1],
+			};
+
+			match *m {
+				CsaMove::Move(m,t) => {
+					let k = moving_piece(&kinds,&m)?.unwrap_or(Blank);
+
+					let text = match m {
+						Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+							let dst = if prev == Some((dx,dy)) {
+								String::from("同　")
+							} else {
+								format!("{}{}",FILES[dx as usize - 1],RANKS[dy as usize - 1])
+							};
+
+							let promotable = !k.is_nari() && k.to_nari() != k &&
+												(in_promotion_zone(teban,sy) || in_promotion_zone(teban,dy));
+
+							let suffix = if n {
+								"成"
+							} else if promotable {
+								"不成"
+							} else {
+								""
+							};
+
+							prev = Some((dx,dy));
+
+							format!("{}{}{}({}{})",dst,piece_name(k),suffix,sx,sy)
+						},
+						Move::Put(k,KomaDstPutPosition(dx,dy)) => {
+							prev = Some((dx,dy));
+
+							format!("{}{}{}打",FILES[dx as usize - 1],RANKS[dy as usize - 1],piece_name(KomaKind::from((teban,k))))
+						}
+					};
+
+					self.write_move_line(i + 1,&text,t,total)?;
+
+					CsaMovesParser::apply_move(&mut kinds,&mut mc,teban,&m);
+				},
+				CsaMove::Kachi(t) => {
+					self.write_move_line(i + 1,"入玉勝ち",t,total)?;
+				},
+				// 持将棋(EndState::Jishogi)と区別するため、引き分けの宣言は「引き分け」とする
+				CsaMove::Hikiwake(t) => {
+					self.write_move_line(i + 1,"引き分け",t,total)?;
+				}
+			}
+
+			self.write_comments(data.moves.comments(i))?;
+
+			// 入玉勝ち等の特殊な手は手番を進めない
+			if let CsaMove::Move(_,_) = *m {
+				teban = teban.opposite();
+			}
+		}
+
+		let text = match data.end_state {
+			None | Some(EndState::Kachi) | Some(EndState::Hikiwake) => {
+				return Ok(());
+			},
+			Some(EndState::Toryo) => "投了",
+			// KIFには対応する表記が無いので最も近いものを使う
+			Some(EndState::Chudan) | Some(EndState::Error) => "中断",
+			Some(EndState::Sennichite) => "千日手",
+			Some(EndState::TimeUp) => "切れ負け",
+			Some(EndState::IllegalMove) => "反則負け",
+			Some(EndState::SIllegalAction) | Some(EndState::SFoul) if teban == Teban::Gote => "反則勝ち",
+			Some(EndState::GIllegalAction) | Some(EndState::GFoul) if teban == Teban::Sente => "反則勝ち",
+			Some(EndState::SIllegalAction) | Some(EndState::SFoul) |
+				Some(EndState::GIllegalAction) | Some(EndState::GFoul) => "反則負け",
+			Some(EndState::Jishogi) | Some(EndState::MaxMoves) => "持将棋",
+			Some(EndState::Matta) => "待った",
+			Some(EndState::Tsumi) => "詰み",
+			Some(EndState::Fuzumi) => "不詰",
+		};
+
+		writeln!(self.writer,"{:>4} {}",data.moves.len() + 1,text)?;
+
+		self.write_comments(&data.end_state_comments)?;
+
+		Ok(())
+	}
+}
+impl CsaData {
+	// KIFに無い終局状態は最も近い表記で書き出すため、読み戻すと元の終局状態にはならない(勝敗は変わらない)。
+	// 手番側の%+ILLEGAL_ACTION/%-ILLEGAL_ACTIONや%+FOUL/%-FOULは反則負け、%MAX_MOVESは持将棋、%ERRORは中断となる
+	pub fn to_kif(&self) -> Result<String,CsaWriterError> {
+		let mut writer = KifWriter::new(Vec::new());
+
+		writer.write(self)?;
+
+		String::from_utf8(writer.into_inner()).map_err(|_| {
+			CsaWriterError::InvalidStateError(String::from("The output is not valid utf-8."))
+		})
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use CsaParser;
+
+	#[test]
+	fn kanji_numbers() {
+		let cases = [(1,"一"),(9,"九"),(10,"十"),(11,"十一"),(18,"十八"),(20,"二十"),(21,"二十一"),(99,"九十九")];
+
+		for &(n,s) in cases.iter() {
+			assert_eq!(kanji_number(n),s);
+			assert_eq!(parse_kanji_number(s),Some(n));
+		}
+
+		assert_eq!(parse_kanji_number(""),Some(1));
+		assert_eq!(parse_kanji_number("一十"),None);
+		assert_eq!(parse_kanji_number("十十"),None);
+		assert_eq!(parse_kanji_number("二三"),None);
+	}
+
+	#[test]
+	fn end_states_round_trip() {
+		let endings = ["%TORYO","%CHUDAN","%SENNICHITE","%TIME_UP","%ILLEGAL_MOVE","%+ILLEGAL_ACTION",
+						"%JISHOGI","%MATTA","%TSUMI","%FUZUMI","%KACHI","%HIKIWAKE"];
+
+		for e in endings.iter() {
+			let data = CsaParser::parse_str(&format!("V2.2\nPI\n+\n+7776FU\nT3\n{}\n",e)).unwrap().remove(0);
+			let s = data.to_kif().unwrap();
+			let parsed = KifParser::parse_str(&s).unwrap();
+
+			assert_eq!(parsed.moves,data.moves,"{}",s);
+			assert_eq!(parsed.end_state,data.end_state,"{}",s);
+		}
+	}
+
+	#[test]
+	fn parse_kif_text() {
+		let s = concat!(
+			"\u{feff}# ---- Kifu for Windows ----\n",
+			"開始日時：2020/01/01 10:00:00\n",
+			"棋戦：テスト\n",
+			"持ち時間：各30分\n",
+			"秒読み：10秒\n",
+			"手合割：平手\n",
+			"先手：先手の人\n",
+			"後手：後手の人\n",
+			"*対局前\n",
+			"手数----指手---------消費時間--\n",
+			"*開始局面\n",
+			"   1 ７六歩(77)   ( 0:03/00:00:03)\n",
+			"*一手目\n",
+			"   2 ３四歩(33)   ( 0:01/00:00:01)\n",
+			"   3 ２二角成(88)   ( 0:10/00:00:13)\n",
+			"   4 同　銀(31)   ( 0:02/00:00:03)\n",
+			"   5 ４五角打   ( 0:01/00:00:14)\n",
+			"   6 投了\n",
+			"*終局\n",
+			"まで5手で先手の勝ち\n",
+			"\n",
+			"変化：3手\n",
+			"   3 ２六歩(27)\n"
+		);
+
+		let data = KifParser::parse_str(s).unwrap();
+		let info = data.kifu_info.as_ref().unwrap();
+
+		assert_eq!(info.sente_name,Some(String::from("先手の人")));
+		assert_eq!(info.gote_name,Some(String::from("後手の人")));
+		assert_eq!(info.event,Some(String::from("テスト")));
+		assert_eq!(info.start_time,Some(String::from("2020/01/01 10:00:00")));
+		assert_eq!(info.time_limit,Some((30,Some(10))));
+
+		let csa = CsaParser::parse_str(concat!(
+			"V2.2\nPI\n+\n",
+			"+7776FU,T3\n-3334FU,T1\n+8822UM,T10\n-3122GI,T2\n+0045KA,T1\n%TORYO\n"
+		)).unwrap().remove(0);
+
+		assert_eq!(data.initial_position,csa.initial_position);
+		assert_eq!(data.teban_at_start,Teban::Sente);
+		assert!(data.moves.iter().eq(csa.moves.iter()));
+		assert_eq!(data.end_state,Some(EndState::Toryo));
+		assert_eq!(data.header_comments,vec!["対局前"]);
+		assert_eq!(data.position_comments,vec!["開始局面"]);
+		assert_eq!(data.moves.comments(0),&["一手目"]);
+		assert_eq!(data.end_state_comments,vec!["終局"]);
+	}
+
+	#[test]
+	fn board_and_hands_round_trip() {
+		let data = CsaParser::parse_str(concat!(
+			"V2.2\n",
+			"P1 *  *  *  * -OU *  *  *  * \n",
+			"P2 *  *  *  *  *  * +FU *  * \n",
+			"P3 *  *  *  *  *  *  *  *  * \n",
+			"P4 *  *  *  *  *  *  *  *  * \n",
+			"P5 *  *  *  * -HI *  *  *  * \n",
+			"P6 *  *  *  *  *  *  *  *  * \n",
+			"P7 *  *  *  *  *  *  *  *  * \n",
+			"P8 *  *  *  * +KI *  *  *  * \n",
+			"P9 *  *  *  * +OU *  *  *  * \n",
+			"P+00KA00FU00FU\n",
+			"P-00KI\n",
+			"-\n",
+			"-5554HI\n+3231TO\n-0052KI\n+0053FU\n"
+		)).unwrap().remove(0);
+
+		let s = data.to_kif().unwrap();
+		let parsed = KifParser::parse_str(&s).unwrap();
+
+		assert_eq!(parsed.initial_position,data.initial_position,"{}",s);
+		assert_eq!(parsed.initial_mochigoma,data.initial_mochigoma,"{}",s);
+		assert_eq!(parsed.teban_at_start,Teban::Gote,"{}",s);
+		assert_eq!(parsed.moves,data.moves,"{}",s);
+	}
+
+	#[test]
+	fn handicap_header() {
+		let data = KifParser::parse_str("手合割：香落ち\n手数----指手---------消費時間--\n   1 ３四歩(33)\n").unwrap();
+
+		assert_eq!(data.initial_position,Handicap::Kyou.banmen());
+		assert_eq!(data.teban_at_start,Teban::Gote);
+		assert_eq!(data.moves.len(),1);
+		assert!(data.to_kif().unwrap().contains("手合割：香落ち"));
+
+		assert!(KifParser::parse_str("手合割：九枚落ち\n").is_err());
+	}
+
+	#[test]
+	fn five_piece_handicaps() {
+		// 五枚落ちは８一、左五枚落ちは２一の桂を落とす
+		let Banmen(kinds) = KifParser::parse_str("手合割：五枚落ち\n").unwrap().initial_position;
+
+		assert_eq!(kinds[0][1],Blank);
+		assert_eq!(kinds[0][7],KomaKind::GKei);
+
+		let Banmen(kinds) = KifParser::parse_str("手合割：左五枚落ち\n").unwrap().initial_position;
+
+		assert_eq!(kinds[0][1],KomaKind::GKei);
+		assert_eq!(kinds[0][7],Blank);
+	}
+
+	#[test]
+	fn reject_bad_moves() {
+		for s in &["   1 同　歩(77)\n","   1 ７六歩(7)\n","   1 ０六歩(77)\n","   1 ７六と打\n","   1 ７六竜王(77)\n"] {
+			assert!(KifParser::parse_str(s).is_err(),"{}",s);
+		}
+	}
+
+	#[test]
+	fn decode_kif_bytes() {
+		let sjis = ::encoding_rs::SHIFT_JIS.encode("先手：先手の人\n").0.into_owned();
+
+		assert_eq!(KifParser::decode(&sjis).unwrap(),"先手：先手の人\n");
+		assert_eq!(KifParser::decode("\u{feff}先手：先手の人\n".as_bytes()).unwrap(),"先手：先手の人\n");
+	}
+
+	#[test]
+	fn writer_rejects_moves_without_a_piece() {
+		let mut data = CsaParser::parse_str("V2.2\nPI\n+\n").unwrap().remove(0);
+
+		data.moves.push(CsaMove::Move(Move::To(KomaSrcPosition(5,5),KomaDstToPosition(5,4,false)),None)).unwrap();
+
+		assert!(data.to_kif().is_err());
+	}
+}
@@ -1,16 +1,36 @@
 extern crate usiagent;
+extern crate encoding_rs;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 
 pub mod error;
+pub mod encoding;
+pub mod writer;
+pub mod sfen;
+pub mod kif;
+pub mod ki2;
+pub mod jkf;
+pub mod handicap;
+pub mod client;
+pub mod server;
+#[cfg(feature = "serde")]
+mod serialize;
 
 use std::io;
 use std::io::BufReader;
 use std::io::BufRead;
+use std::io::Cursor;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::path::Path;
 use std::str::Chars;
 use std::slice::Iter;
 use std::ops::Index;
+use std::time::Duration;
+use std::convert::TryFrom;
 
 use usiagent::shogi::*;
 use usiagent::shogi::KomaKind::{
@@ -45,11 +65,18 @@ use usiagent::shogi::KomaKind::{
 	Blank
 };
 use usiagent::rule::*;
+use usiagent::protocol::ToSfen;
+use usiagent::error::SfenStringConvertError;
 
 use error::*;
+use encoding::CsaEncoding;
+use encoding::LineDecoder;
 
 pub trait CsaStream {
 	fn next(&mut self) -> Result<Option<String>,CsaStreamReadError>;
+	fn location(&self) -> Option<SourceLocation> {
+		None
+	}
 	fn read_real_line(l:String) -> Vec<String> {
 		if l.starts_with('\'') {
 			vec![l]
@@ -60,73 +87,165 @@ pub trait CsaStream {
 		}
 	}
 }
-pub struct CsaFileStream {
-	reader:BufReader<File>,
+pub struct CsaReaderStream<R> where R: BufRead {
+	reader:R,
+	decoder:LineDecoder,
 	lines:Option<Vec<String>>,
 	current_pos:u32,
+	line_number:u32,
+	location:Option<SourceLocation>,
 }
-impl CsaFileStream {
-	pub fn new<P>(file:P) -> Result<CsaFileStream,io::Error> where P: AsRef<Path> {
-		let mut reader = BufReader::new(OpenOptions::new().read(true).create(false).open(file)?);
-		let mut buf = String::new();
-		let n = reader.read_line(&mut buf)?;
+impl<R> CsaReaderStream<R> where R: BufRead {
+	pub fn new(reader:R) -> CsaReaderStream<R> {
+		CsaReaderStream::with_encoding(reader,CsaEncoding::Auto)
+	}
 
-		let r: &[_] = &['\0'];
+	pub fn with_encoding(reader:R,encoding:CsaEncoding) -> CsaReaderStream<R> {
+		// 最初の行は文字コードの判定のため最初のnextの呼び出しで読む
+		CsaReaderStream {
+			reader,
+			decoder:LineDecoder::new(encoding),
+			lines:Some(Vec::new()),
+			current_pos:0,
+			line_number:0,
+			location:None,
+		}
+	}
 
-		buf = buf.trim_end().trim_end_matches(r).to_string();
+	fn read_physical_line(&mut self,buf:&mut String) -> Result<usize,CsaStreamReadError> {
+		self.line_number += 1;
+		self.location = Some(SourceLocation::new(self.line_number,1,1));
 
-		let lines = if n == 0 {
-			None
-		} else {
-			Some(CsaFileStream::read_real_line(buf))
-		};
+		let mut bytes = Vec::new();
 
-		Ok(CsaFileStream {
-			reader:reader,
-			lines:lines,
-			current_pos:0,
-		})
+		let n = self.reader.read_until(b'\n',&mut bytes)?;
+
+		let r: &[_] = &['\0'];
+
+		*buf = self.decoder.decode(&bytes)?.trim_end().trim_end_matches(r).to_string();
+
+		Ok(n)
 	}
 }
-impl CsaStream for CsaFileStream {
+impl<R> CsaStream for CsaReaderStream<R> where R: BufRead {
 	fn next(&mut self) -> Result<Option<String>,CsaStreamReadError> {
-		let read_next = match self.lines {
-			Some(ref lines) if self.current_pos >= lines.len() as u32 => true,
-			_ => false,
-		};
+		let read_next = matches!(self.lines,
+			Some(ref lines) if self.current_pos >= lines.len() as u32
+		);
 
 		if read_next {
 			self.current_pos = 0;
 
 			let mut buf = String::new();
-			let mut n = self.reader.read_line(&mut buf)?;
-
-			let r: &[_] = &['\0'];
+			let mut n = self.read_physical_line(&mut buf)?;
 
-			buf = buf.trim_end().trim_end_matches(r).to_string();
-
-			while n > 0 && buf == "" {
+			while n > 0 && buf.is_empty() {
 				buf.clear();
-				n = self.reader.read_line(&mut buf)?;
-				buf = buf.trim_end().trim_end_matches(r).to_string();
+				n = self.read_physical_line(&mut buf)?;
 			}
 
 			if n == 0 {
 				self.lines = None;
 			} else {
-				self.lines = Some(CsaFileStream::read_real_line(buf));
+				self.lines = Some(CsaReaderStream::<R>::read_real_line(buf));
 			}
 		}
 
 		match self.lines {
-			None => Ok(None),
+			None => {
+				self.location = None;
+				Ok(None)
+			},
 			Some(ref lines) => {
 				let p = self.current_pos;
 				self.current_pos += 1;
+
+				let column = lines.iter().take(p as usize).fold(1, |acc,s| {
+					acc + s.chars().count() as u32 + 1
+				});
+
+				self.location = Some(SourceLocation::new(self.line_number,p+1,column));
+
 				Ok(Some(lines[p as usize].clone()))
 			}
 		}
 	}
+
+	fn location(&self) -> Option<SourceLocation> {
+		self.location
+	}
+}
+pub struct CsaFileStream {
+	reader:CsaReaderStream<BufReader<File>>,
+}
+impl CsaFileStream {
+	pub fn new<P>(file:P) -> Result<CsaFileStream,io::Error> where P: AsRef<Path> {
+		CsaFileStream::with_encoding(file,CsaEncoding::Auto)
+	}
+
+	pub fn with_encoding<P>(file:P,encoding:CsaEncoding) -> Result<CsaFileStream,io::Error> where P: AsRef<Path> {
+		let reader = BufReader::new(OpenOptions::new().read(true).create(false).open(file)?);
+
+		Ok(CsaFileStream {
+			reader:CsaReaderStream::with_encoding(reader,encoding),
+		})
+	}
+}
+impl CsaStream for CsaFileStream {
+	fn next(&mut self) -> Result<Option<String>,CsaStreamReadError> {
+		self.reader.next()
+	}
+
+	fn location(&self) -> Option<SourceLocation> {
+		self.reader.location()
+	}
+}
+pub struct CsaStringStream {
+	reader:CsaReaderStream<Cursor<Vec<u8>>>,
+}
+impl CsaStringStream {
+	pub fn new<S>(s:S) -> CsaStringStream where S: Into<String> {
+		let reader = Cursor::new(s.into().into_bytes());
+
+		CsaStringStream {
+			// 文字列は既にデコード済みなので'encoding=の指定に関わらずUTF-8のまま読む
+			reader:CsaReaderStream::with_encoding(reader,CsaEncoding::Utf8),
+		}
+	}
+}
+impl CsaStream for CsaStringStream {
+	fn next(&mut self) -> Result<Option<String>,CsaStreamReadError> {
+		self.reader.next()
+	}
+
+	fn location(&self) -> Option<SourceLocation> {
+		self.reader.location()
+	}
+}
+pub struct CsaBytesStream {
+	reader:CsaReaderStream<Cursor<Vec<u8>>>,
+}
+impl CsaBytesStream {
+	pub fn new<B>(bytes:B) -> CsaBytesStream where B: Into<Vec<u8>> {
+		CsaBytesStream::with_encoding(bytes,CsaEncoding::Auto)
+	}
+
+	pub fn with_encoding<B>(bytes:B,encoding:CsaEncoding) -> CsaBytesStream where B: Into<Vec<u8>> {
+		let reader = Cursor::new(bytes.into());
+
+		CsaBytesStream {
+			reader:CsaReaderStream::with_encoding(reader,encoding),
+		}
+	}
+}
+impl CsaStream for CsaBytesStream {
+	fn next(&mut self) -> Result<Option<String>,CsaStreamReadError> {
+		self.reader.next()
+	}
+
+	fn location(&self) -> Option<SourceLocation> {
+		self.reader.location()
+	}
 }
 pub struct CsaStringReader {
 
@@ -144,9 +263,7 @@ impl CsaStringReader {
 		for _ in 0..len {
 			match chars.next() {
 				None => {
-					return Err(CsaParserError::FormatError(String::from(
-						"Invalid csa format, Could not read the specified length."
-					)));
+					return Err(CsaParserError::FormatError(CsaFormatErrorKind::UnexpectedEndOfStatement,None));
 				},
 				Some(c) => {
 					s.push(c);
@@ -157,18 +274,43 @@ impl CsaStringReader {
 		Ok(s)
 	}
 }
+impl Default for CsaStringReader {
+	fn default() -> CsaStringReader {
+		CsaStringReader::new()
+	}
+}
 pub struct CsaParser<S> where S: CsaStream {
 	st:S,
+	location:Option<SourceLocation>,
+	separated:bool,
+	eof:bool,
+	strict:bool,
 }
 impl<S> CsaParser<S> where S: CsaStream {
 	pub fn new(st:S) -> CsaParser<S> where S: CsaStream {
 		CsaParser {
-			st:st
+			st,
+			location:None,
+			separated:false,
+			eof:false,
+			strict:false,
 		}
 	}
 
-	fn read_next_lines<F>(&mut self,lines:&mut Vec<String>, comments:&mut Vec<String>,cond:F)
-		-> Result<Option<String>,CsaStreamReadError> where F: Fn(&String) -> bool {
+	pub fn strict(mut self,strict:bool) -> CsaParser<S> {
+		self.strict = strict;
+		self
+	}
+
+	fn statement(&self,line:&str) -> SourceStatement {
+		SourceStatement::new(self.location,line.to_string())
+	}
+
+	fn read_next_lines<F>(&mut self,lines:&mut Vec<String>,
+							locations:&mut Vec<Option<SourceLocation>>,
+							marks:&mut Vec<usize>,
+							comments:&mut Vec<String>,cond:F)
+		-> Result<Option<String>,CsaParserError> where F: Fn(&String) -> bool {
 
 		let mut current = self.read_next(comments)?;
 
@@ -183,6 +325,8 @@ impl<S> CsaParser<S> where S: CsaStream {
 
 		while cond(&l) {
 			lines.push(l.clone());
+			locations.push(self.location);
+			marks.push(comments.len());
 
 			current = self.read_next(comments)?;
 
@@ -201,6 +345,42 @@ impl<S> CsaParser<S> where S: CsaStream {
 
 	pub fn parse(&mut self) -> Result<Vec<CsaData>,CsaParserError> {
 		let mut results:Vec<CsaData> = Vec::new();
+
+		loop {
+			match self.parse_game()? {
+				Some(data) => {
+					results.push(data);
+				},
+				None => {
+					return Err(CsaParserError::FormatError(
+						CsaFormatErrorKind::UnexpectedEndOfInput { stage:Stage::Initial },None
+					));
+				}
+			}
+
+			if self.eof {
+				return Ok(results);
+			}
+		}
+	}
+
+	pub fn games(&mut self) -> CsaGames<'_,S> {
+		CsaGames {
+			parser:self,
+		}
+	}
+
+	fn skip_to_separator(&mut self) {
+		let mut comments = Vec::new();
+
+		while !self.separated && !self.eof {
+			if self.read_next(&mut comments).is_err() {
+				self.eof = true;
+			}
+		}
+	}
+
+	fn parse_game(&mut self) -> Result<Option<CsaData>,CsaParserError> {
 		let mut comments:Vec<String> = Vec::new();
 		let mut stage = Stage::Initial;
 
@@ -217,6 +397,13 @@ impl<S> CsaParser<S> where S: CsaStream {
 		let mut mvs:CsaMoves = CsaMoves::new();
 		let mut end_state = None;
 
+		// 局面の開始位置と各指し手の位置をコメントの数で記録しておき、
+		// 読み込み後にコメントをその直前の局面または指し手に割り当てる
+		let mut position_mark = 0;
+		let mut move_marks:Vec<usize> = Vec::new();
+		let mut end_state_mark = None;
+		let mut positions:Vec<CsaPlyState> = Vec::new();
+
 		while let Some(line) = current {
 			if line.starts_with("V") && stage == Stage::Initial {
 				stage = Stage::Version;
@@ -228,124 +415,235 @@ impl<S> CsaParser<S> where S: CsaStream {
 						(stage == Stage::Version || stage == Stage::Info) {
 				stage = Stage::Info;
 
-				if let None = info {
+				if info.is_none() {
 					info = Some(KifuInfo::new());
 				}
 
 				if let Some(ref mut info) = info {
-					info.parse(&line)?;
+					info.parse_with_version(&line,version.as_deref()).map_err(|e| {
+						e.with_statement(self.statement(&line))
+					})?;
 				}
 				current = self.read_next(&mut comments)?;
 			} else if line.starts_with("PI") && stage >= Stage::Version && stage <= Stage::Info {
 				stage = Stage::Position;
-				let (b,m) = CsaPositionParser::new().parse(vec![line.clone()])?;
+				position_mark = comments.len();
+
+				let mut lines:Vec<String> = Vec::new();
+				let mut locations:Vec<Option<SourceLocation>> = Vec::new();
+				let mut marks:Vec<usize> = Vec::new();
+
+				let l = line;
+				lines.push(l.clone());
+				locations.push(self.location);
+				marks.push(comments.len());
+
+				// PIの後にP+/P-で駒を追加できる
+				current = self.read_next_lines(&mut lines,&mut locations,&mut marks,&mut comments,|s| {
+					s.starts_with("P+") || s.starts_with("P-")
+				})?;
+
+				let mut parser = CsaPositionParser::new();
+
+				let (b,m) = parser.parse(&lines).map_err(|e| {
+					e.with_statement(SourceStatement::new(locations[parser.line],lines[parser.line].clone()))
+				})?;
 				banmen = b;
 				mc = m;
-				current = self.read_next(&mut comments)?;
 			} else if (line.starts_with("P1") ||
 						line.starts_with("P+") ||
 						line.starts_with("P-")) &&
 							stage >= Stage::Version && stage <= Stage::Info {
 				stage = Stage::Position;
+				position_mark = comments.len();
 
 				let mut lines:Vec<String> = Vec::new();
+				let mut locations:Vec<Option<SourceLocation>> = Vec::new();
+				let mut marks:Vec<usize> = Vec::new();
 
 				let l = line;
 				lines.push(l.clone());
+				locations.push(self.location);
+				marks.push(comments.len());
 
-				current = self.read_next_lines(&mut lines,&mut comments,|s| {
+				current = self.read_next_lines(&mut lines,&mut locations,&mut marks,&mut comments,|s| {
 					s.starts_with("P")
 				})?;
 
-				let (b,m) = CsaPositionParser::new().parse(lines)?;
+				let mut parser = CsaPositionParser::new();
+
+				let (b,m) = parser.parse(&lines).map_err(|e| {
+					e.with_statement(SourceStatement::new(locations[parser.line],lines[parser.line].clone()))
+				})?;
 				banmen = b;
 				mc = m;
 			} else if (line == "+" || line == "-") && stage == Stage::Position {
 				stage = Stage::Moves;
 
 				let mut lines:Vec<String> = Vec::new();
+				let mut locations:Vec<Option<SourceLocation>> = Vec::new();
+				let mut marks:Vec<usize> = Vec::new();
 
 				let l = line;
 				lines.push(l.clone());
+				locations.push(self.location);
+				marks.push(comments.len());
 
-				current = self.read_next_lines(&mut lines,&mut comments,|s| {
+				current = self.read_next_lines(&mut lines,&mut locations,&mut marks,&mut comments,|s| {
 					s.starts_with("+") || s.starts_with("-") || s.starts_with("T") || s.starts_with("%")
 				})?;
 
-				let (t,m,s) = CsaMovesParser::new().parse(lines,&banmen)?;
+				// V行が無い場合はV2.2として扱い、V3.0の終局状態は受け付けない
+				let mut parser = CsaMovesParser::new(version.as_ref().map(|v| is_v3(v)).unwrap_or(false),self.strict);
+
+				let (t,m,s,p) = parser.parse(&lines,&banmen,&mc).map_err(|e| {
+					e.with_statement(SourceStatement::new(locations[parser.line],lines[parser.line].clone()))
+				})?;
 
 				teban = t;
 				mvs = m;
 				end_state = s;
+				move_marks = parser.move_lines.iter().map(|&i| marks[i]).collect();
+				end_state_mark = parser.end_line.map(|i| marks[i]);
+				positions = p;
 			} else if line == "/" && stage >= Stage::Position {
-				stage = Stage::Initial;
-
-				results.push(CsaData::new(version,
-											info,
-											teban,
-											banmen,
-											mc,
-											mvs,
-											end_state,
-											comments));
-				version = None;
-				info = None;
-				banmen = Banmen([[KomaKind::Blank; 9]; 9]);
-				mc = MochigomaCollections::Pair(Mochigoma::new(),Mochigoma::new());
-				mvs = CsaMoves::new();
-				end_state = None;
-				comments = Vec::new();
-				current = self.read_next(&mut comments)?;
+				break;
 			} else {
-				return Err(CsaParserError::FormatError(String::from("Invalid csa format.")));
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::UnexpectedStatement { stage },
+														Some(self.statement(&line))));
 			}
 		}
 
 		if stage >= Stage::Position {
-			results.push(CsaData::new(version,
+			// 終局状態の後のコメントは指し手ではなく終局状態に割り当てる
+			let end_mark = end_state_mark.unwrap_or(comments.len());
+
+			for (i,&m) in move_marks.iter().enumerate() {
+				let next = move_marks.get(i+1).cloned().unwrap_or(end_mark);
+
+				for c in &comments[m..next] {
+					mvs.add_comment(i,c.clone());
+				}
+
+				let t = if i % 2 == 0 {
+					teban.opposite()
+				} else {
+					teban
+				};
+
+				// 評価値と読み筋のコメントは自由形式なので、解釈できないものは無視する
+				if let CsaMove::Move(_,_) = mvs[i] {
+					let e = comments[m..next].iter().filter(|c| c.starts_with("**")).filter_map(|c| {
+						EngineComment::parse(c,t,&positions[i+1].0,&positions[i+1].1).ok()
+					}).next_back();
+
+					if let Some(e) = e {
+						mvs.set_engine_comment(i,e);
+					}
+				}
+			}
+
+			let position_comments = comments[position_mark..move_marks.first().cloned().unwrap_or(end_mark)].to_vec();
+			let end_state_comments = comments[end_mark..].to_vec();
+
+			comments.truncate(position_mark);
+
+			let mut data = CsaData::new(version,
 										info,
 										teban,
 										banmen,
 										mc,
-										mvs,end_state,comments));
-			Ok(results)
+										mvs,end_state,comments);
+
+			data.position_comments = position_comments;
+			data.end_state_comments = end_state_comments;
+
+			Ok(Some(data))
+		} else if stage == Stage::Initial {
+			Ok(None)
 		} else {
-			Err(CsaParserError::FormatError(String::from("Invalid csa format.")))
+			Err(CsaParserError::FormatError(CsaFormatErrorKind::UnexpectedEndOfInput { stage },None))
 		}
 	}
 
-	fn read_next(&mut self, comments:&mut Vec<String>) -> Result<Option<String>,CsaStreamReadError> {
-		while let Some(ref line) = self.st.next()? {
-			if line.starts_with("'") {
-				comments.push(String::from(&line.as_str()[1..]));
-			} else {
-				return Ok(Some(line.clone()));
+	fn read_next(&mut self, comments:&mut Vec<String>) -> Result<Option<String>,CsaParserError> {
+		loop {
+			let line = self.st.next().map_err(|e| {
+				self.eof = true;
+				CsaParserError::StreamReadError(e,Some(SourceStatement::new(self.st.location(),String::new())))
+			})?;
+
+			self.location = self.st.location();
+			self.separated = line.as_ref().map(|l| l == "/").unwrap_or(false);
+			self.eof = line.is_none();
+
+			match line {
+				Some(ref line) if line.starts_with("'") => {
+					comments.push(String::from(&line.as_str()[1..]));
+				},
+				line => {
+					return Ok(line);
+				}
 			}
 		}
+	}
+}
+pub struct CsaGames<'a,S> where S: CsaStream + 'a {
+	parser:&'a mut CsaParser<S>,
+}
+impl<'a,S> Iterator for CsaGames<'a,S> where S: CsaStream {
+	type Item = Result<CsaData,CsaParserError>;
+
+	fn next(&mut self) -> Option<Result<CsaData,CsaParserError>> {
+		if self.parser.eof {
+			return None;
+		}
 
-		Ok(None)
+		match self.parser.parse_game() {
+			Ok(Some(data)) => Some(Ok(data)),
+			Ok(None) => None,
+			Err(e) => {
+				self.parser.skip_to_separator();
+				Some(Err(e))
+			}
+		}
+	}
+}
+impl CsaParser<CsaStringStream> {
+	pub fn parse_str(s:&str) -> Result<Vec<CsaData>,CsaParserError> {
+		CsaParser::new(CsaStringStream::new(s)).parse()
 	}
 }
 #[derive(Clone, Copy, Eq, PartialOrd, PartialEq, Debug)]
-enum Stage {
+pub enum Stage {
 	Initial = 0,
 	Version,
 	Info,
 	Position,
 	Moves,
 }
-#[derive(Debug)]
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CsaData {
 	pub version:Option<String>,
 	pub kifu_info:Option<KifuInfo>,
+	#[cfg_attr(feature = "serde", serde(with = "serialize::teban"))]
 	pub teban_at_start:Teban,
+	#[cfg_attr(feature = "serde", serde(with = "serialize::banmen"))]
 	pub initial_position:Banmen,
+	#[cfg_attr(feature = "serde", serde(with = "serialize::mochigoma"))]
 	pub initial_mochigoma:MochigomaCollections,
 	pub moves:CsaMoves,
 	pub end_state:Option<EndState>,
-	pub comments:Vec<String>,
+	#[cfg_attr(feature = "serde", serde(default))]
+	pub header_comments:Vec<String>,
+	#[cfg_attr(feature = "serde", serde(default))]
+	pub position_comments:Vec<String>,
+	#[cfg_attr(feature = "serde", serde(default))]
+	pub end_state_comments:Vec<String>, // 終局状態の後のコメント
 }
 impl CsaData {
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(version:Option<String>,
 				kifu_info:Option<KifuInfo>,
 				teban:Teban,
@@ -355,18 +653,187 @@ impl CsaData {
 				end_state:Option<EndState>,
 				comments:Vec<String>) -> CsaData {
 		CsaData {
-			version:version,
-			kifu_info:kifu_info,
+			version,
+			kifu_info,
 			teban_at_start:teban,
 			initial_position:banmen,
 			initial_mochigoma:mochigoma,
 			moves:mvs,
-			end_state:end_state,
-			comments:comments,
+			end_state,
+			// 位置の分からないコメントは棋譜の先頭のコメントとして扱う
+			header_comments:comments,
+			position_comments:Vec::new(),
+			end_state_comments:Vec::new(),
+		}
+	}
+
+	// 棋譜情報・開始局面・指し手・終局状態のコメントを棋譜に現れる順に並べて返す
+	pub fn comments(&self) -> Vec<String> {
+		let mut comments = self.header_comments.clone();
+
+		comments.extend(self.position_comments.iter().cloned());
+
+		for i in 0..self.moves.len() {
+			comments.extend(self.moves.comments(i).iter().cloned());
+		}
+
+		comments.extend(self.end_state_comments.iter().cloned());
+
+		comments
+	}
+
+	pub fn positions(&self) -> CsaPositions<'_> {
+		CsaPositions {
+			data:self,
+			banmen:match self.initial_position {
+				Banmen(ref kinds) => *kinds
+			},
+			mc:self.initial_mochigoma.clone(),
+			teban:self.teban_at_start,
+			ply:0,
+		}
+	}
+
+	pub fn position_at(&self,ply:u32) -> Option<CsaPosition> {
+		self.positions().nth(ply as usize)
+	}
+
+	pub fn result(&self) -> Option<GameResult> {
+		// %KACHI等の特殊な手や終局状態は手番を進めないので、終局時の手番は指し手の数だけで決まる
+		let count = self.moves.iter().filter(|m| matches!(**m, CsaMove::Move(_,_))).count();
+
+		let teban = if count % 2 == 0 {
+			self.teban_at_start
+		} else {
+			self.teban_at_start.opposite()
+		};
+
+		let reason = match (self.end_state,self.moves.iter().last()) {
+			(Some(s),_) => s,
+			(None,Some(&CsaMove::Kachi(_))) => EndState::Kachi,
+			(None,Some(&CsaMove::Hikiwake(_))) => EndState::Hikiwake,
+			(None,_) => {
+				return None;
+			}
+		};
+
+		let winner = match reason {
+			EndState::Toryo | EndState::TimeUp | EndState::IllegalMove | EndState::Tsumi => Some(teban.opposite()),
+			EndState::Kachi => Some(teban),
+			EndState::SIllegalAction | EndState::SFoul => Some(Teban::Gote),
+			EndState::GIllegalAction | EndState::GFoul => Some(Teban::Sente),
+			EndState::Chudan |
+				EndState::Sennichite |
+				EndState::Jishogi |
+				EndState::Hikiwake |
+				EndState::Matta |
+				EndState::Fuzumi |
+				EndState::Error |
+				EndState::MaxMoves => None,
+		};
+
+		Some(GameResult {
+			winner,
+			reason,
+		})
+	}
+
+	pub fn to_usi_position(&self) -> Result<String,SfenStringConvertError> {
+		// 勝ち宣言や千日手などの指し手以外の手はUSIで表現できないのでそこで打ち切る
+		let mvs = self.moves.iter().map_while(|m| {
+			match *m {
+				CsaMove::Move(m,_) => Some(m),
+				_ => None,
+			}
+		}).collect::<Vec<Move>>();
+
+		let p = (self.teban_at_start,self.initial_position.clone(),self.initial_mochigoma.clone(),mvs).to_sfen()?;
+
+		Ok(format!("position {}",p))
+	}
+}
+// 読み込んだ順にコメントを棋譜情報・開始局面・指し手・終局状態のいずれかに割り当てる
+pub(crate) struct CommentRouter {
+	header_comments:Vec<String>,
+	position_comments:Vec<String>,
+	end_state_comments:Vec<String>,
+}
+impl CommentRouter {
+	pub(crate) fn new() -> CommentRouter {
+		CommentRouter {
+			header_comments:Vec::new(),
+			position_comments:Vec::new(),
+			end_state_comments:Vec::new(),
+		}
+	}
+
+	pub(crate) fn push(&mut self,c:String,in_header:bool,mvs:&mut CsaMoves,ended:bool) {
+		if in_header {
+			self.header_comments.push(c);
+		} else if ended {
+			self.end_state_comments.push(c);
+		} else if mvs.is_empty() {
+			self.position_comments.push(c);
+		} else {
+			mvs.add_comment(mvs.len() - 1,c);
+		}
+	}
+
+	pub(crate) fn assign(self,data:&mut CsaData) {
+		data.header_comments = self.header_comments;
+		data.position_comments = self.position_comments;
+		data.end_state_comments = self.end_state_comments;
+	}
+}
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CsaPosition {
+	#[cfg_attr(feature = "serde", serde(with = "serialize::banmen"))]
+	pub banmen:Banmen,
+	#[cfg_attr(feature = "serde", serde(with = "serialize::mochigoma"))]
+	pub mochigoma:MochigomaCollections,
+	#[cfg_attr(feature = "serde", serde(with = "serialize::teban"))]
+	pub teban:Teban,
+	pub ply:u32,
+}
+pub struct CsaPositions<'a> {
+	data:&'a CsaData,
+	banmen:[[KomaKind; 9]; 9],
+	mc:MochigomaCollections,
+	teban:Teban,
+	ply:u32,
+}
+impl<'a> Iterator for CsaPositions<'a> {
+	type Item = CsaPosition;
+
+	fn next(&mut self) -> Option<CsaPosition> {
+		let ply = self.ply as usize;
+
+		if ply > self.data.moves.len() {
+			return None;
+		}
+
+		// %KACHI等の特殊な手は手番を進めない
+		if ply > 0 {
+			if let CsaMove::Move(ref m,_) = self.data.moves[ply - 1] {
+				CsaMovesParser::apply_move(&mut self.banmen,&mut self.mc,self.teban,m);
+
+				self.teban = self.teban.opposite();
+			}
 		}
+
+		self.ply += 1;
+
+		Some(CsaPosition {
+			banmen:Banmen(self.banmen),
+			mochigoma:self.mc.clone(),
+			teban:self.teban,
+			ply:ply as u32,
+		})
 	}
 }
-#[derive(Debug)]
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct KifuInfo {
 	pub sente_name:Option<String>,
 	pub gote_name:Option<String>,
@@ -376,6 +843,10 @@ pub struct KifuInfo {
 	pub end_time:Option<String>,
 	pub time_limit:Option<(u32,Option<u32>)>,
 	pub opening:Option<String>,
+	pub time:Option<TimeSettings>,
+	pub max_moves:Option<u32>,
+	pub jishogi:Option<u32>,
+	pub note:Option<String>,
 }
 impl KifuInfo {
 	pub fn new() -> KifuInfo {
@@ -388,26 +859,51 @@ impl KifuInfo {
 			end_time:None,
 			time_limit:None,
 			opening:None,
+			time:None,
+			max_moves:None,
+			jishogi:None,
+			note:None,
 		}
 	}
 
-	pub fn parse(&mut self, line:&String) -> Result<(),CsaParserError> {
+	// バージョンを指定しない場合はV3.0より前の形式として解釈する
+	pub fn parse(&mut self, line:&str) -> Result<(),CsaParserError> {
+		self.parse_with_version(line,None)
+	}
+
+	pub fn parse_with_version(&mut self, line:&str, version:Option<&str>) -> Result<(),CsaParserError> {
 		let mut reader = CsaStringReader::new();
 
-		if line.starts_with("N+") {
-			self.sente_name = Some(String::from(&line.as_str()[2..]));
-		} else if line.starts_with("N-") {
-			self.gote_name = Some(String::from(&line.as_str()[2..]));
-		} else if line.starts_with("$EVENT:") {
-			self.event = Some(String::from(&line.as_str()[7..]));
-		} else if line.starts_with("$SITE:") {
-			self.site = Some(String::from(&line.as_str()[6..]));
-		} else if line.starts_with("$START_TIME:") {
-			self.start_time = Some(String::from(&line.as_str()[12..]));
-		} else if line.starts_with("$END_TIME:") {
-			self.end_time = Some(String::from(&line.as_str()[10..]));
-		} else if line.starts_with("$TIME_LIMIT:") {
-			let t = String::from(&line.as_str()[12..]);
+		// V3.0で追加された棋譜情報は、バージョンが3.0未満の棋譜では受け付けない
+		// バージョンが無い場合はV3.0より前の形式として扱う
+		let v3 = version.map(is_v3).unwrap_or(false);
+
+		if let Some(t) = line.strip_prefix("$TIME:").filter(|_| v3) {
+			let mut time = self.time.unwrap_or_default();
+
+			time.parse(t)?;
+
+			self.time = Some(time);
+		} else if let Some(s) = line.strip_prefix("$MAX_MOVES:").filter(|_| v3) {
+			self.max_moves = Some(s.parse()?);
+		} else if let Some(s) = line.strip_prefix("$JISHOGI:").filter(|_| v3) {
+			self.jishogi = Some(s.parse()?);
+		} else if let Some(s) = line.strip_prefix("$NOTE:").filter(|_| v3) {
+			self.note = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("N+") {
+			self.sente_name = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("N-") {
+			self.gote_name = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("$EVENT:") {
+			self.event = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("$SITE:") {
+			self.site = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("$START_TIME:") {
+			self.start_time = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("$END_TIME:") {
+			self.end_time = Some(String::from(s));
+		} else if let Some(s) = line.strip_prefix("$TIME_LIMIT:") {
+			let t = String::from(s);
 			let mut chars = t.chars();
 
 			let hh = reader.read(&mut chars, 2)?;
@@ -415,17 +911,13 @@ impl KifuInfo {
 
 			let delimiter = chars.next();
 
-			if let None = delimiter {
-				return Err(CsaParserError::FormatError(String::from(
-					"Invalid csa info format of timelimit."
-				)));
+			if delimiter.is_none() {
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTimeLimit,None));
 			}
 
 			if let Some(c) = delimiter {
 				if c != ':' {
-					return Err(CsaParserError::FormatError(String::from(
-						"Invalid csa info format of timelimit."
-					)));
+					return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTimeLimit,None));
 				}
 			}
 
@@ -444,26 +936,179 @@ impl KifuInfo {
 					match chars.next() {
 						None => Some(s),
 						Some(_) => {
-							return Err(CsaParserError::FormatError(String::from(
-								"Invalid csa info format of timelimit."
-							)));
+							return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTimeLimit,None));
 						}
 					}
 				},
 				_ => {
-					return Err(CsaParserError::FormatError(String::from(
-						"Invalid csa info format of timelimit."
-					)));
+					return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTimeLimit,None));
 				}
 			};
 
 			self.time_limit = Some((h * 60 + m, s));
-		} else if line.starts_with("$OPENING:") {
-			self.opening = Some(String::from(&line.as_str()[9..]));
+		} else if let Some(s) = line.strip_prefix("$OPENING:") {
+			self.opening = Some(String::from(s));
 		} else {
-			return Err(CsaParserError::FormatError(String::from(
-				"Invalid csa info format."
-			)));
+			return Err(CsaParserError::FormatError(CsaFormatErrorKind::UnknownInfoKey,None));
+		}
+
+		Ok(())
+	}
+}
+impl Default for KifuInfo {
+	fn default() -> KifuInfo {
+		KifuInfo::new()
+	}
+}
+fn is_v3(version:&str) -> bool {
+	version.split('.').next().and_then(|v| v.parse::<u32>().ok()).map(|v| v >= 3).unwrap_or(false)
+}
+// 指し手の移動元と移動先が盤内にあり、移動元に駒があることを確かめて動かす駒を返す(駒を打つ手はNone)
+pub(crate) fn moving_piece(kinds:&[[KomaKind; 9]; 9],m:&Move) -> Result<Option<KomaKind>,CsaWriterError> {
+	let on_board = |x:u32,y:u32| (1..=9).contains(&x) && (1..=9).contains(&y);
+	let out_of_board = || CsaWriterError::InvalidStateError(String::from("The move is out of the board."));
+
+	match *m {
+		Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,_)) => {
+			if !on_board(sx,sy) || !on_board(dx,dy) {
+				return Err(out_of_board());
+			}
+
+			match kinds[sy as usize - 1][9 - sx as usize] {
+				Blank => {
+					Err(CsaWriterError::InvalidStateError(String::from(
+						"There is no piece at the source square."
+					)))
+				},
+				k => Ok(Some(k)),
+			}
+		},
+		Move::Put(_,KomaDstPutPosition(dx,dy)) => {
+			if !on_board(dx,dy) {
+				return Err(out_of_board());
+			}
+
+			Ok(None)
+		}
+	}
+}
+fn parse_duration(s:&str,unit:Duration) -> Result<Duration,CsaParserError> {
+	let mut parts = s.splitn(2,'.');
+
+	let secs:u64 = match parts.next() {
+		Some(p) => p.parse()?,
+		None => 0,
+	};
+
+	let nanos:u64 = match parts.next() {
+		Some(p) if !p.is_empty() && p.len() <= 9 && p.chars().all(|c| c.is_ascii_digit()) => {
+			p.parse::<u64>()? * 10u64.pow(9 - p.len() as u32)
+		},
+		Some(_) => {
+			return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTime,None));
+		},
+		None => 0,
+	};
+
+	let total = (secs as u128 * 1_000_000_000 + nanos as u128) * unit.as_nanos() / 1_000_000_000;
+
+	Ok(Duration::new((total / 1_000_000_000) as u64,(total % 1_000_000_000) as u32))
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimeSettings {
+	pub time_unit:Duration, // Total_Time等の値の単位
+	pub total_time:Duration, // 持ち時間
+	pub byoyomi:Duration, // 秒読み
+	pub least_time_per_move:Duration, // 一手毎に最低限消費される時間
+	pub increment:Duration, // 一手毎の加算時間(フィッシャールール)
+	pub delay:Duration, // 一手毎の消費時間から差し引かれる時間
+	pub time_roundup:bool, // 消費時間の端数を切り上げるか否か
+}
+impl Default for TimeSettings {
+	fn default() -> TimeSettings {
+		TimeSettings {
+			time_unit:Duration::from_secs(1),
+			total_time:Duration::from_secs(0),
+			byoyomi:Duration::from_secs(0),
+			least_time_per_move:Duration::from_secs(0),
+			increment:Duration::from_secs(0),
+			delay:Duration::from_secs(0),
+			time_roundup:false,
+		}
+	}
+}
+impl TimeSettings {
+	pub fn parse(&mut self,s:&str) -> Result<(),CsaParserError> {
+		match s.find(':') {
+			Some(p) => {
+				self.parse_item(&s[0..p],&s[(p+1)..])
+			},
+			None => {
+				let items = s.split('+').collect::<Vec<&str>>();
+
+				if items.len() != 3 {
+					return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTime,None));
+				}
+
+				let unit = Duration::from_secs(1);
+
+				self.total_time = parse_duration(items[0],unit)?;
+				self.byoyomi = parse_duration(items[1],unit)?;
+				self.increment = parse_duration(items[2],unit)?;
+
+				Ok(())
+			}
+		}
+	}
+
+	pub fn parse_item(&mut self,key:&str,value:&str) -> Result<(),CsaParserError> {
+		match key {
+			"Time_Unit" => {
+				let p = value.find(|c:char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+
+				let unit = match &value[p..] {
+					"msec" => Duration::from_millis(1),
+					"sec" => Duration::from_secs(1),
+					"min" => Duration::from_secs(60),
+					_ => {
+						return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTime,None));
+					}
+				};
+
+				self.time_unit = if p == 0 {
+					unit
+				} else {
+					parse_duration(&value[0..p],unit)?
+				};
+			},
+			"Total_Time" => {
+				self.total_time = parse_duration(value,self.time_unit)?;
+			},
+			"Byoyomi" => {
+				self.byoyomi = parse_duration(value,self.time_unit)?;
+			},
+			"Least_Time_Per_Move" => {
+				self.least_time_per_move = parse_duration(value,self.time_unit)?;
+			},
+			"Increment" => {
+				self.increment = parse_duration(value,self.time_unit)?;
+			},
+			"Delay" => {
+				self.delay = parse_duration(value,self.time_unit)?;
+			},
+			"Time_Roundup" => {
+				self.time_roundup = match value {
+					"YES" => true,
+					"NO" => false,
+					_ => {
+						return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTime,None));
+					}
+				};
+			},
+			_ => {
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::BadTime,None));
+			}
 		}
 
 		Ok(())
@@ -483,9 +1128,7 @@ impl<'a> TryFromCsa<&'a String> for MochigomaKind {
 			"KA" | "UM" => MochigomaKind::Kaku,
 			"HI" | "RY" => MochigomaKind::Hisha,
 			_ => {
-				return Err(CsaParserError::FormatError(String::from(
-					"Invalid csa position format."
-				)));
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::UnknownPieceCode,None));
 			}
 		})
 	}
@@ -513,9 +1156,7 @@ impl<'a> TryFromCsa<(Teban,&'a String)> for KomaKind {
 			"RY" if teban == Teban::Sente => SHishaN,
 			"RY" => GHishaN,
 			_ => {
-				return Err(CsaParserError::FormatError(String::from(
-					"Invalid csa position format."
-				)));
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::UnknownPieceCode,None));
 			}
 		})
 	}
@@ -537,377 +1178,471 @@ impl<'a> TryFromCsa<&'a String> for EndState {
 			"%TSUMI" => EndState::Tsumi,
 			"%FUZUMI" => EndState::Fuzumi,
 			"%ERROR" => EndState::Error,
+			"%MAX_MOVES" => EndState::MaxMoves,
+			"%+FOUL" => EndState::SFoul,
+			"%-FOUL" => EndState::GFoul,
 			_ => {
-				return Err(CsaParserError::FormatError(String::from(
-					"Invalid end state."))
-				);
+				return Err(CsaParserError::FormatError(CsaFormatErrorKind::UnknownEndState,None));
 			}
 		})
 	}
 }
 struct CsaPositionParser {
-
+	line:usize,
 }
 impl CsaPositionParser {
 	pub fn new() -> CsaPositionParser {
 		CsaPositionParser {
-
+			line:0,
 		}
 	}
 
-	fn create_error(&self) -> CsaParserError {
-		CsaParserError::FormatError(String::from(
-			"Invalid csa position format."
-		))
+	fn create_error(&self,kind:CsaFormatErrorKind) -> CsaParserError {
+		CsaParserError::FormatError(kind,None)
 	}
 
-	pub fn parse(&mut self, lines:Vec<String>)
+	pub fn parse(&mut self, lines:&[String])
 		-> Result<(Banmen,MochigomaCollections),CsaParserError> {
 
-		if lines.len() == 0 {
+		if lines.is_empty() {
 			return Err(CsaParserError::InvalidStateError(String::from(
 				"lines is empty."
-			)));
+			),None));
 		}
 
-		let mut reader = CsaStringReader::new();
+		let (mut kinds,start) = if lines[0].starts_with("PI") {
+			(self.parse_removed(&lines[0])?,1)
+		} else if lines[0].starts_with("P1") {
+			if lines.len() < 9 {
+				self.line = lines.len() - 1;
+				return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
+			}
+
+			(self.parse_board(&lines[..9])?,9)
+		} else if lines[0].starts_with("P+") || lines[0].starts_with("P-") {
+			([[Blank; 9]; 9],0)
+		} else {
+			return Err(self.create_error(CsaFormatErrorKind::UnexpectedStatement { stage:Stage::Position }));
+		};
+
+		// 盤上と持ち駒のどちらにもまだ置かれていない駒(00ALで配られる)
+		let mut rest = Mochigoma::new();
+
+		for m in &MOCHIGOMA_KINDS {
+			rest.insert(*m,Mochigoma::filled().get(*m) * 2);
+		}
+
+		let mut kings = (0,0);
+
+		for (y,row) in kinds.iter().enumerate() {
+			if start == 9 {
+				self.line = y;
+			}
+
+			for &k in row.iter().filter(|&&k| k != Blank) {
+				self.take_piece(&mut rest,&mut kings,k)?;
+			}
+		}
+
+		let mut ms = Mochigoma::new();
+		let mut mg = Mochigoma::new();
+
+		for i in start..lines.len() {
+			self.line = i;
+
+			let line = &lines[i];
+
+			let teban = if line.starts_with("P+") {
+				Teban::Sente
+			} else if line.starts_with("P-") {
+				Teban::Gote
+			} else {
+				return Err(self.create_error(CsaFormatErrorKind::UnexpectedStatement { stage:Stage::Position }));
+			};
+
+			let mut reader = CsaStringReader::new();
+			let mut chars = line.chars();
 
-		if lines[0].starts_with("PI") {
-			let initial_banmen = BANMEN_START_POS.clone();
-			let mut initial_banmen = initial_banmen.0;
-			let mut chars = lines[0].chars();
 			chars.next();
 			chars.next();
 
-			while let Some(c) = chars.next() {
-				let x = c;
-
+			while let Some(x) = chars.next() {
 				let y = match chars.next() {
 					None => {
-						return Err(self.create_error());
+						return Err(self.create_error(CsaFormatErrorKind::BadSquare));
 					},
 					Some(y) => {
 						y
 					}
 				};
 
-				if x < '1' || x > '9' || y < '1' || y > '9' {
-					return Err(self.create_error());
-				}
+				let kind = reader.read(&mut chars, 2)?;
 
-				let x = x as usize - '0' as usize;
-				let y = y as usize - '0' as usize;
+				let hand = match teban {
+					Teban::Sente => &mut ms,
+					Teban::Gote => &mut mg,
+				};
 
-				let kind = reader.read(&mut chars, 2)?;
+				if x == '0' && y == '0' && kind == "AL" {
+					// 00ALは最後の行の最後にのみ書ける
+					if i < lines.len() - 1 || chars.next().is_some() {
+						return Err(self.create_error(CsaFormatErrorKind::MisplacedAll));
+					}
 
-				let k = initial_banmen[y-1][9-x];
+					for m in &MOCHIGOMA_KINDS {
+						let c = hand.get(*m) + rest.get(*m);
+						hand.insert(*m,c);
+						rest.insert(*m,0);
+					}
+				} else if x == '0' && y == '0' {
+					let m = MochigomaKind::try_from_csa(&kind)?;
 
-				match &*kind {
-					"FU" if k == SFu || k == GFu => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					"KY" if k == SKyou || k == GKyou => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					"KE" if k == SKei || k == GKei => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					"GI" if k == SGin || k == GGin => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					"KI" if k == SKin || k == GKin => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					"KA" if k == SKaku || k == GKaku => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					"HI" if k == SHisha || k == GHisha => {
-						initial_banmen[y-1][9-x] = Blank;
-					},
-					_ => {
-						return Err(self.create_error());
+					self.take_piece(&mut rest,&mut kings,KomaKind::from((teban,m)))?;
+
+					hand.put(m);
+				} else {
+					if !('1'..='9').contains(&x) || !('1'..='9').contains(&y) {
+						return Err(self.create_error(CsaFormatErrorKind::BadSquare));
 					}
-				}
-			}
 
-			Ok((Banmen(initial_banmen),MochigomaCollections::Empty))
-		} else if lines[0].starts_with("P1") {
-			let mut initial_banmen:[[KomaKind; 9]; 9] = [[KomaKind::Blank; 9]; 9];
-			let mut ms = Mochigoma::filled();
-			let mut mg = Mochigoma::filled();
+					let x = x as usize - '0' as usize;
+					let y = y as usize - '0' as usize;
 
-			let mut sou_count = 1;
-			let mut gou_count = 1;
+					if kinds[y-1][9-x] != Blank {
+						return Err(self.create_error(CsaFormatErrorKind::SquareOccupied));
+					}
 
-			for i in 0..9 {
-				let line_number = (i + '1' as u8) as char;
+					let k = KomaKind::try_from_csa((teban,&kind))?;
 
-				let mut chars = lines[i as usize].chars();
+					self.take_piece(&mut rest,&mut kings,k)?;
 
-				match chars.next() {
-					None => {
-						return Err(self.create_error());
-					},
-					Some(c) if c != 'P' => {
-						return Err(self.create_error());
-					},
-					_ => (),
+					kinds[y-1][9-x] = k;
 				}
+			}
+		}
 
-				match chars.next() {
-					None => {
-						return Err(self.create_error());
-					},
-					Some(c) if c != line_number => {
-						return Err(self.create_error());
-					},
-					_ => (),
+		Ok((Banmen(kinds),MochigomaCollections::new(ms,mg)))
+	}
+
+	fn take_piece(&self,rest:&mut Mochigoma,kings:&mut (u32,u32),k:KomaKind) -> Result<(),CsaParserError> {
+		match k {
+			SOu | GOu => {
+				let count = if k == SOu {
+					&mut kings.0
+				} else {
+					&mut kings.1
+				};
+
+				if *count > 0 {
+					return Err(self.create_error(CsaFormatErrorKind::DuplicateKing));
 				}
 
-				for j in 0..9 {
-					match chars.next() {
-						None => {
-							return Err(self.create_error());
-						},
-						Some(c) if c == '+' || c == '-' => {
-							let teban = match c {
-								'+' => Teban::Sente,
-								'-' => Teban::Gote,
-								_ => {
-									return Err(self.create_error());
-								}
-							};
-
-							let kind = reader.read(&mut chars, 2)?;
-
-							if kind == "OU" {
-								match teban {
-									Teban::Sente => {
-										if sou_count == 1 {
-											sou_count -= 1;
-											initial_banmen[i as usize][j as usize] = SOu;
-										} else {
-											return Err(self.create_error());
-										}
-									},
-									Teban::Gote => {
-										if gou_count == 1 {
-											gou_count -= 1;
-											initial_banmen[i as usize][j as usize] = GOu;
-										} else {
-											return Err(self.create_error());
-										}
-									}
-								}
-							} else {
-								let k = MochigomaKind::try_from_csa(&kind)?;
-
-								match teban {
-									Teban::Sente => {
-										let c = match ms.get(k) {
-											0 => {
-												return Err(self.create_error());
-											},
-											c => {
-												c - 1
-											}
-										};
-
-										ms.insert(k, c);
-									},
-									Teban::Gote => {
-										let c = match mg.get(k) {
-											0 => {
-												return Err(self.create_error());
-											},
-											c => {
-												c - 1
-											}
-										};
-
-										mg.insert(k, c);
-									}
-								}
-
-								initial_banmen[i as usize][j as usize] = KomaKind::try_from_csa((teban,&kind))?;
-							}
-						},
-						_ => {
-							chars.next();
-							chars.next();
-						}
+				*count += 1;
+			},
+			_ => {
+				// 成駒は成る前の駒として数える
+				let m = MochigomaKind::try_from(k).map_err(|_| self.create_error(CsaFormatErrorKind::UnknownPieceCode))?;
+
+				match rest.get(m) {
+					0 => {
+						return Err(self.create_error(CsaFormatErrorKind::PieceCountExceeded));
+					},
+					c => {
+						rest.insert(m,c - 1);
 					}
 				}
 			}
+		}
 
-			Ok((Banmen(initial_banmen),MochigomaCollections::Pair(ms,mg)))
-		} else if lines[0].starts_with("P+") || lines[0].starts_with("P-") {
-			let mut initial_banmen:[[KomaKind; 9]; 9] = [[KomaKind::Blank; 9]; 9];
-			let mut ms = Mochigoma::filled();
-			let mut mg = Mochigoma::filled();
+		Ok(())
+	}
 
-			let mut sou_count = 1;
-			let mut gou_count = 1;
+	fn parse_removed(&mut self,line:&str) -> Result<[[KomaKind; 9]; 9],CsaParserError> {
+		let mut reader = CsaStringReader::new();
 
-			for i in 0..lines.len() {
-				let line = &lines[i];
+		let initial_banmen = BANMEN_START_POS.clone();
+		let mut initial_banmen = initial_banmen.0;
+		let mut chars = line.chars();
+		chars.next();
+		chars.next();
 
-				let teban = if line.starts_with("P+") {
-					Teban::Sente
-				} else if line.starts_with("P-") {
-					Teban::Gote
-				} else {
-					return Err(self.create_error());
-				};
+		while let Some(c) = chars.next() {
+			let x = c;
 
-				let mut chars = line.chars();
+			let y = match chars.next() {
+				None => {
+					return Err(self.create_error(CsaFormatErrorKind::BadSquare));
+				},
+				Some(y) => {
+					y
+				}
+			};
 
-				chars.next();
-				chars.next();
+			if !('1'..='9').contains(&x) || !('1'..='9').contains(&y) {
+				return Err(self.create_error(CsaFormatErrorKind::BadSquare));
+			}
 
-				while let Some(c) = chars.next() {
-					let x = c;
+			let x = x as usize - '0' as usize;
+			let y = y as usize - '0' as usize;
 
-					let y = match chars.next() {
-						None => {
-							return Err(self.create_error());
-						},
-						Some(y) => {
-							y
-						}
-					};
+			let kind = reader.read(&mut chars, 2)?;
 
-					if x == '0' && y == '0' {
-						if i < lines.len() - 1 {
-							return Err(self.create_error());
-						}
+			let k = initial_banmen[y-1][9-x];
 
-						if chars.next() == Some('A') &&
-							chars.next() == Some('L') &&
-							chars.next() == None {
-
-							match teban {
-								Teban::Sente => {
-									for m in &MOCHIGOMA_KINDS {
-										let c = mg.get(*m);
-										mg.insert(*m, 0);
-										let c = ms.get(*m) + c;
-										ms.insert(*m,c);
-									}
-								},
-								Teban::Gote => {
-									for m in &MOCHIGOMA_KINDS {
-										let c = ms.get(*m);
-										ms.insert(*m, 0);
-										let c = mg.get(*m) + c;
-										mg.insert(*m,c);
-									}
-								}
-							}
-						} else {
-							return Err(self.create_error());
-						}
-					} else {
-						if x < '1' || x > '9' || y < '1' || y > '9' {
-							return Err(self.create_error());
-						}
+			match &*kind {
+				"FU" if k == SFu || k == GFu => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				"KY" if k == SKyou || k == GKyou => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				"KE" if k == SKei || k == GKei => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				"GI" if k == SGin || k == GGin => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				"KI" if k == SKin || k == GKin => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				"KA" if k == SKaku || k == GKaku => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				"HI" if k == SHisha || k == GHisha => {
+					initial_banmen[y-1][9-x] = Blank;
+				},
+				_ => {
+					return Err(self.create_error(CsaFormatErrorKind::NoPieceToRemove));
+				}
+			}
+		}
+
+		Ok(initial_banmen)
+	}
 
-						let x = x as usize - '0' as usize;
-						let y = y as usize - '0' as usize;
+	fn parse_board(&mut self,lines:&[String]) -> Result<[[KomaKind; 9]; 9],CsaParserError> {
+		let mut reader = CsaStringReader::new();
 
-						let kind = reader.read(&mut chars, 2)?;
+		let mut initial_banmen:[[KomaKind; 9]; 9] = [[KomaKind::Blank; 9]; 9];
 
-						let k = initial_banmen[y-1][9-x];
+		for i in 0..9 {
+			self.line = i as usize;
 
-						if k != Blank {
-							return Err(self.create_error());
-						}
+			let line_number = (i + b'1') as char;
 
-						if kind == "OU" {
-							match teban {
-								Teban::Sente => {
-									if sou_count == 1 {
-										sou_count -= 1;
-										initial_banmen[y-1][9-x] = SOu;
-									} else {
-										return Err(self.create_error());
-									}
-								},
-								Teban::Gote => {
-									if gou_count == 1 {
-										gou_count -= 1;
-										initial_banmen[y-1][9-x] = GOu;
-									} else {
-										return Err(self.create_error());
-									}
-								}
-							}
-						} else {
-							let k = MochigomaKind::try_from_csa(&kind)?;
-
-							match teban {
-								Teban::Sente => {
-									let c = match ms.get(k) {
-										0 => {
-											return Err(self.create_error());
-										},
-										c => {
-											c - 1
-										}
-									};
-
-									ms.insert(k, c);
-								},
-								Teban::Gote => {
-									let c = match mg.get(k) {
-										0 => {
-											return Err(self.create_error());
-										},
-										c => {
-											c - 1
-										}
-									};
-
-									mg.insert(k, c);
-								}
+			let mut chars = lines[i as usize].chars();
+
+			match chars.next() {
+				None => {
+					return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
+				},
+				Some(c) if c != 'P' => {
+					return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
+				},
+				_ => (),
+			}
+
+			match chars.next() {
+				None => {
+					return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
+				},
+				Some(c) if c != line_number => {
+					return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
+				},
+				_ => (),
+			}
+
+			for j in 0..9 {
+				match chars.next() {
+					None => {
+						return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
+					},
+					Some(c) if c == '+' || c == '-' => {
+						let teban = match c {
+							'+' => Teban::Sente,
+							'-' => Teban::Gote,
+							_ => {
+								return Err(self.create_error(CsaFormatErrorKind::BadBoardRow));
 							}
+						};
 
-							initial_banmen[y-1][9-x] = KomaKind::try_from_csa((teban,&kind))?;
-						}
+						let kind = reader.read(&mut chars, 2)?;
+
+						initial_banmen[i as usize][j as usize] = KomaKind::try_from_csa((teban,&kind))?;
+					},
+					_ => {
+						chars.next();
+						chars.next();
 					}
 				}
 			}
-
-			Ok((Banmen(initial_banmen),MochigomaCollections::Pair(ms,mg)))
-		} else {
-			Err(self.create_error())
 		}
+
+		Ok(initial_banmen)
 	}
 }
-struct CsaMovesParser {
-
+pub type CsaPlyState = (Banmen,MochigomaCollections,Teban);
+pub struct CsaMovesParser {
+	line:usize,
+	move_lines:Vec<usize>,
+	end_line:Option<usize>,
+	v3:bool,
+	strict:bool,
+	time_unit:Duration,
 }
 impl CsaMovesParser {
-	pub fn new() -> CsaMovesParser {
+	pub fn new(v3:bool,strict:bool) -> CsaMovesParser {
 		CsaMovesParser {
+			line:0,
+			move_lines:Vec::new(),
+			end_line:None,
+			v3,
+			strict,
+			time_unit:Duration::from_secs(1),
+		}
+	}
+
+	// 消費時間(T行)の値の単位
+	pub fn time_unit(mut self,time_unit:Duration) -> CsaMovesParser {
+		self.time_unit = time_unit;
+		self
+	}
+
+	fn create_error(&self,kind:CsaFormatErrorKind) -> CsaParserError {
+		CsaParserError::FormatError(kind,None)
+	}
 
+	fn owner(k:KomaKind) -> Option<Teban> {
+		if k == Blank {
+			None
+		} else if k < GFu {
+			Some(Teban::Sente)
+		} else {
+			Some(Teban::Gote)
 		}
 	}
 
-	fn create_error(&self) -> CsaParserError {
-		CsaParserError::FormatError(String::from(
-			"Invalid csa moves format."
-		))
+	fn parse_move(&self,line:&str,teban:Teban,banmen:&[[KomaKind; 9]; 9],check:bool)
+		-> Result<Move,CsaParserError> {
+
+		match teban {
+			Teban::Sente if !line.starts_with("+") => {
+				return Err(self.create_error(CsaFormatErrorKind::WrongSideToMove));
+			},
+			Teban::Gote if !line.starts_with("-") => {
+				return Err(self.create_error(CsaFormatErrorKind::WrongSideToMove));
+			},
+			_ => ()
+		}
+
+		let mut reader = CsaStringReader::new();
+
+		let mut chars = line.chars();
+		chars.next();
+
+		let mut digits = [0; 4];
+
+		for d in digits.iter_mut() {
+			*d = match chars.next() {
+				None => {
+					return Err(self.create_error(CsaFormatErrorKind::UnexpectedEndOfStatement));
+				},
+				Some(c) => {
+					match c.to_digit(10) {
+						Some(n) => n,
+						None => {
+							return Err(self.create_error(CsaFormatErrorKind::BadSquare));
+						}
+					}
+				}
+			};
+		}
+
+		let (sx,sy,dx,dy) = (digits[0],digits[1],digits[2],digits[3]);
+
+		let kind = reader.read(&mut chars, 2)?;
+
+		if sx == 0 && sy == 0 && (1..=9).contains(&dx) && (1..=9).contains(&dy) {
+			let k = MochigomaKind::try_from_csa(&kind)?;
+
+			let dx = dx as usize;
+			let dy = dy as usize;
+
+			if check && banmen[dy-1][9-dx] != Blank {
+				return Err(self.create_error(CsaFormatErrorKind::SquareOccupied));
+			}
+
+			Ok(Move::Put(k,KomaDstPutPosition(dx as u32,dy as u32)))
+		} else {
+			if !(1..=9).contains(&sx) || !(1..=9).contains(&sy) {
+				return Err(self.create_error(CsaFormatErrorKind::BadSquare));
+			}
+
+			if !(1..=9).contains(&dx) || !(1..=9).contains(&dy) {
+				return Err(self.create_error(CsaFormatErrorKind::BadSquare));
+			}
+
+			let k = KomaKind::try_from_csa((teban,&kind))?;
+
+			let sx = sx as usize;
+			let sy = sy as usize;
+			let dx = dx as usize;
+			let dy = dy as usize;
+
+			if check {
+				let src = banmen[sy-1][9-sx];
+
+				if CsaMovesParser::owner(src) != Some(teban) {
+					return Err(self.create_error(CsaFormatErrorKind::NoPieceToMove));
+				}
+
+				if src != k && src.to_nari() != k {
+					return Err(self.create_error(CsaFormatErrorKind::PieceMismatch));
+				}
+
+				if CsaMovesParser::owner(banmen[dy-1][9-dx]) == Some(teban) {
+					return Err(self.create_error(CsaFormatErrorKind::SquareOccupied));
+				}
+			}
+
+			let n = match k {
+				SFuN |
+					SKyouN |
+					SKeiN |
+					SGinN |
+					SKakuN |
+					SHishaN if banmen[sy-1][9-sx] != k => {
+
+					true
+				},
+				GFuN |
+					GKyouN |
+					GKeiN |
+					GGinN |
+					GKakuN |
+					GHishaN if banmen[sy-1][9-sx] != k => {
+
+					true
+				},
+				_ => false,
+			};
+
+			Ok(Move::To(
+				KomaSrcPosition(sx as u32,sy as u32),
+				KomaDstToPosition(dx as u32,dy as u32,n)
+			))
+		}
 	}
 
-	fn parse_elpsed(&self,lines:&Vec<String>,i:&mut usize,len:usize) -> Result<Option<i32>,CsaParserError> {
+	fn parse_elpsed(&mut self,lines:&[String],i:&mut usize,len:usize) -> Result<Option<Duration>,CsaParserError> {
 		*i += 1;
 
 		if *i < len && lines[*i].starts_with("T") {
+			self.line = *i;
+
 			let line = &lines[*i];
 
-			let s = String::from(&line.as_str()[1..]);
-			let s:i32 = s.parse()?;
+			let s = parse_duration(&line.as_str()[1..],self.time_unit)?;
 
 			*i += 1;
 
@@ -917,32 +1652,148 @@ impl CsaMovesParser {
 		}
 	}
 
-	pub fn parse(&mut self, lines:Vec<String>,banmen:&Banmen)
-		-> Result<(Teban,CsaMoves,Option<EndState>),CsaParserError> {
+	fn hand_mut(mc:&mut MochigomaCollections,teban:Teban) -> &mut Mochigoma {
+		if let MochigomaCollections::Empty = *mc {
+			*mc = MochigomaCollections::Pair(Mochigoma::new(),Mochigoma::new());
+		}
+
+		match (teban,mc) {
+			(Teban::Sente,&mut MochigomaCollections::Pair(ref mut ms,_)) => ms,
+			(Teban::Gote,&mut MochigomaCollections::Pair(_,ref mut mg)) => mg,
+			(_,&mut MochigomaCollections::Empty) => unreachable!(),
+		}
+	}
+
+	fn apply_move(banmen:&mut [[KomaKind; 9]; 9],mc:&mut MochigomaCollections,teban:Teban,m:&Move) {
+		match *m {
+			Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+				let (sx,sy,dx,dy) = (sx as usize,sy as usize,dx as usize,dy as usize);
+
+				let k = banmen[sy-1][9-sx];
+
+				if banmen[dy-1][9-dx] != Blank {
+					if let Ok(o) = MochigomaKind::try_from(banmen[dy-1][9-dx]) {
+						CsaMovesParser::hand_mut(mc,teban).put(o);
+					}
+				}
+
+				banmen[sy-1][9-sx] = Blank;
+				banmen[dy-1][9-dx] = if n { k.to_nari() } else { k };
+			},
+			Move::Put(k,KomaDstPutPosition(dx,dy)) => {
+				// 持ち駒に無い駒を打つ不正な棋譜は厳密モード以外ではそのまま読み進める
+				let _ = CsaMovesParser::hand_mut(mc,teban).pull(k);
+
+				banmen[dy as usize - 1][9 - dx as usize] = KomaKind::from((teban,k));
+			}
+		}
+	}
+
+	fn illegal_move(&self,ply:u32,reason:IllegalMoveReason) -> CsaParserError {
+		self.create_error(CsaFormatErrorKind::IllegalMove { ply, reason })
+	}
+
+	fn validate_move(&self,ply:u32,state:&State,mc:&MochigomaCollections,teban:Teban,m:Move)
+		-> Result<(),CsaParserError> {
+
+		moving_piece(&state.get_banmen().0,&m).map_err(|_| {
+			self.illegal_move(ply,IllegalMoveReason::NoPieceToMove)
+		})?;
+
+		let am = m.to_applied_move();
+
+		match m {
+			Move::Put(k,KomaDstPutPosition(x,_)) => {
+				let count = match (teban,mc) {
+					(_,&MochigomaCollections::Empty) => 0,
+					(Teban::Sente,MochigomaCollections::Pair(ms,_)) => ms.get(k),
+					(Teban::Gote,MochigomaCollections::Pair(_,mg)) => mg.get(k),
+				};
+
+				if count == 0 {
+					return Err(self.illegal_move(ply,IllegalMoveReason::NotInHand));
+				}
+
+				if !Rule::is_valid_move(state,teban,mc,am) {
+					return Err(self.illegal_move(ply,IllegalMoveReason::DeadPiece));
+				}
+
+				if k == MochigomaKind::Fu {
+					let fu = KomaKind::from((teban,MochigomaKind::Fu));
+
+					let Banmen(kinds) = state.get_banmen();
+
+					if (0..9).any(|r| kinds[r][9 - x as usize] == fu) {
+						return Err(self.illegal_move(ply,IllegalMoveReason::Nifu));
+					}
+				}
+
+				let (next,nmc,_) = Rule::apply_move_none_check(state,teban,mc,am);
+
+				if k == MochigomaKind::Fu && Rule::is_put_fu_and_mate(&next,teban,&nmc,am) {
+					return Err(self.illegal_move(ply,IllegalMoveReason::DropPawnMate));
+				}
+
+				if Rule::is_mate(teban.opposite(),&next) {
+					return Err(self.illegal_move(ply,IllegalMoveReason::LeftInCheck));
+				}
+
+				Ok(())
+			},
+			Move::To(s,KomaDstToPosition(x,y,n)) => {
+				if !Rule::is_valid_move(state,teban,mc,am) {
+					let alt = Move::To(s,KomaDstToPosition(x,y,!n)).to_applied_move();
+
+					let reason = if !Rule::is_valid_move(state,teban,mc,alt) {
+						IllegalMoveReason::CannotReach
+					} else if n {
+						IllegalMoveReason::CannotPromote
+					} else {
+						IllegalMoveReason::MustPromote
+					};
+
+					return Err(self.illegal_move(ply,reason));
+				}
+
+				let (next,_,_) = Rule::apply_move_none_check(state,teban,mc,am);
+
+				if Rule::is_mate(teban.opposite(),&next) {
+					return Err(self.illegal_move(ply,IllegalMoveReason::LeftInCheck));
+				}
+
+				Ok(())
+			}
+		}
+	}
+
+	pub fn parse(&mut self, lines:&[String],banmen:&Banmen,mc:&MochigomaCollections)
+		-> Result<(Teban,CsaMoves,Option<EndState>,Vec<CsaPlyState>),CsaParserError> {
 
-		if lines.len() == 0 {
+		if lines.is_empty() {
 			return Err(CsaParserError::InvalidStateError(String::from(
 				"lines is empty."
-			)));
+			),None));
 		}
 
 		let mut teban = match &*lines[0] {
 			"+" => Teban::Sente,
 			"-" => Teban::Gote,
 			_ => {
-				return Err(self.create_error());
+				return Err(self.create_error(CsaFormatErrorKind::UnexpectedStatement { stage:Stage::Moves }));
 			}
 		};
 
 		let teban_at_start = teban;
 
 		let mut banmen = match banmen {
-			Banmen(ref kinds) => kinds.clone()
+			Banmen(ref kinds) => *kinds
 		};
 
-		let mut mvs:CsaMoves = CsaMoves::new();
+		let mut mc = mc.clone();
 
-		let mut reader = CsaStringReader::new();
+		let mut positions = vec![(Banmen(banmen),mc.clone(),teban)];
+
+		let mut mvs:CsaMoves = CsaMoves::new();
 
 		let mut i = 1;
 		let len = lines.len();
@@ -950,155 +1801,178 @@ impl CsaMovesParser {
 		let mut end_state = None;
 
 		while i < len {
+			self.line = i;
+
+			let start = i;
 			let line = &lines[i];
 
 			if line == "%KACHI" && !moveend {
-				mvs.push(CsaMove::Kachi(self.parse_elpsed(&lines,&mut i,len)?))?;
+				mvs.push(CsaMove::Kachi(self.parse_elpsed(lines,&mut i,len)?))?;
+				self.move_lines.push(start);
+				positions.push((Banmen(banmen),mc.clone(),teban));
 				moveend = true;
 			} else if line == "%HIKIWAKE" && !moveend {
-				mvs.push(CsaMove::Hikiwake(self.parse_elpsed(&lines,&mut i,len)?))?;
+				mvs.push(CsaMove::Hikiwake(self.parse_elpsed(lines,&mut i,len)?))?;
+				self.move_lines.push(start);
+				positions.push((Banmen(banmen),mc.clone(),teban));
 				moveend = true;
 			} else if line.starts_with("+") || line.starts_with("-") {
-				match teban {
-					Teban::Sente if !line.starts_with("+") => {
-						return Err(self.create_error());
-					},
-					Teban::Gote if !line.starts_with("-") => {
-						return Err(self.create_error());
-					},
-					_ => ()
-				}
-
-				let mut chars = line.chars();
-				chars.next();
+				let m = if self.strict {
+					let ply = mvs.len() as u32 + 1;
 
-				let (sx,sy) = match chars.next() {
-					None => {
-						return Err(self.create_error());
-					},
-					Some(sx) => {
-						let sx = sx as u32 - '0' as u32;
-
-						let sy = match chars.next() {
-							None => {
-								return Err(self.create_error());
+					let m = self.parse_move(line,teban,&banmen,true).map_err(|e| {
+						match e {
+							CsaParserError::FormatError(CsaFormatErrorKind::NoPieceToMove,_) => {
+								self.illegal_move(ply,IllegalMoveReason::NoPieceToMove)
 							},
-							Some(sy) => {
-								sy as u32 - '0' as u32
-							}
-						};
-
-						(sx,sy)
-					}
-				};
-
-				let (dx,dy) = match chars.next() {
-					None => {
-						return Err(self.create_error());
-					},
-					Some(dx) => {
-						let dx = dx as u32 - '0' as u32;
-
-						let dy = match chars.next() {
-							None => {
-								return Err(self.create_error());
+							CsaParserError::FormatError(CsaFormatErrorKind::PieceMismatch,_) => {
+								self.illegal_move(ply,IllegalMoveReason::PieceMismatch)
 							},
-							Some(dy) => {
-								dy as u32 - '0' as u32
-							}
-						};
+							CsaParserError::FormatError(CsaFormatErrorKind::SquareOccupied,_) => {
+								self.illegal_move(ply,IllegalMoveReason::SquareOccupied)
+							},
+							e => e
+						}
+					})?;
 
-						(dx,dy)
-					}
+					self.validate_move(ply,&State::new(Banmen(banmen)),&mc,teban,m)?;
+
+					m
+				} else {
+					self.parse_move(line,teban,&banmen,false)?
 				};
 
-				let kind = reader.read(&mut chars, 2)?;
+				CsaMovesParser::apply_move(&mut banmen,&mut mc,teban,&m);
 
-				if sx == 0 && sy == 0 && dx >= 1 && dx <= 9 && dy >= 1 && dy <= 9 {
-					let k = MochigomaKind::try_from_csa(&kind)?;
+				mvs.push(CsaMove::Move(m,self.parse_elpsed(lines,&mut i,len)?))?;
+				self.move_lines.push(start);
+				positions.push((Banmen(banmen),mc.clone(),teban.opposite()));
 
-					mvs.push(CsaMove::Move(Move::Put(k,KomaDstPutPosition(dx,dy)),
-												self.parse_elpsed(&lines,&mut i,len)?))?;
+				teban = teban.opposite();
+			} else if line.starts_with("%") {
+				let s = EndState::try_from_csa(line)?;
 
-					let dx = dx as usize;
-					let dy = dy as usize;
-					banmen[dy-1][9-dx] = KomaKind::from((teban,k));
-				} else {
-					if sx < 1 || sx > 9 || sy < 1 || sy > 9 {
-						return Err(self.create_error());
-					}
+				if s.is_v3() && !self.v3 {
+					return Err(self.create_error(CsaFormatErrorKind::UnknownEndState));
+				}
 
-					if dx < 1 || dx > 9 || dy < 1 || dy > 9 {
-						return Err(self.create_error());
-					}
+				end_state = Some(s);
+				self.end_line = Some(start);
+				i += 1;
+			} else {
+				return Err(self.create_error(CsaFormatErrorKind::UnexpectedStatement { stage:Stage::Moves }));
+			}
+		}
+		Ok((teban_at_start,mvs,end_state,positions))
+	}
+}
+#[derive(Clone, Copy, Eq, PartialOrd, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CsaMove {
+	Move(#[cfg_attr(feature = "serde", serde(with = "serialize::usi_move"))] Move,Option<Duration>),
+	Kachi(Option<Duration>),
+	Hikiwake(Option<Duration>),
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum EngineScore {
+	Value(i32), // 評価値
+	Mate(i32), // 詰みまでの手数(負の値は詰まされる側)
+}
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EngineComment {
+	pub score:EngineScore,
+	#[cfg_attr(feature = "serde", serde(with = "serialize::usi_moves"))]
+	pub pv:Vec<Move>,
+}
+impl EngineComment {
+	pub fn parse(comment:&str,teban:Teban,banmen:&Banmen,mc:&MochigomaCollections) -> Result<EngineComment,CsaParserError> {
+		let create_error = || CsaParserError::FormatError(CsaFormatErrorKind::BadEngineComment,None);
 
-					let k = KomaKind::try_from_csa((teban,&kind))?;
+		let mut items = comment.split_whitespace();
 
-					let sx = sx as usize;
-					let sy = sy as usize;
-					let dx = dx as usize;
-					let dy = dy as usize;
+		match items.next() {
+			Some("*") | Some("**") => (),
+			_ => {
+				return Err(create_error());
+			}
+		}
 
-					let n = match k {
-						SFuN |
-							SKyouN |
-							SKeiN |
-							SGinN |
-							SKakuN |
-							SHishaN if banmen[sy-1][9-sx] != k => {
+		let score = match items.next() {
+			Some(s) if s.starts_with("#") || s.starts_with("+#") || s.starts_with("-#") => {
+				let n:i32 = s.trim_start_matches('+').replacen('#',"",1).parse()?;
+				EngineScore::Mate(n)
+			},
+			Some(s) => {
+				EngineScore::Value(s.trim_start_matches('+').parse()?)
+			},
+			None => {
+				return Err(create_error());
+			}
+		};
 
-							true
-						},
-						GFuN |
-							GKyouN |
-							GKeiN |
-							GGinN |
-							GKakuN |
-							GHishaN if banmen[sy-1][9-sx] != k => {
-
-							true
-						},
-						_ => false,
-					};
+		let parser = CsaMovesParser::new(true,false);
+
+		let Banmen(mut kinds) = *banmen;
+		let mut mc = mc.clone();
+		let mut teban = teban;
+		let mut pv = Vec::new();
 
-					mvs.push(CsaMove::Move(Move::To(
-						KomaSrcPosition(sx as u32,sy as u32),
-						KomaDstToPosition(dx as u32,dy as u32,n)
-					),self.parse_elpsed(&lines,&mut i,len)?))?;
+		for m in items {
+			let m = parser.parse_move(m,teban,&kinds,true)?;
 
-					banmen[sy-1][9-sx] = Blank;
-					banmen[dy-1][9-dx] = k;
+			// 持ち駒に無い駒を打つ読み筋は受け付けない
+			if let Move::Put(k,_) = m {
+				if CsaMovesParser::hand_mut(&mut mc,teban).get(k) == 0 {
+					return Err(create_error());
 				}
-			} else if line.starts_with("%") {
-				end_state = Some(EndState::try_from_csa(line)?);
-				i += 1;
-			} else {
-				return Err(self.create_error());
 			}
 
+			CsaMovesParser::apply_move(&mut kinds,&mut mc,teban,&m);
+
+			pv.push(m);
 			teban = teban.opposite();
 		}
-		Ok((teban_at_start,mvs,end_state))
+
+		Ok(EngineComment {
+			score,
+			pv,
+		})
 	}
 }
-#[derive(Clone, Copy, Eq, PartialOrd, PartialEq, Debug)]
-pub enum CsaMove {
-	Move(Move,Option<i32>),
-	Kachi(Option<i32>),
-	Hikiwake(Option<i32>),
-}
-#[derive(Debug)]
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub struct CsaMoves {
 	moves:Vec<CsaMove>,
+	comments:Vec<Vec<String>>,
+	engine_comments:Vec<Option<EngineComment>>,
 }
 impl CsaMoves {
 	pub fn new() -> CsaMoves {
 		CsaMoves {
-			moves:Vec::new()
+			moves:Vec::new(),
+			comments:Vec::new(),
+			engine_comments:Vec::new(),
 		}
 	}
 
-	pub fn iter(&self) -> Iter<CsaMove> {
+	pub fn engine_comment(&self,i:usize) -> Option<&EngineComment> {
+		self.engine_comments[i].as_ref()
+	}
+
+	pub fn set_engine_comment(&mut self,i:usize,comment:EngineComment) {
+		self.engine_comments[i] = Some(comment);
+	}
+
+	pub fn comments(&self,i:usize) -> &[String] {
+		&self.comments[i]
+	}
+
+	pub fn add_comment(&mut self,i:usize,comment:String) {
+		self.comments[i].push(comment);
+	}
+
+	pub fn iter(&self) -> Iter<'_,CsaMove> {
 		self.moves.iter()
 	}
 
@@ -1106,11 +1980,17 @@ impl CsaMoves {
 		self.moves.len()
 	}
 
+	pub fn is_empty(&self) -> bool {
+		self.moves.is_empty()
+	}
+
 	pub fn push(&mut self,m:CsaMove) -> Result<usize,CsaStateError> {
 		let len = self.len();
 
 		if len == 0 {
 			self.moves.push(m);
+			self.comments.push(Vec::new());
+			self.engine_comments.push(None);
 		} else {
 			match self.moves[len-1] {
 				CsaMove::Kachi(_) | CsaMove::Hikiwake(_) => {
@@ -1129,11 +2009,18 @@ impl CsaMoves {
 			}
 
 			self.moves.push(m);
+			self.comments.push(Vec::new());
+			self.engine_comments.push(None);
 		}
 
 		Ok(len)
 	}
 }
+impl Default for CsaMoves {
+	fn default() -> CsaMoves {
+		CsaMoves::new()
+	}
+}
 impl<'a> IntoIterator for &'a CsaMoves {
 	type Item = &'a CsaMove;
 	type IntoIter = Iter<'a,CsaMove>;
@@ -1150,6 +2037,7 @@ impl Index<usize> for CsaMoves {
 	}
 }
 #[derive(Clone, Copy, Eq, PartialOrd, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum EndState {
 	Toryo = 0, // 投了
 	Chudan, // 中断
@@ -1165,4 +2053,543 @@ pub enum EndState {
 	Tsumi, // 詰み
 	Fuzumi, // 不詰
 	Error, // エラー
+	MaxMoves, // 最大手数に達したことによる終局
+	SFoul, // 先手(下手)の反則により、後手(上手)の勝ち
+	GFoul, // 後手(上手)の反則により、先手(下手)の勝ち
+}
+impl EndState {
+	fn is_v3(&self) -> bool {
+		matches!(*self, EndState::MaxMoves | EndState::SFoul | EndState::GFoul)
+	}
+}
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GameResult {
+	#[cfg_attr(feature = "serde", serde(with = "serialize::option_teban"))]
+	pub winner:Option<Teban>, // 引き分けや中断等で勝者が決まらない場合はNone
+	pub reason:EndState,
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn read_all<S>(mut st:S) -> Vec<String> where S: CsaStream {
+		let mut lines = Vec::new();
+
+		while let Some(l) = st.next().unwrap() {
+			lines.push(l);
+		}
+
+		lines
+	}
+
+	#[test]
+	fn string_stream_splits_statements_and_skips_blank_lines() {
+		let st = CsaStringStream::new("V2.2\n\nPI\n+\n+7776FU,T1,-3334FU,T2\n'comment,with comma\n");
+
+		assert_eq!(read_all(st),vec!["V2.2","PI","+","+7776FU","T1","-3334FU","T2","'comment,with comma"]);
+	}
+
+	#[test]
+	fn bytes_stream_parses_a_game() {
+		let st = CsaBytesStream::new(b"V2.2\r\nPI\r\n+\r\n+7776FU\r\n%TORYO\r\n".to_vec());
+
+		let data = CsaParser::new(st).parse().unwrap().remove(0);
+
+		assert_eq!(data.moves.len(),1);
+		assert_eq!(data.end_state,Some(EndState::Toryo));
+	}
+
+	#[test]
+	fn reader_stream_reads_any_buf_read() {
+		let input:&[u8] = b"V2.2\nPI\n-\n-3334FU\n";
+
+		let data = CsaParser::new(CsaReaderStream::new(input)).parse().unwrap().remove(0);
+
+		assert_eq!(data.teban_at_start,Teban::Gote);
+		assert_eq!(data.moves.len(),1);
+
+		let st = CsaReaderStream::new(BufReader::new(Cursor::new(b"PI\n+".to_vec())));
+
+		assert_eq!(read_all(st),vec!["PI","+"]);
+	}
+
+	#[test]
+	fn errors_carry_the_source_location() {
+		let e = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU,T1,-3334ZZ\n").unwrap_err();
+
+		assert_eq!(e.location(),Some(SourceLocation::new(4,3,12)));
+		assert_eq!(e.statement().map(|s| s.text.as_str()),Some("-3334ZZ"));
+
+		let e = CsaParser::parse_str("V2.2\nPI\n+\n\n+7776FU\n+3334FU\n").unwrap_err();
+
+		assert_eq!(e.location().map(|l| (l.line,l.statement,l.column)),Some((6,1,1)));
+	}
+
+	fn error_kind(s:&str) -> Option<CsaFormatErrorKind> {
+		match CsaParser::parse_str(s) {
+			Err(CsaParserError::FormatError(k,_)) => Some(k),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn format_errors_have_structured_kinds() {
+		assert_eq!(error_kind("V2.2\nPI\n+\n+7776ZZ\n"),Some(CsaFormatErrorKind::UnknownPieceCode));
+		assert_eq!(error_kind("V2.2\nPI\n+\n+7076FU\n"),Some(CsaFormatErrorKind::BadSquare));
+		assert_eq!(error_kind("V2.2\nPI\n+\n%FOO\n"),Some(CsaFormatErrorKind::UnknownEndState));
+		assert_eq!(error_kind("V2.2\n$FOO:x\nPI\n+\n"),Some(CsaFormatErrorKind::UnknownInfoKey));
+		assert_eq!(error_kind("V2.2\nN+a\n"),Some(CsaFormatErrorKind::UnexpectedEndOfInput { stage:Stage::Info }));
+		assert_eq!(error_kind("V2.2\nPI\n+\n+7776FU\n-3334\n"),Some(CsaFormatErrorKind::UnexpectedEndOfStatement));
+		assert_eq!(error_kind("V2.2\nP1-KY-KE\n+\n"),Some(CsaFormatErrorKind::BadBoardRow));
+		assert_eq!(error_kind("V2.2\nPI\n+\n%TORYO\nPI\n"),Some(CsaFormatErrorKind::UnexpectedStatement { stage:Stage::Moves }));
+	}
+
+	#[test]
+	fn writers_reject_moves_without_a_piece() {
+		let Banmen(ref kinds) = BANMEN_START_POS;
+
+		let blank = Move::To(KomaSrcPosition(5,5),KomaDstToPosition(5,4,false));
+		let outside = Move::To(KomaSrcPosition(0,7),KomaDstToPosition(7,6,false));
+
+		assert_eq!(moving_piece(kinds,&Move::To(KomaSrcPosition(7,7),KomaDstToPosition(7,6,false))).ok(),Some(Some(SFu)));
+		assert_eq!(moving_piece(kinds,&Move::Put(MochigomaKind::Fu,KomaDstPutPosition(5,5))).ok(),Some(None));
+		assert!(moving_piece(kinds,&Move::Put(MochigomaKind::Fu,KomaDstPutPosition(5,10))).is_err());
+
+		for m in &[blank,outside] {
+			assert!(moving_piece(kinds,m).is_err());
+
+			let mut data = CsaParser::parse_str("V2.2\nPI\n+\n").unwrap().remove(0);
+
+			data.moves.push(CsaMove::Move(*m,None)).unwrap();
+
+			assert!(data.to_csa().is_err());
+		}
+	}
+
+	#[test]
+	fn games_iterates_lazily_and_recovers_after_errors() {
+		let input = "V2.2\nPI\n+\n+7776FU\n%TORYO\n/\nV2.2\nPI\n+\n+7776ZZ\n/\nV2.2\nPI\n-\n-3334FU\n";
+
+		let mut parser = CsaParser::new(CsaStringStream::new(input));
+		let games = parser.games().collect::<Vec<_>>();
+
+		assert_eq!(games.len(),3);
+		assert_eq!(games[0].as_ref().ok().and_then(|d| d.end_state),Some(EndState::Toryo));
+		assert!(games[1].is_err());
+		assert_eq!(games[2].as_ref().ok().map(|d| d.teban_at_start),Some(Teban::Gote));
+
+		let mut parser = CsaParser::new(CsaStringStream::new(input));
+
+		assert!(parser.games().next().map(|r| r.is_ok()).unwrap_or(false));
+		assert!(CsaParser::parse_str(input).is_err());
+	}
+
+	#[test]
+	fn missing_version_is_treated_as_v2() {
+		let mut info = KifuInfo::new();
+
+		assert!(info.parse_with_version("$MAX_MOVES:256",None).is_err());
+		assert!(info.parse_with_version("$MAX_MOVES:256",Some("2.2")).is_err());
+		assert!(info.parse_with_version("$EVENT:test",None).is_ok());
+
+		info.parse_with_version("$MAX_MOVES:256",Some("3.0")).unwrap();
+
+		assert_eq!(info.max_moves,Some(256));
+	}
+
+	#[test]
+	fn parse_without_version_rejects_v3_keys() {
+		let mut info = KifuInfo::new();
+
+		assert!(info.parse("$JISHOGI:27").is_err());
+
+		info.parse("$EVENT:test").unwrap();
+
+		assert_eq!(info.event,Some(String::from("test")));
+	}
+
+	#[test]
+	fn v3_end_states_require_v3() {
+		assert!(CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n%MAX_MOVES\n").is_err());
+
+		let data = CsaParser::parse_str("V3.0\nPI\n+\n+7776FU\n%MAX_MOVES\n").unwrap();
+
+		assert_eq!(data[0].end_state,Some(EndState::MaxMoves));
+	}
+
+	#[test]
+	fn v3_info_keys() {
+		let data = CsaParser::parse_str(concat!(
+			"V3.0\n",
+			"$TIME:Time_Unit:1msec\n",
+			"$TIME:Total_Time:600000\n",
+			"$TIME:Byoyomi:10000\n",
+			"$TIME:Time_Roundup:YES\n",
+			"$MAX_MOVES:256\n",
+			"$JISHOGI:24\n",
+			"$NOTE:test game\n",
+			"PI\n+\n"
+		)).unwrap().remove(0);
+
+		let info = data.kifu_info.unwrap();
+		let time = info.time.unwrap();
+
+		assert_eq!(time.time_unit,Duration::from_millis(1));
+		assert_eq!(time.total_time,Duration::from_secs(600));
+		assert_eq!(time.byoyomi,Duration::from_secs(10));
+		assert!(time.time_roundup);
+		assert_eq!(info.max_moves,Some(256));
+		assert_eq!(info.jishogi,Some(24));
+		assert_eq!(info.note,Some(String::from("test game")));
+
+		let mut time = TimeSettings::default();
+
+		time.parse("300+30+5").unwrap();
+
+		assert_eq!((time.total_time,time.byoyomi,time.increment),
+					(Duration::from_secs(300),Duration::from_secs(30),Duration::from_secs(5)));
+		assert!(time.parse("300+30").is_err());
+		assert!(time.parse("Time_Unit:1hour").is_err());
+	}
+
+	fn elapsed_times(s:&str) -> Result<Vec<Option<Duration>>,CsaParserError> {
+		CsaParser::parse_str(s).map(|mut d| d.remove(0).moves.iter().map(|m| match *m {
+			CsaMove::Move(_,t) | CsaMove::Kachi(t) | CsaMove::Hikiwake(t) => t,
+		}).collect())
+	}
+
+	#[test]
+	fn fractional_elapsed_times() {
+		assert_eq!(elapsed_times("V3.0\nPI\n+\n+7776FU\nT1.25\n-3334FU\nT0.001\n+2726FU\nT12\n-8384FU\n").unwrap(),
+					vec![Some(Duration::from_millis(1250)),Some(Duration::from_millis(1)),Some(Duration::from_secs(12)),None]);
+		assert!(elapsed_times("V3.0\nPI\n+\n+7776FU\nT1.x\n").is_err());
+		assert!(elapsed_times("V3.0\nPI\n+\n+7776FU\nT1.\n").is_err());
+		assert!(elapsed_times("V3.0\nPI\n+\n+7776FU\nT-1\n").is_err());
+	}
+
+	#[test]
+	fn comments_are_attached_to_where_they_appear() {
+		let data = CsaParser::parse_str(concat!(
+			"'header\nV2.2\n'info\nN+a\nPI\n+\n'position\n",
+			"+7776FU\n'first\nT3\n'first again\n-3334FU\n'second\n%TORYO\n'end\n"
+		)).unwrap().remove(0);
+
+		assert_eq!(data.comments(),vec!["header","info","position","first","first again","second","end"]);
+		assert_eq!(data.header_comments,vec!["header","info"]);
+		assert_eq!(data.position_comments,vec!["position"]);
+		assert_eq!(data.moves.comments(0),&["first","first again"]);
+		assert_eq!(data.moves.comments(1),&["second"]);
+		assert_eq!(data.end_state_comments,vec!["end"]);
+	}
+
+	#[test]
+	fn engine_comments_are_parsed_for_each_move() {
+		let data = CsaParser::parse_str(concat!(
+			"V2.2\nPI\n+\n",
+			"+7776FU\n'** 30 -3334FU +2726FU\n",
+			"-3334FU\n'** -#3\n",
+			"+2726FU\n'** abc\n"
+		)).unwrap().remove(0);
+
+		assert_eq!(data.moves.engine_comment(0),Some(&EngineComment {
+			score:EngineScore::Value(30),
+			pv:vec![
+				Move::To(KomaSrcPosition(3,3),KomaDstToPosition(3,4,false)),
+				Move::To(KomaSrcPosition(2,7),KomaDstToPosition(2,6,false))
+			],
+		}));
+		assert_eq!(data.moves.engine_comment(1),Some(&EngineComment {
+			score:EngineScore::Mate(-3),
+			pv:Vec::new(),
+		}));
+		assert_eq!(data.moves.engine_comment(2),None);
+		assert_eq!(data.moves.comments(2),&["** abc"]);
+
+		let banmen = BANMEN_START_POS.clone();
+
+		assert!(EngineComment::parse("** 10 +7776FU",Teban::Gote,&banmen,&MochigomaCollections::Empty).is_err());
+		assert!(EngineComment::parse("10 +7776FU",Teban::Sente,&banmen,&MochigomaCollections::Empty).is_err());
+	}
+
+	fn strict_error(s:&str) -> Option<CsaFormatErrorKind> {
+		match CsaParser::new(CsaStringStream::new(s)).strict(true).parse() {
+			Err(CsaParserError::FormatError(k,_)) => Some(k),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn strict_mode_rejects_illegal_moves() {
+		let illegal = |ply,reason| Some(CsaFormatErrorKind::IllegalMove { ply, reason });
+		let hirate = |s:&str| format!("V2.2\nPI\n+\n{}",s);
+
+		assert_eq!(strict_error(&hirate("+7776FU\n-3334FU\n+8822UM\n")),None);
+		assert_eq!(strict_error(&hirate("+7775FU\n")),illegal(1,IllegalMoveReason::CannotReach));
+		assert_eq!(strict_error(&hirate("+0055FU\n")),illegal(1,IllegalMoveReason::NotInHand));
+		assert_eq!(strict_error(&hirate("+5655FU\n")),illegal(1,IllegalMoveReason::NoPieceToMove));
+		assert_eq!(strict_error(&hirate("+7776TO\n")),illegal(1,IllegalMoveReason::CannotPromote));
+		assert_eq!(strict_error(&hirate("+5958OU\n-5152OU\n+5857OU\n")),illegal(3,IllegalMoveReason::SquareOccupied));
+		assert_eq!(strict_error(&hirate("+2726FU\n-8384FU\n+2625FU\n-8485FU\n+2524FU\n-2324FU\n+2824HI\n-0023FU\n+0076FU\n")),
+					illegal(9,IllegalMoveReason::Nifu));
+
+		let board = concat!(
+			"V2.2\n",
+			"P1 *  *  *  * -OU *  *  *  * \n",
+			"P2 *  *  *  *  *  * +FU *  * \n",
+			"P3 *  *  *  *  *  *  *  *  * \n",
+			"P4 *  *  *  *  *  *  *  *  * \n",
+			"P5 *  *  *  * -HI *  *  *  * \n",
+			"P6 *  *  *  *  *  *  *  *  * \n",
+			"P7 *  *  *  *  *  *  *  *  * \n",
+			"P8 *  *  *  * +KI *  *  *  * \n",
+			"P9 *  *  *  * +OU *  *  *  * \n",
+			"+\n"
+		);
+
+		assert_eq!(strict_error(&format!("{}+5848KI\n",board)),illegal(1,IllegalMoveReason::LeftInCheck));
+		assert_eq!(strict_error(&format!("{}+3231FU\n",board)),illegal(1,IllegalMoveReason::MustPromote));
+		assert_eq!(strict_error(&format!("{}+3231TO\n",board)),None);
+
+		assert!(CsaParser::parse_str(&hirate("+7775FU\n")).is_ok());
+	}
+
+	fn hand_count(mc:&MochigomaCollections,teban:Teban,k:MochigomaKind) -> u32 {
+		match (teban,mc) {
+			(_,&MochigomaCollections::Empty) => 0,
+			(Teban::Sente,MochigomaCollections::Pair(ms,_)) => ms.get(k) as u32,
+			(Teban::Gote,MochigomaCollections::Pair(_,mg)) => mg.get(k) as u32,
+		}
+	}
+
+	#[test]
+	fn replaying_moves_tracks_captures_and_hands() {
+		let lines = ["+","+7776FU","-3334FU","+8822UM","-3122GI","+0055KA","%TORYO"].iter().map(|s| s.to_string()).collect::<Vec<String>>();
+
+		let (teban,mvs,end_state,positions) = CsaMovesParser::new(false,false).parse(&lines,&BANMEN_START_POS,&MochigomaCollections::Empty).unwrap();
+
+		assert_eq!(teban,Teban::Sente);
+		assert_eq!(mvs.len(),5);
+		assert_eq!(end_state,Some(EndState::Toryo));
+		assert_eq!(positions.len(),6);
+
+		let (Banmen(ref kinds),ref mc,t) = positions[3];
+
+		assert_eq!(t,Teban::Gote);
+		assert_eq!(kinds[1][7],SKakuN);
+		assert_eq!(kinds[7][1],Blank);
+		assert_eq!(hand_count(mc,Teban::Sente,MochigomaKind::Kaku),1);
+
+		let (Banmen(ref kinds),ref mc,_) = positions[4];
+
+		assert_eq!(kinds[1][7],GGin);
+		assert_eq!(hand_count(mc,Teban::Gote,MochigomaKind::Kaku),1);
+
+		let (Banmen(ref kinds),ref mc,t) = positions[5];
+
+		assert_eq!(t,Teban::Gote);
+		assert_eq!(kinds[4][4],SKaku);
+		assert_eq!(hand_count(mc,Teban::Sente,MochigomaKind::Kaku),0);
+		assert_eq!(hand_count(mc,Teban::Gote,MochigomaKind::Kaku),1);
+	}
+
+	#[test]
+	fn engine_comments_drop_only_pieces_in_hand() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n-3334FU\n+8822UM\n'** 0 -3122GI +0055KA\n").unwrap().remove(0);
+
+		assert_eq!(data.moves.engine_comment(2).map(|e| e.pv.len()),Some(2));
+
+		let lines = ["+","+7776FU","-3334FU","+8822UM"].iter().map(|s| s.to_string()).collect::<Vec<String>>();
+
+		let (_,_,_,positions) = CsaMovesParser::new(false,false).parse(&lines,&BANMEN_START_POS,&MochigomaCollections::Empty).unwrap();
+
+		let (ref banmen,ref mc,_) = positions[3];
+
+		// 後手は角を持っていない
+		assert!(EngineComment::parse("** 0 -0055KA",Teban::Gote,banmen,mc).is_err());
+		assert!(EngineComment::parse("** 0 -3122GI +0055KA -0044KA",Teban::Gote,banmen,mc).is_ok());
+	}
+
+	#[test]
+	fn positions_follow_the_moves() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n-3334FU\n+8822UM\n").unwrap().remove(0);
+
+		let positions = data.positions().collect::<Vec<CsaPosition>>();
+
+		assert_eq!(positions.len(),4);
+		assert_eq!(positions.iter().map(|p| p.ply).collect::<Vec<u32>>(),vec![0,1,2,3]);
+		assert_eq!(positions.iter().map(|p| p.teban).collect::<Vec<Teban>>(),vec![Teban::Sente,Teban::Gote,Teban::Sente,Teban::Gote]);
+		assert_eq!(positions[0].banmen,data.initial_position);
+		assert_eq!(positions[0].mochigoma,data.initial_mochigoma);
+
+		let Banmen(ref kinds) = positions[3].banmen;
+
+		assert_eq!(kinds[1][7],SKakuN);
+		assert_eq!(hand_count(&positions[3].mochigoma,Teban::Sente,MochigomaKind::Kaku),1);
+
+		assert_eq!(data.position_at(2),Some(positions[2].clone()));
+		assert_eq!(data.position_at(4),None);
+	}
+
+	#[test]
+	fn special_moves_do_not_pass_the_turn() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n%KACHI\n").unwrap().remove(0);
+
+		let last = data.positions().last().unwrap();
+
+		assert_eq!(last.ply,2);
+		assert_eq!(last.teban,Teban::Gote);
+	}
+
+	#[test]
+	fn usi_position_commands() {
+		let usi = |s:&str| CsaParser::parse_str(s).unwrap().remove(0).to_usi_position().unwrap();
+
+		assert_eq!(usi("V2.2\nPI\n+\n"),"position startpos");
+		assert_eq!(usi("V2.2\nPI\n+\n+7776FU\n-3334FU\n+8822UM\n%TORYO\n"),"position startpos moves 7g7f 3c3d 8h2b+");
+		assert_eq!(usi("V2.2\nPI\n+\n+7776FU\n%KACHI\n"),"position startpos moves 7g7f");
+		assert_eq!(usi("V2.2\nPI82HI\n-\n-3334FU\n"),
+					"position sfen lnsgkgsnl/7b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1 moves 3c3d");
+	}
+
+	#[test]
+	fn comment_router_assigns_each_comment_once() {
+		let mut mvs = CsaMoves::new();
+		let mut router = CommentRouter::new();
+
+		router.push(String::from("header"),true,&mut mvs,false);
+		router.push(String::from("position"),false,&mut mvs,false);
+
+		mvs.push(CsaMove::Move(Move::To(KomaSrcPosition(7,7),KomaDstToPosition(7,6,false)),None)).unwrap();
+
+		router.push(String::from("move"),false,&mut mvs,false);
+		router.push(String::from("end"),false,&mut mvs,true);
+
+		let mut data = CsaData::new(None,None,Teban::Sente,BANMEN_START_POS.clone(),MochigomaCollections::Empty,mvs,None,Vec::new());
+
+		router.assign(&mut data);
+
+		assert_eq!(data.comments(),vec!["header","position","move","end"]);
+		assert_eq!(data.header_comments,vec!["header"]);
+		assert_eq!(data.position_comments,vec!["position"]);
+		assert_eq!(data.moves.comments(0),&["move"]);
+		assert_eq!(data.end_state_comments,vec!["end"]);
+	}
+
+	const KINGS_ONLY:&str = concat!(
+		"P1 *  *  *  * -OU *  *  *  * \n",
+		"P2 *  *  *  *  *  *  *  *  * \n",
+		"P3 *  *  *  *  *  *  *  *  * \n",
+		"P4 *  *  *  *  *  *  *  *  * \n",
+		"P5 *  *  *  *  *  *  *  *  * \n",
+		"P6 *  *  *  *  *  *  *  *  * \n",
+		"P7 *  *  *  *  *  *  *  *  * \n",
+		"P8 *  *  *  *  *  *  *  *  * \n",
+		"P9 *  *  *  * +OU *  *  *  * \n"
+	);
+
+	#[test]
+	fn hand_lines_after_a_board() {
+		let data = CsaParser::parse_str(&format!("V2.2\n{}P+00KI00FU\nP-00AL\n+\n",KINGS_ONLY)).unwrap().remove(0);
+
+		let mc = &data.initial_mochigoma;
+
+		assert_eq!(hand_count(mc,Teban::Sente,MochigomaKind::Kin),1);
+		assert_eq!(hand_count(mc,Teban::Sente,MochigomaKind::Fu),1);
+		assert_eq!(hand_count(mc,Teban::Gote,MochigomaKind::Fu),17);
+		assert_eq!(hand_count(mc,Teban::Gote,MochigomaKind::Kin),3);
+		assert_eq!(hand_count(mc,Teban::Gote,MochigomaKind::Hisha),2);
+
+		let data = CsaParser::parse_str(&format!("V2.2\n{}P+55KI\n+\n",KINGS_ONLY)).unwrap().remove(0);
+		let Banmen(ref kinds) = data.initial_position;
+
+		assert_eq!(kinds[4][4],SKin);
+		assert_eq!(data.initial_mochigoma,MochigomaCollections::Empty);
+	}
+
+	#[test]
+	fn all_remaining_pieces_after_pi() {
+		let data = CsaParser::parse_str("V2.2\nPI82HI22KA\nP+00AL\n+\n").unwrap().remove(0);
+
+		assert_eq!(hand_count(&data.initial_mochigoma,Teban::Sente,MochigomaKind::Hisha),1);
+		assert_eq!(hand_count(&data.initial_mochigoma,Teban::Sente,MochigomaKind::Kaku),1);
+		assert_eq!(hand_count(&data.initial_mochigoma,Teban::Gote,MochigomaKind::Hisha),0);
+	}
+
+	#[test]
+	fn reject_invalid_hand_lines() {
+		assert_eq!(error_kind(&format!("V2.2\n{}P+00AL\nP-00FU\n+\n",KINGS_ONLY)),Some(CsaFormatErrorKind::MisplacedAll));
+		assert_eq!(error_kind(&format!("V2.2\n{}P+00KI00KI00KI00KI00KI\n+\n",KINGS_ONLY)),Some(CsaFormatErrorKind::PieceCountExceeded));
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn game_result_serde_round_trip() {
+		for r in &[GameResult { winner:Some(Teban::Gote), reason:EndState::Toryo },
+					GameResult { winner:None, reason:EndState::Sennichite }] {
+			let s = serde_json::to_string(r).unwrap();
+
+			assert_eq!(serde_json::from_str::<GameResult>(&s).unwrap(),*r);
+		}
+	}
+
+	fn result_of(s:&str) -> Option<GameResult> {
+		CsaParser::parse_str(s).unwrap().remove(0).result()
+	}
+
+	#[test]
+	fn result_for_each_end_state() {
+		let cases = [
+			("%TORYO",EndState::Toryo,Some(Teban::Sente)),
+			("%CHUDAN",EndState::Chudan,None),
+			("%SENNICHITE",EndState::Sennichite,None),
+			("%TIME_UP",EndState::TimeUp,Some(Teban::Sente)),
+			("%ILLEGAL_MOVE",EndState::IllegalMove,Some(Teban::Sente)),
+			("%+ILLEGAL_ACTION",EndState::SIllegalAction,Some(Teban::Gote)),
+			("%-ILLEGAL_ACTION",EndState::GIllegalAction,Some(Teban::Sente)),
+			("%JISHOGI",EndState::Jishogi,None),
+			("%MATTA",EndState::Matta,None),
+			("%TSUMI",EndState::Tsumi,Some(Teban::Sente)),
+			("%FUZUMI",EndState::Fuzumi,None),
+			("%ERROR",EndState::Error,None),
+			("%MAX_MOVES",EndState::MaxMoves,None),
+			("%+FOUL",EndState::SFoul,Some(Teban::Gote)),
+			("%-FOUL",EndState::GFoul,Some(Teban::Sente)),
+		];
+
+		for &(s,reason,winner) in cases.iter() {
+			assert_eq!(result_of(&format!("V3.0\nPI\n+\n+7776FU\n{}\n",s)),Some(GameResult {
+				winner,
+				reason,
+			}),"{}",s);
+		}
+	}
+
+	#[test]
+	fn result_for_declarations() {
+		assert_eq!(result_of("V2.2\nPI\n+\n+7776FU\n%KACHI\n"),Some(GameResult {
+			winner:Some(Teban::Gote),
+			reason:EndState::Kachi,
+		}));
+		assert_eq!(result_of("V2.2\nPI\n+\n+7776FU\n%HIKIWAKE\n"),Some(GameResult {
+			winner:None,
+			reason:EndState::Hikiwake,
+		}));
+
+		let mut data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n").unwrap().remove(0);
+
+		assert_eq!(data.result(),None);
+
+		data.end_state = Some(EndState::Kachi);
+
+		assert_eq!(data.result().and_then(|r| r.winner),Some(Teban::Gote));
+	}
+
+	#[test]
+	fn result_for_handicap_games() {
+		assert_eq!(result_of("V2.2\nPI82HI\n-\n%TORYO\n").and_then(|r| r.winner),Some(Teban::Sente));
+		assert_eq!(result_of("V2.2\nPI82HI\n-\n-3334FU\n%TORYO\n").and_then(|r| r.winner),Some(Teban::Gote));
+		assert_eq!(result_of("V2.2\nPI82HI\n-\n-3334FU\n+7776FU\n%TIME_UP\n").and_then(|r| r.winner),Some(Teban::Sente));
+		assert_eq!(result_of("V2.2\nPI82HI\n-\n-3334FU\n%KACHI\n").and_then(|r| r.winner),Some(Teban::Sente));
+	}
 }
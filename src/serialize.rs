@@ -0,0 +1,263 @@
+// serdeでのusiagentの型の表現はSFEN/USIの文字列とする
+use std::fmt::Display;
+
+use serde::Serialize;
+use serde::Serializer;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::de;
+use serde::ser;
+
+use CsaMove;
+use CsaMoves;
+use EngineComment;
+
+fn to_str<T,E,S>(v:&T,serializer:S) -> Result<S::Ok,S::Error>
+	where T: ::usiagent::protocol::ToSfen<E>,
+		  E: ::std::error::Error + Display,
+		  S: Serializer {
+	serializer.serialize_str(&v.to_sfen().map_err(ser::Error::custom)?)
+}
+pub(crate) mod teban {
+	use std::convert::TryFrom;
+
+	use serde::Deserialize;
+	use serde::Deserializer;
+	use serde::Serializer;
+	use serde::de;
+
+	use usiagent::shogi::Teban;
+
+	pub fn serialize<S: Serializer>(teban:&Teban,serializer:S) -> Result<S::Ok,S::Error> {
+		super::to_str(teban,serializer)
+	}
+
+	pub fn deserialize<'de,D: Deserializer<'de>>(deserializer:D) -> Result<Teban,D::Error> {
+		let s = String::deserialize(deserializer)?;
+
+		Teban::try_from(s.as_str()).map_err(|_| de::Error::custom(format!("invalid side to move: {}",s)))
+	}
+}
+pub(crate) mod option_teban {
+	use std::convert::TryFrom;
+
+	use serde::Deserialize;
+	use serde::Deserializer;
+	use serde::Serializer;
+	use serde::de;
+
+	use usiagent::shogi::Teban;
+
+	pub fn serialize<S: Serializer>(teban:&Option<Teban>,serializer:S) -> Result<S::Ok,S::Error> {
+		match *teban {
+			Some(ref t) => super::teban::serialize(t,serializer),
+			None => serializer.serialize_none(),
+		}
+	}
+
+	pub fn deserialize<'de,D: Deserializer<'de>>(deserializer:D) -> Result<Option<Teban>,D::Error> {
+		Option::<String>::deserialize(deserializer)?.map(|s| {
+			Teban::try_from(s.as_str()).map_err(|_| de::Error::custom(format!("invalid side to move: {}",s)))
+		}).transpose()
+	}
+}
+pub(crate) mod banmen {
+	use serde::Deserialize;
+	use serde::Deserializer;
+	use serde::Serializer;
+	use serde::de;
+
+	use usiagent::shogi::Banmen;
+
+	use sfen::parse_board;
+
+	pub fn serialize<S: Serializer>(banmen:&Banmen,serializer:S) -> Result<S::Ok,S::Error> {
+		super::to_str(banmen,serializer)
+	}
+
+	pub fn deserialize<'de,D: Deserializer<'de>>(deserializer:D) -> Result<Banmen,D::Error> {
+		let s = String::deserialize(deserializer)?;
+
+		parse_board(&s).ok_or_else(|| de::Error::custom(format!("invalid board: {}",s)))
+	}
+}
+pub(crate) mod mochigoma {
+	use std::convert::TryFrom;
+
+	use serde::Deserialize;
+	use serde::Deserializer;
+	use serde::Serializer;
+	use serde::de;
+
+	use usiagent::shogi::MochigomaCollections;
+
+	pub fn serialize<S: Serializer>(mc:&MochigomaCollections,serializer:S) -> Result<S::Ok,S::Error> {
+		super::to_str(mc,serializer)
+	}
+
+	pub fn deserialize<'de,D: Deserializer<'de>>(deserializer:D) -> Result<MochigomaCollections,D::Error> {
+		let s = String::deserialize(deserializer)?;
+
+		let mc = MochigomaCollections::try_from(s.as_str()).map_err(|_| {
+			de::Error::custom(format!("invalid pieces in hand: {}",s))
+		})?;
+
+		// "-"は空の持ち駒の組として変換されるので元の表現に戻す
+		if mc.is_empty() {
+			Ok(MochigomaCollections::Empty)
+		} else {
+			Ok(mc)
+		}
+	}
+}
+pub(crate) mod usi_move {
+	use std::convert::TryFrom;
+
+	use serde::Deserialize;
+	use serde::Deserializer;
+	use serde::Serializer;
+	use serde::de;
+
+	use usiagent::shogi::Move;
+
+	pub fn serialize<S: Serializer>(m:&Move,serializer:S) -> Result<S::Ok,S::Error> {
+		super::to_str(m,serializer)
+	}
+
+	pub fn deserialize<'de,D: Deserializer<'de>>(deserializer:D) -> Result<Move,D::Error> {
+		let s = String::deserialize(deserializer)?;
+
+		Move::try_from(s.as_str()).map_err(|_| de::Error::custom(format!("invalid move: {}",s)))
+	}
+}
+pub(crate) mod usi_moves {
+	use std::convert::TryFrom;
+
+	use serde::Deserialize;
+	use serde::Deserializer;
+	use serde::Serializer;
+	use serde::ser::SerializeSeq;
+	use serde::de;
+
+	use usiagent::protocol::ToSfen;
+	use usiagent::shogi::Move;
+
+	pub fn serialize<S: Serializer>(mvs:&[Move],serializer:S) -> Result<S::Ok,S::Error> {
+		let mut seq = serializer.serialize_seq(Some(mvs.len()))?;
+
+		for m in mvs {
+			seq.serialize_element(&m.to_sfen().map_err(::serde::ser::Error::custom)?)?;
+		}
+
+		seq.end()
+	}
+
+	pub fn deserialize<'de,D: Deserializer<'de>>(deserializer:D) -> Result<Vec<Move>,D::Error> {
+		Vec::<String>::deserialize(deserializer)?.iter().map(|s| {
+			Move::try_from(s.as_str()).map_err(|_| de::Error::custom(format!("invalid move: {}",s)))
+		}).collect()
+	}
+}
+// 指し手とコメントの数の整合性を保つため、一手ごとの要素の列として表現する
+#[derive(Serialize, Deserialize)]
+struct CsaMovesEntry {
+	#[serde(rename = "move")]
+	m:CsaMove,
+	#[serde(default)]
+	comments:Vec<String>,
+	#[serde(default)]
+	engine_comment:Option<EngineComment>,
+}
+impl Serialize for CsaMoves {
+	fn serialize<S: Serializer>(&self,serializer:S) -> Result<S::Ok,S::Error> {
+		use serde::ser::SerializeSeq;
+
+		let mut seq = serializer.serialize_seq(Some(self.len()))?;
+
+		for (i,m) in self.iter().enumerate() {
+			seq.serialize_element(&CsaMovesEntry {
+				m:*m,
+				comments:self.comments(i).to_vec(),
+				engine_comment:self.engine_comment(i).cloned(),
+			})?;
+		}
+
+		seq.end()
+	}
+}
+impl<'de> Deserialize<'de> for CsaMoves {
+	fn deserialize<D: Deserializer<'de>>(deserializer:D) -> Result<CsaMoves,D::Error> {
+		let entries = Vec::<CsaMovesEntry>::deserialize(deserializer)?;
+
+		let mut mvs = CsaMoves::new();
+
+		for e in entries {
+			let i = mvs.push(e.m).map_err(de::Error::custom)?;
+
+			for c in e.comments {
+				mvs.add_comment(i,c);
+			}
+
+			if let Some(c) = e.engine_comment {
+				mvs.set_engine_comment(i,c);
+			}
+		}
+
+		Ok(mvs)
+	}
+}
+#[cfg(test)]
+mod tests {
+	use serde_json;
+
+	use usiagent::shogi::*;
+
+	use CsaData;
+	use CsaParser;
+	use CsaPosition;
+
+	fn round_trip(data:&CsaData) -> CsaData {
+		serde_json::from_str(&serde_json::to_string(data).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn csa_data_round_trip() {
+		let data = CsaParser::parse_str(concat!(
+			"'header\nV3.0\nN+sente\nN-gote\n$TIME:Total_Time:600\n$MAX_MOVES:256\n",
+			"PI82HI\n-\n'position\n",
+			"-3334FU\nT1.5\n'** -30 +7776FU\n+7776FU\n-2288UM\n+7988GI\n-0055KA\n%TORYO\n'end\n"
+		)).unwrap().remove(0);
+
+		assert!(data.moves.engine_comment(0).is_some());
+		assert_eq!(round_trip(&data),data);
+	}
+
+	#[test]
+	fn positions_are_sfen_strings() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n").unwrap().remove(0);
+
+		let v = serde_json::to_value(&data).unwrap();
+
+		assert_eq!(v["teban_at_start"],"b");
+		assert_eq!(v["initial_position"],"lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL");
+		assert_eq!(v["moves"][0]["move"]["Move"][0],"7g7f");
+
+		let p = data.position_at(1).unwrap();
+
+		assert_eq!(serde_json::from_value::<CsaPosition>(serde_json::to_value(&p).unwrap()).unwrap(),p);
+		assert_eq!(p.teban,Teban::Gote);
+	}
+
+	#[test]
+	fn missing_comments_default_to_empty() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n").unwrap().remove(0);
+
+		let mut v = serde_json::to_value(&data).unwrap();
+
+		for k in ["header_comments","position_comments","end_state_comments"].iter() {
+			v.as_object_mut().unwrap().remove(*k);
+		}
+
+		assert_eq!(serde_json::from_value::<CsaData>(v).unwrap(),data);
+	}
+}
@@ -0,0 +1,872 @@
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use usiagent::shogi::*;
+use usiagent::rule::*;
+
+use CsaData;
+use CsaMove;
+use CsaMoves;
+use CsaMovesParser;
+use EndState;
+use EngineComment;
+use TimeSettings;
+use error::*;
+use writer::CsaWriter;
+use writer::ToCsa;
+use writer::move_to_csa;
+
+// 接続後にLOGINを待つ時間
+const LOGIN_TIMEOUT:Duration = Duration::from_secs(30);
+// 終局後にLOGOUTを待つ時間
+const LOGOUT_TIMEOUT:Duration = Duration::from_secs(10);
+
+struct Player {
+	name:String,
+	reader:Option<BufReader<TcpStream>>, // 対局中は受信用のスレッドに渡す
+	writer:TcpStream,
+	remaining:Duration, // 残りの持ち時間
+}
+impl Player {
+	fn send(&mut self,line:&str) -> io::Result<()> {
+		writeln!(self.writer,"{}",line)?;
+		self.writer.flush()
+	}
+
+	// 空行はキープアライブなので読み飛ばす。接続が切れた場合はNoneを返す
+	fn read_message(&mut self,deadline:Option<Instant>) -> io::Result<Option<String>> {
+		loop {
+			let timeout = match deadline {
+				Some(d) => {
+					let now = Instant::now();
+
+					if now >= d {
+						return Err(io::Error::new(io::ErrorKind::TimedOut,"The time limit has been reached."));
+					}
+
+					Some(d - now)
+				},
+				None => None,
+			};
+
+			self.writer.set_read_timeout(timeout)?;
+
+			let reader = match self.reader.as_mut() {
+				Some(reader) => reader,
+				None => return Ok(None),
+			};
+
+			let mut buf = String::new();
+
+			if reader.read_line(&mut buf)? == 0 {
+				return Ok(None);
+			}
+
+			let line = buf.trim_end_matches(['\r','\n']);
+
+			if !line.is_empty() {
+				return Ok(Some(line.to_string()));
+			}
+		}
+	}
+}
+// 手番でない対局者の受信も監視できるよう、対局者ごとのスレッドで受信して通知する。
+// 接続が切れた場合はNoneを通知する
+fn spawn_readers(players:&mut [Player; 2]) -> Result<Receiver<(usize,Option<String>)>,CsaServerError> {
+	let (sender,receiver) = mpsc::channel();
+
+	for (i,p) in players.iter_mut().enumerate() {
+		p.writer.set_read_timeout(None)?;
+
+		let mut reader = p.reader.take().ok_or(CsaServerError::ConnectionClosed)?;
+		let sender = sender.clone();
+
+		thread::spawn(move || {
+			loop {
+				let mut buf = String::new();
+
+				match reader.read_line(&mut buf) {
+					Ok(0) | Err(_) => {
+						let _ = sender.send((i,None));
+						return;
+					},
+					Ok(_) => {
+						let line = buf.trim_end_matches(['\r','\n']);
+
+						if !line.is_empty() && sender.send((i,Some(line.to_string()))).is_err() {
+							return;
+						}
+					}
+				}
+			}
+		});
+	}
+
+	Ok(receiver)
+}
+fn receive(receiver:&Receiver<(usize,Option<String>)>,deadline:Option<Instant>)
+	-> Result<(usize,Option<String>),RecvTimeoutError> {
+	match deadline {
+		Some(d) => receiver.recv_timeout(d.saturating_duration_since(Instant::now())),
+		None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+	}
+}
+fn index(teban:Teban) -> usize {
+	match teban {
+		Teban::Sente => 0,
+		Teban::Gote => 1,
+	}
+}
+fn units(d:Duration,unit:Duration) -> u128 {
+	d.as_nanos() / unit.as_nanos()
+}
+// 終局の理由と各対局者への結果を送る
+fn finish(players:&mut [Player; 2],reason:&str,winner:Option<Teban>,other:&str) {
+	for (i,p) in players.iter_mut().enumerate() {
+		let result = match winner {
+			Some(w) if index(w) == i => "#WIN",
+			Some(_) => "#LOSE",
+			None => other,
+		};
+
+		let _ = p.send(&format!("#{}",reason)).and_then(|_| p.send(result));
+	}
+}
+pub struct CsaServer {
+	listener:TcpListener,
+	data:CsaData,
+	games:u32,
+	login_timeout:Duration,
+}
+impl CsaServer {
+	// dataの開始局面と途中までの指し手、$TIMEと$MAX_MOVESの設定で対局させる
+	pub fn bind<A: ToSocketAddrs>(addr:A,data:CsaData) -> Result<CsaServer,CsaServerError> {
+		if data.end_state.is_some() || data.moves.iter().any(|m| !matches!(*m, CsaMove::Move(_,_))) {
+			return Err(CsaServerError::InvalidStateError(String::from(
+				"The game to start from has already ended."
+			)));
+		}
+
+		Ok(CsaServer {
+			listener:TcpListener::bind(addr)?,
+			data,
+			games:0,
+			login_timeout:LOGIN_TIMEOUT,
+		})
+	}
+
+	pub fn login_timeout(mut self,login_timeout:Duration) -> CsaServer {
+		self.login_timeout = login_timeout;
+		self
+	}
+
+	pub fn local_addr(&self) -> Result<SocketAddr,CsaServerError> {
+		Ok(self.listener.local_addr()?)
+	}
+
+	fn accept_player(&self,taken:Option<&str>) -> Result<Player,CsaServerError> {
+		loop {
+			let (stream,_) = self.listener.accept()?;
+
+			stream.set_nodelay(true)?;
+
+			let mut player = Player {
+				name:String::new(),
+				reader:Some(BufReader::new(stream.try_clone()?)),
+				writer:stream,
+				remaining:Duration::from_secs(0),
+			};
+
+			// LOGINを送らない接続や読み込みに失敗した接続は切断して次の接続を待つ
+			let line = match player.read_message(Some(Instant::now() + self.login_timeout)) {
+				Ok(Some(line)) => line,
+				Ok(None) | Err(_) => continue,
+			};
+
+			let items = line.split(' ').collect::<Vec<&str>>();
+
+			if items.len() == 3 && items[0] == "LOGIN" && Some(items[1]) != taken {
+				player.name = items[1].to_string();
+				player.send(&format!("LOGIN:{} OK",player.name))?;
+
+				return Ok(player);
+			}
+
+			let _ = player.send("LOGIN:incorrect");
+		}
+	}
+
+	fn game_summary(&self,game_id:&str,players:&[Player; 2],your_turn:Teban) -> Result<String,CsaServerError> {
+		let data = &self.data;
+		let to_move = if data.moves.len().is_multiple_of(2) {
+			data.teban_at_start
+		} else {
+			data.teban_at_start.opposite()
+		};
+
+		let mut lines = vec![
+			String::from("BEGIN Game_Summary"),
+			String::from("Protocol_Version:1.2"),
+			String::from("Protocol_Mode:Server"),
+			String::from("Format:Shogi 1.0"),
+			String::from("Declaration:Jishogi 1.1"),
+			format!("Game_ID:{}",game_id),
+			format!("Name+:{}",players[0].name),
+			format!("Name-:{}",players[1].name),
+			format!("Your_Turn:{}",your_turn.to_csa()),
+			String::from("Rematch_On_Draw:NO"),
+			format!("To_Move:{}",to_move.to_csa()),
+		];
+
+		let info = data.kifu_info.as_ref();
+
+		if let Some(m) = info.and_then(|i| i.max_moves) {
+			lines.push(format!("Max_Moves:{}",m));
+		}
+
+		if let Some(t) = info.and_then(|i| i.time) {
+			let unit = t.time_unit;
+
+			lines.push(String::from("BEGIN Time"));
+
+			if unit.subsec_nanos() == 0 {
+				lines.push(format!("Time_Unit:{}sec",unit.as_secs()));
+			} else {
+				lines.push(format!("Time_Unit:{}msec",unit.as_millis()));
+			}
+
+			lines.push(format!("Total_Time:{}",units(t.total_time,unit)));
+			lines.push(format!("Byoyomi:{}",units(t.byoyomi,unit)));
+			lines.push(format!("Least_Time_Per_Move:{}",units(t.least_time_per_move,unit)));
+
+			if t.increment > Duration::from_secs(0) {
+				lines.push(format!("Increment:{}",units(t.increment,unit)));
+			}
+
+			if t.delay > Duration::from_secs(0) {
+				lines.push(format!("Delay:{}",units(t.delay,unit)));
+			}
+
+			if t.time_roundup {
+				lines.push(String::from("Time_Roundup:YES"));
+			}
+
+			lines.push(String::from("END Time"));
+		}
+
+		lines.push(String::from("BEGIN Position"));
+
+		let mut writer = CsaWriter::new(Vec::new());
+
+		writer.write_position(&data.initial_position,&data.initial_mochigoma)?;
+
+		let position = String::from_utf8(writer.into_inner()).map_err(|_| {
+			CsaServerError::InvalidStateError(String::from("The position is not valid utf-8."))
+		})?;
+
+		lines.extend(position.lines().map(|l| l.to_string()));
+		lines.push(data.teban_at_start.to_csa());
+
+		let unit = info.and_then(|i| i.time).map(|t| t.time_unit).unwrap_or(Duration::from_secs(1));
+		let Banmen(mut kinds) = data.initial_position;
+		let mut mc = data.initial_mochigoma.clone();
+		let mut teban = data.teban_at_start;
+
+		for m in data.moves.iter() {
+			if let CsaMove::Move(ref m,t) = *m {
+				let mut line = move_to_csa(&kinds,teban,m)?;
+
+				if let Some(t) = t {
+					line.push_str(&format!(",T{}",units(t,unit)));
+				}
+
+				lines.push(line);
+
+				CsaMovesParser::apply_move(&mut kinds,&mut mc,teban,m);
+				teban = teban.opposite();
+			}
+		}
+
+		lines.push(String::from("END Position"));
+		lines.push(String::from("END Game_Summary"));
+
+		Ok(lines.join("\n"))
+	}
+
+	// 二人の対局者のログインを受け付けて一局対局させ、終局した棋譜を返す
+	pub fn run_game(&mut self) -> Result<CsaData,CsaServerError> {
+		let sente = self.accept_player(None)?;
+		let gote = self.accept_player(Some(&sente.name))?;
+
+		let mut players = [sente,gote];
+
+		self.games += 1;
+
+		let game_id = format!("{}-{}",SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),self.games);
+
+		for (i,&t) in [Teban::Sente,Teban::Gote].iter().enumerate() {
+			let summary = self.game_summary(&game_id,&players,t)?;
+
+			players[i].send(&summary)?;
+		}
+
+		for i in 0..2 {
+			match players[i].read_message(None)? {
+				Some(ref line) if *line == "AGREE" || *line == format!("AGREE {}",game_id) => (),
+				Some(_) => {
+					let name = players[i].name.clone();
+
+					for p in players.iter_mut() {
+						let _ = p.send(&format!("REJECT:{} by {}",game_id,name));
+					}
+
+					return Err(CsaServerError::GameRejected(name));
+				},
+				None => {
+					return Err(CsaServerError::ConnectionClosed);
+				}
+			}
+		}
+
+		let time = self.data.kifu_info.as_ref().and_then(|i| i.time);
+
+		for p in players.iter_mut() {
+			p.remaining = time.map(|t| t.total_time).unwrap_or(Duration::from_secs(0));
+			p.send(&format!("START:{}",game_id))?;
+		}
+
+		let receiver = spawn_readers(&mut players)?;
+		let (mvs,end_state) = self.play(&mut players,&receiver,time)?;
+
+		// 終局後に届いた指し手等は読み捨てる
+		let deadline = Instant::now() + LOGOUT_TIMEOUT;
+		let mut done = [false; 2];
+
+		while !done.iter().all(|&d| d) {
+			match receive(&receiver,Some(deadline)) {
+				Ok((i,Some(ref line))) if line == "LOGOUT" => {
+					let _ = players[i].send("LOGOUT:completed");
+					done[i] = true;
+				},
+				Ok((i,None)) => done[i] = true,
+				Ok(_) => (),
+				Err(_) => break,
+			}
+		}
+
+		// 受信用のスレッドを終了させる
+		for p in players.iter() {
+			let _ = p.writer.shutdown(Shutdown::Both);
+		}
+
+		let mut data = self.data.clone();
+		let mut info = data.kifu_info.take().unwrap_or_default();
+
+		info.sente_name = Some(players[0].name.clone());
+		info.gote_name = Some(players[1].name.clone());
+
+		data.version = Some(String::from("3.0"));
+		data.kifu_info = Some(info);
+		data.moves = mvs;
+		data.end_state = end_state;
+
+		Ok(data)
+	}
+
+	fn consumed(t:&TimeSettings,elapsed:Duration) -> Duration {
+		let unit = t.time_unit.as_nanos();
+		let mut n = elapsed.as_nanos() / unit;
+
+		if t.time_roundup && !elapsed.as_nanos().is_multiple_of(unit) {
+			n += 1;
+		}
+
+		Duration::from_nanos((n * unit) as u64).max(t.least_time_per_move)
+	}
+
+	fn play(&self,players:&mut [Player; 2],receiver:&Receiver<(usize,Option<String>)>,time:Option<TimeSettings>)
+		-> Result<(CsaMoves,Option<EndState>),CsaServerError> {
+
+		let parser = CsaMovesParser::new(true,true);
+		let data = &self.data;
+		let max_moves = data.kifu_info.as_ref().and_then(|i| i.max_moves);
+		let unit = time.map(|t| t.time_unit).unwrap_or(Duration::from_secs(1));
+
+		let mut mvs = data.moves.clone();
+
+		// 千日手の判定のため、局面とその局面に至る手が王手だったかを記録する
+		let mut history = Vec::new();
+		let mut checks = Vec::new();
+
+		for p in data.positions() {
+			checks.push(!history.is_empty() && Rule::is_mate(p.teban.opposite(),&State::new(p.banmen.clone())));
+			history.push((p.banmen,p.mochigoma,p.teban));
+		}
+
+		let (Banmen(mut kinds),mut mc,mut teban) = match history.last() {
+			Some(p) => p.clone(),
+			None => {
+				return Err(CsaServerError::InvalidStateError(String::from(
+					"The position of the game could not be restored."
+				)));
+			}
+		};
+
+		loop {
+			let start = Instant::now();
+			let i = index(teban);
+
+			let deadline = time.map(|t| start + players[i].remaining + t.byoyomi + t.delay + t.time_unit);
+
+			let (sender,line) = match receive(receiver,deadline) {
+				Ok((sender,Some(line))) => (sender,line),
+				Err(RecvTimeoutError::Timeout) => {
+					finish(players,"TIME_UP",Some(teban.opposite()),"#DRAW");
+					return Ok((mvs,Some(EndState::TimeUp)));
+				},
+				Ok((sender,None)) => {
+					let _ = players[1 - sender].send("#CHUDAN");
+					return Ok((mvs,Some(EndState::Chudan)));
+				},
+				Err(RecvTimeoutError::Disconnected) => {
+					return Ok((mvs,Some(EndState::Chudan)));
+				}
+			};
+
+			// 手番でない対局者からは中断の要求のみ受け付け、指し手等は反則とする
+			if sender != i {
+				if line == "%CHUDAN" {
+					for p in players.iter_mut() {
+						let _ = p.send("#CHUDAN");
+					}
+
+					return Ok((mvs,Some(EndState::Chudan)));
+				}
+
+				finish(players,"ILLEGAL_ACTION",Some(teban),"#DRAW");
+
+				return Ok((mvs,Some(match teban {
+					Teban::Sente => EndState::GIllegalAction,
+					Teban::Gote => EndState::SIllegalAction,
+				})));
+			}
+
+			let elapsed = start.elapsed();
+
+			let consumed = match time {
+				Some(ref t) => {
+					let consumed = CsaServer::consumed(t,elapsed);
+					let charged = consumed.checked_sub(t.delay).unwrap_or(Duration::from_secs(0));
+
+					if charged > players[i].remaining + t.byoyomi {
+						finish(players,"TIME_UP",Some(teban.opposite()),"#DRAW");
+						return Ok((mvs,Some(EndState::TimeUp)));
+					}
+
+					players[i].remaining = players[i].remaining.checked_sub(charged).unwrap_or(Duration::from_secs(0)) + t.increment;
+
+					consumed
+				},
+				None => Duration::from_secs(elapsed.as_secs()),
+			};
+
+			let t = format!(",T{}",units(consumed,unit));
+
+			if line == "%TORYO" {
+				for p in players.iter_mut() {
+					let _ = p.send(&format!("%TORYO{}",t));
+				}
+
+				finish(players,"RESIGN",Some(teban.opposite()),"#DRAW");
+				return Ok((mvs,Some(EndState::Toryo)));
+			} else if line == "%KACHI" {
+				if !Rule::is_nyugyoku_win(&State::new(Banmen(kinds)),teban,&mc,&None) {
+					finish(players,"ILLEGAL_MOVE",Some(teban.opposite()),"#DRAW");
+					return Ok((mvs,Some(EndState::IllegalMove)));
+				}
+
+				for p in players.iter_mut() {
+					let _ = p.send(&format!("%KACHI{}",t));
+				}
+
+				mvs.push(CsaMove::Kachi(Some(consumed))).map_err(|e| CsaServerError::InvalidStateError(e.to_string()))?;
+
+				finish(players,"JISHOGI",Some(teban),"#DRAW");
+				return Ok((mvs,None));
+			} else if line == "%CHUDAN" {
+				for p in players.iter_mut() {
+					let _ = p.send("#CHUDAN");
+				}
+
+				return Ok((mvs,Some(EndState::Chudan)));
+			}
+
+			let mut items = line.splitn(2,',');
+			let statement = items.next().unwrap_or("");
+			let comment = items.next().and_then(|c| c.strip_prefix('\''));
+
+			let ply = mvs.len() as u32 + 1;
+
+			let m = match parser.parse_move(statement,teban,&kinds,true).and_then(|m| {
+				parser.validate_move(ply,&State::new(Banmen(kinds)),&mc,teban,m).map(|_| m)
+			}) {
+				Ok(m) => m,
+				Err(_) => {
+					finish(players,"ILLEGAL_MOVE",Some(teban.opposite()),"#DRAW");
+					return Ok((mvs,Some(EndState::IllegalMove)));
+				}
+			};
+
+			let statement = move_to_csa(&kinds,teban,&m)?;
+
+			CsaMovesParser::apply_move(&mut kinds,&mut mc,teban,&m);
+
+			let n = mvs.push(CsaMove::Move(m,Some(consumed))).map_err(|e| CsaServerError::InvalidStateError(e.to_string()))?;
+
+			// 拡張モードの評価値と読み筋は棋譜のコメントとして残す
+			if let Some(c) = comment {
+				mvs.add_comment(n,format!("*{}",c));
+
+				if let Ok(e) = EngineComment::parse(c,teban.opposite(),&Banmen(kinds),&mc) {
+					mvs.set_engine_comment(n,e);
+				}
+			}
+
+			for p in players.iter_mut() {
+				let _ = p.send(&format!("{}{}",statement,t));
+			}
+
+			checks.push(Rule::is_mate(teban,&State::new(Banmen(kinds))));
+			teban = teban.opposite();
+			history.push((Banmen(kinds),mc.clone(),teban));
+
+			let current = &history[history.len() - 1];
+			let repeated = history.iter().enumerate().filter(|&(_,p)| p == current).map(|(i,_)| i).collect::<Vec<usize>>();
+
+			if repeated.len() >= 4 {
+				// 同一局面の間、一方の手が全て王手だった場合は王手をかけ続けた側の負け
+				let first = repeated[0];
+
+				let oute = |t:Teban| {
+					(first+1..history.len()).filter(|&k| history[k].2 != t).all(|k| checks[k])
+				};
+
+				if oute(Teban::Sente) {
+					finish(players,"OUTE_SENNICHITE",Some(Teban::Gote),"#DRAW");
+					return Ok((mvs,Some(EndState::SIllegalAction)));
+				} else if oute(Teban::Gote) {
+					finish(players,"OUTE_SENNICHITE",Some(Teban::Sente),"#DRAW");
+					return Ok((mvs,Some(EndState::GIllegalAction)));
+				}
+
+				finish(players,"SENNICHITE",None,"#DRAW");
+				return Ok((mvs,Some(EndState::Sennichite)));
+			}
+
+			if max_moves.map(|m| mvs.len() as u32 >= m).unwrap_or(false) {
+				finish(players,"MAX_MOVES",None,"#CENSORED");
+				return Ok((mvs,Some(EndState::MaxMoves)));
+			}
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use std::thread;
+	use std::thread::JoinHandle;
+
+	use usiagent::rule::BANMEN_START_POS;
+
+	use super::*;
+	use CsaParser;
+	use KifuInfo;
+	use client::*;
+
+	type Client = CsaClient<BufReader<TcpStream>,TcpStream>;
+
+	fn start_data(info:Option<KifuInfo>) -> CsaData {
+		CsaData::new(None,info,Teban::Sente,BANMEN_START_POS.clone(),MochigomaCollections::Empty,CsaMoves::new(),None,Vec::new())
+	}
+
+	fn serve(server:CsaServer) -> (SocketAddr,JoinHandle<CsaData>) {
+		let addr = server.local_addr().unwrap();
+
+		let handle = thread::spawn(move || {
+			let mut server = server;
+
+			server.run_game().unwrap()
+		});
+
+		(addr,handle)
+	}
+
+	fn login(addr:SocketAddr,name:&str) -> Client {
+		let mut client = CsaClient::connect(addr).unwrap();
+
+		client.login(name,"pass").unwrap();
+
+		client
+	}
+
+	// 先手と後手の順にログインして対局を開始する
+	fn start_game(addr:SocketAddr) -> (Client,Client) {
+		let sente = login(addr,"sente");
+		let gote = login(addr,"gote");
+
+		agree(sente,gote)
+	}
+
+	fn agree(mut sente:Client,mut gote:Client) -> (Client,Client) {
+		assert_eq!(sente.read_game_summary().unwrap().your_turn,Teban::Sente);
+		assert_eq!(gote.read_game_summary().unwrap().your_turn,Teban::Gote);
+
+		let agreed = thread::spawn(move || {
+			assert_eq!(gote.agree().unwrap(),StartResult::Started);
+			gote
+		});
+
+		assert_eq!(sente.agree().unwrap(),StartResult::Started);
+
+		(sente,agreed.join().unwrap())
+	}
+
+	fn logout(mut sente:Client,mut gote:Client,server:JoinHandle<CsaData>) -> CsaData {
+		sente.logout().unwrap();
+		gote.logout().unwrap();
+
+		server.join().unwrap()
+	}
+
+	#[test]
+	fn silent_connections_time_out_at_login() {
+		let server = CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap().login_timeout(Duration::from_millis(100));
+		let (addr,server) = serve(server);
+
+		// LOGINを送らない接続は時間切れで切断され、後続の接続を受け付ける
+		let _silent = TcpStream::connect(addr).unwrap();
+
+		let (mut sente,gote) = start_game(addr);
+
+		sente.resign().unwrap();
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.end_state,Some(EndState::Toryo));
+	}
+	fn mv(s:&str) -> Move {
+		let d = s.chars().map(|c| c.to_digit(10).unwrap()).collect::<Vec<u32>>();
+
+		Move::To(KomaSrcPosition(d[0],d[1]),KomaDstToPosition(d[2],d[3],false))
+	}
+
+	// 先手から交互に指し、両方の対局者がサーバーから指し手を受け取ったことを確かめる
+	fn play(sente:&mut Client,gote:&mut Client,moves:&[&str]) {
+		for (i,s) in moves.iter().enumerate() {
+			let m = mv(s);
+
+			let teban = if i % 2 == 0 {
+				sente.send_move(&m,None).unwrap();
+				Teban::Sente
+			} else {
+				gote.send_move(&m,None).unwrap();
+				Teban::Gote
+			};
+
+			for c in [&mut *sente,&mut *gote].iter_mut() {
+				match c.receive().unwrap() {
+					GameEvent::Move(t,r,_,None) if t == teban && r == m => (),
+					e => panic!("unexpected event {:?}",e),
+				}
+			}
+		}
+	}
+
+	fn expect_end(client:&mut Client,reason:GameEndReason,outcome:GameOutcome) {
+		loop {
+			match client.receive().unwrap() {
+				// 終局の直前の指し手は読み飛ばす
+				GameEvent::Move(_,_,_,_) | GameEvent::Special(_,_) => (),
+				GameEvent::Reason(r) => assert_eq!(r,reason),
+				GameEvent::End(o) => {
+					assert_eq!(o,outcome);
+					return;
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn resignation() {
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		play(&mut sente,&mut gote,&["7776"]);
+
+		gote.resign().unwrap();
+
+		expect_end(&mut sente,GameEndReason::Resign,GameOutcome::Win);
+		expect_end(&mut gote,GameEndReason::Resign,GameOutcome::Lose);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.moves.len(),1);
+		assert_eq!(data.end_state,Some(EndState::Toryo));
+		assert_eq!(data.kifu_info.as_ref().and_then(|i| i.gote_name.clone()),Some(String::from("gote")));
+
+		let written = CsaParser::parse_str(&data.to_csa().unwrap()).unwrap().remove(0);
+
+		assert_eq!(written,data);
+	}
+
+	#[test]
+	fn time_up() {
+		let mut info = KifuInfo::new();
+
+		info.time = Some(TimeSettings {
+			time_unit:Duration::from_millis(1),
+			total_time:Duration::from_millis(50),
+			..TimeSettings::default()
+		});
+
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(Some(info))).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		expect_end(&mut sente,GameEndReason::TimeUp,GameOutcome::Lose);
+		expect_end(&mut gote,GameEndReason::TimeUp,GameOutcome::Win);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.end_state,Some(EndState::TimeUp));
+	}
+
+	#[test]
+	fn illegal_move() {
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		play(&mut sente,&mut gote,&["7776"]);
+
+		// 歩は二マス進めない
+		gote.send_move(&mv("3335"),None).unwrap();
+
+		expect_end(&mut sente,GameEndReason::IllegalMove,GameOutcome::Win);
+		expect_end(&mut gote,GameEndReason::IllegalMove,GameOutcome::Lose);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.moves.len(),1);
+		assert_eq!(data.end_state,Some(EndState::IllegalMove));
+	}
+
+	#[test]
+	fn failed_declaration() {
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		sente.declare_win().unwrap();
+
+		expect_end(&mut sente,GameEndReason::IllegalMove,GameOutcome::Lose);
+		expect_end(&mut gote,GameEndReason::IllegalMove,GameOutcome::Win);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.end_state,Some(EndState::IllegalMove));
+	}
+
+	#[test]
+	fn sennichite() {
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		play(&mut sente,&mut gote,&["2838","8272","3828","7282",
+									"2838","8272","3828","7282",
+									"2838","8272","3828"]);
+
+		gote.send_move(&mv("7282"),None).unwrap();
+
+		expect_end(&mut sente,GameEndReason::Sennichite,GameOutcome::Draw);
+		expect_end(&mut gote,GameEndReason::Sennichite,GameOutcome::Draw);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.moves.len(),12);
+		assert_eq!(data.end_state,Some(EndState::Sennichite));
+	}
+
+	#[test]
+	fn max_moves() {
+		let mut info = KifuInfo::new();
+
+		info.max_moves = Some(2);
+
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(Some(info))).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		play(&mut sente,&mut gote,&["7776"]);
+
+		gote.send_move(&mv("3334"),None).unwrap();
+
+		expect_end(&mut sente,GameEndReason::MaxMoves,GameOutcome::Censored);
+		expect_end(&mut gote,GameEndReason::MaxMoves,GameOutcome::Censored);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.moves.len(),2);
+		assert_eq!(data.end_state,Some(EndState::MaxMoves));
+	}
+
+	#[test]
+	fn premature_move() {
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap());
+		let sente = login(addr,"sente");
+
+		// クライアントは手番でない指し手を送らないため、同じ接続に直接書き込む
+		let stream = TcpStream::connect(addr).unwrap();
+		let mut raw = stream.try_clone().unwrap();
+		let mut gote = CsaClient::new(BufReader::new(stream.try_clone().unwrap()),stream);
+
+		gote.login("gote","pass").unwrap();
+
+		let (mut sente,mut gote) = agree(sente,gote);
+
+		writeln!(raw,"-3334FU").unwrap();
+
+		expect_end(&mut sente,GameEndReason::IllegalAction,GameOutcome::Win);
+		expect_end(&mut gote,GameEndReason::IllegalAction,GameOutcome::Lose);
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.moves.len(),0);
+		assert_eq!(data.end_state,Some(EndState::GIllegalAction));
+	}
+
+	#[test]
+	fn chudan_from_waiting_player() {
+		let (addr,server) = serve(CsaServer::bind("127.0.0.1:0",start_data(None)).unwrap());
+		let (mut sente,mut gote) = start_game(addr);
+
+		gote.chudan().unwrap();
+
+		assert_eq!(sente.receive().unwrap(),GameEvent::End(GameOutcome::Chudan));
+		assert_eq!(gote.receive().unwrap(),GameEvent::End(GameOutcome::Chudan));
+
+		let data = logout(sente,gote,server);
+
+		assert_eq!(data.end_state,Some(EndState::Chudan));
+	}
+}
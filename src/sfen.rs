@@ -0,0 +1,128 @@
+use std::convert::TryFrom;
+
+use usiagent::shogi::*;
+use usiagent::protocol::ToSfen;
+use usiagent::error::SfenStringConvertError;
+
+use CsaData;
+use CsaMoves;
+use CsaPosition;
+use error::*;
+
+pub(crate) fn parse_board(s:&str) -> Option<Banmen> {
+	// usiagentの変換は段や筋の数が足りない盤面も受け付けるので先に確認する
+	let rows = s.split('/').collect::<Vec<&str>>();
+
+	if rows.len() != 9 || rows.iter().any(|r| {
+		r.chars().filter(|&c| c != '+').map(|c| c.to_digit(10).unwrap_or(1)).sum::<u32>() != 9
+	}) {
+		return None;
+	}
+
+	Banmen::try_from(s).ok()
+}
+pub fn parse_sfen(sfen:&str) -> Result<(Banmen,MochigomaCollections,Teban,u32),CsaParserError> {
+	let create_error = || {
+		CsaParserError::FormatError(CsaFormatErrorKind::BadSfen,Some(SourceStatement::new(None,sfen.to_string())))
+	};
+
+	let s = sfen.trim();
+	let s = s.strip_prefix("sfen ").unwrap_or(s);
+
+	let items = s.split_whitespace().collect::<Vec<&str>>();
+
+	if items.len() < 3 || items.len() > 4 {
+		return Err(create_error());
+	}
+
+	let banmen = parse_board(items[0]).ok_or_else(create_error)?;
+	let teban = Teban::try_from(items[1]).map_err(|_| create_error())?;
+	let mc = MochigomaCollections::try_from(items[2]).map_err(|_| create_error())?;
+
+	// 手数は省略可能(省略時は1手目)
+	let ply = match items.get(3) {
+		Some(n) => n.parse::<u32>().map_err(|_| create_error())?,
+		None => 1,
+	};
+
+	Ok((banmen,mc,teban,ply))
+}
+pub fn to_sfen(banmen:&Banmen,mc:&MochigomaCollections,teban:Teban,ply:u32) -> Result<String,SfenStringConvertError> {
+	Ok(format!("{} {} {} {}",banmen.to_sfen()?,teban.to_sfen()?,mc.to_sfen()?,ply))
+}
+impl CsaData {
+	// CsaDataは手数を持たないので、SFENの手数は読み捨てる
+	pub fn from_sfen(sfen:&str) -> Result<CsaData,CsaParserError> {
+		let (banmen,mc,teban,_) = parse_sfen(sfen)?;
+
+		Ok(CsaData::new(None,None,teban,banmen,mc,CsaMoves::new(),None,Vec::new()))
+	}
+
+	// 開始局面を手数1として書き出す(from_sfenで読んだ手数は保たれない)
+	pub fn to_sfen(&self) -> Result<String,SfenStringConvertError> {
+		to_sfen(&self.initial_position,&self.initial_mochigoma,self.teban_at_start,1)
+	}
+}
+impl CsaPosition {
+	pub fn to_sfen(&self) -> Result<String,SfenStringConvertError> {
+		// SFENの手数は次に指す手の番号
+		to_sfen(&self.banmen,&self.mochigoma,self.teban,self.ply + 1)
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use usiagent::rule::BANMEN_START_POS;
+	use CsaParser;
+
+	const STARTPOS:&str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
+
+	#[test]
+	fn parse_startpos() {
+		let (banmen,mc,teban,ply) = parse_sfen(&format!("sfen {}",STARTPOS)).unwrap();
+
+		assert_eq!(banmen,BANMEN_START_POS.clone());
+		assert_eq!(mc,MochigomaCollections::Empty);
+		assert_eq!(teban,Teban::Sente);
+		assert_eq!(ply,1);
+
+		assert_eq!(parse_sfen("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w -").unwrap().3,1);
+	}
+
+	#[test]
+	fn reject_malformed_sfen() {
+		for s in &["",
+				   "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1 b - 1",
+				   "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R/LNSGKGSNL b - 1",
+				   "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL x - 1",
+				   "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - a",
+				   "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1 1"] {
+			match parse_sfen(s) {
+				Err(CsaParserError::FormatError(CsaFormatErrorKind::BadSfen,_)) => (),
+				r => panic!("{:?} for {:?}",r.map(|_| ()),s),
+			}
+		}
+	}
+
+	#[test]
+	fn round_trip_with_hands() {
+		let sfen = "ln1gkg1nl/1r1s3b1/p1pppp1pp/6p2/1p7/2P6/PP1PPPPPP/1B1S3R1/LN1GKGSNL w Sp 12";
+
+		let data = CsaData::from_sfen(sfen).unwrap();
+
+		assert_eq!(data.teban_at_start,Teban::Gote);
+
+		// 手数は保たれず、常に1として書き出される
+		assert_eq!(data.to_sfen().unwrap(),sfen.replace(" 12"," 1"));
+		assert_eq!(CsaData::from_sfen(&sfen.replace(" 12"," 1")).unwrap(),data);
+	}
+
+	#[test]
+	fn position_sfen_uses_the_next_move_number() {
+		let data = CsaParser::parse_str("V2.2\nPI\n+\n+7776FU\n-3334FU\n").unwrap().remove(0);
+
+		assert_eq!(data.position_at(0).unwrap().to_sfen().unwrap(),STARTPOS);
+		assert_eq!(data.position_at(2).unwrap().to_sfen().unwrap(),
+					"lnsgkgsnl/1r5b1/pppppp1pp/6p2/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL b - 3");
+	}
+}
@@ -0,0 +1,601 @@
+use std::io::Write;
+use std::time::Duration;
+
+use usiagent::shogi::*;
+use usiagent::shogi::KomaKind::{
+	SFu,
+	SKyou,
+	SKei,
+	SGin,
+	SKin,
+	SKaku,
+	SHisha,
+	SOu,
+	SFuN,
+	SKyouN,
+	SKeiN,
+	SGinN,
+	SKakuN,
+	SHishaN,
+	GFu,
+	GKyou,
+	GKei,
+	GGin,
+	GKin,
+	GKaku,
+	GHisha,
+	GOu,
+	GFuN,
+	GKyouN,
+	GKeiN,
+	GGinN,
+	GKakuN,
+	GHishaN,
+	Blank
+};
+use usiagent::rule::BANMEN_START_POS;
+
+use CsaData;
+use CsaMove;
+use EndState;
+use KifuInfo;
+use TimeSettings;
+use is_v3;
+use moving_piece;
+use error::*;
+
+fn format_seconds(d:Duration) -> String {
+	let nanos = d.subsec_nanos();
+
+	// CSA 3.0の消費時間はミリ秒単位なので、ミリ秒で表せる端数は小数点以下3桁で書き出す
+	if nanos == 0 {
+		format!("{}",d.as_secs())
+	} else if nanos.is_multiple_of(1_000_000) {
+		format!("{}.{:03}",d.as_secs(),nanos / 1_000_000)
+	} else {
+		format!("{}.{}",d.as_secs(),format!("{:09}",nanos).trim_end_matches('0'))
+	}
+}
+// V3.0で追加された棋譜情報または終局状態を含むかどうか
+fn requires_v3(data:&CsaData) -> bool {
+	let info = data.kifu_info.as_ref().map(|info| {
+		info.time.is_some() || info.max_moves.is_some() || info.jishogi.is_some() || info.note.is_some()
+	}).unwrap_or(false);
+
+	info || data.end_state.map(|s| s.is_v3()).unwrap_or(false)
+}
+// 指し手を盤面上の駒の種類を使ってCSA形式の文字列に変換する
+pub(crate) fn move_to_csa(kinds:&[[KomaKind; 9]; 9],teban:Teban,m:&Move) -> Result<String,CsaWriterError> {
+	let k = moving_piece(kinds,m)?;
+
+	match *m {
+		Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+			let k = k.unwrap_or(Blank);
+
+			let k = if n {
+				k.to_nari()
+			} else {
+				k
+			};
+
+			Ok(format!("{}{}{}{}{}{}",teban.to_csa(),sx,sy,dx,dy,&k.to_csa()[1..]))
+		},
+		Move::Put(k,KomaDstPutPosition(dx,dy)) => {
+			Ok(format!("{}00{}{}{}",teban.to_csa(),dx,dy,k.to_csa()))
+		}
+	}
+}
+// move_to_csaで検証済みの指し手を盤面に適用する(持ち駒は扱わない)
+pub(crate) fn apply_move(kinds:&mut [[KomaKind; 9]; 9],teban:Teban,m:&Move) {
+	match *m {
+		Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+			let (sx,sy,dx,dy) = (sx as usize,sy as usize,dx as usize,dy as usize);
+
+			let k = kinds[sy-1][9-sx];
+
+			kinds[sy-1][9-sx] = Blank;
+			kinds[dy-1][9-dx] = if n { k.to_nari() } else { k };
+		},
+		Move::Put(k,KomaDstPutPosition(dx,dy)) => {
+			kinds[dy as usize - 1][9 - dx as usize] = KomaKind::from((teban,k));
+		}
+	}
+}
+pub trait ToCsa {
+	fn to_csa(&self) -> String;
+}
+impl ToCsa for Teban {
+	fn to_csa(&self) -> String {
+		match *self {
+			Teban::Sente => String::from("+"),
+			Teban::Gote => String::from("-"),
+		}
+	}
+}
+impl ToCsa for MochigomaKind {
+	fn to_csa(&self) -> String {
+		String::from(match *self {
+			MochigomaKind::Fu => "FU",
+			MochigomaKind::Kyou => "KY",
+			MochigomaKind::Kei => "KE",
+			MochigomaKind::Gin => "GI",
+			MochigomaKind::Kin => "KI",
+			MochigomaKind::Kaku => "KA",
+			MochigomaKind::Hisha => "HI",
+		})
+	}
+}
+impl ToCsa for KomaKind {
+	fn to_csa(&self) -> String {
+		String::from(match *self {
+			SFu => "+FU",
+			SKyou => "+KY",
+			SKei => "+KE",
+			SGin => "+GI",
+			SKin => "+KI",
+			SKaku => "+KA",
+			SHisha => "+HI",
+			SOu => "+OU",
+			SFuN => "+TO",
+			SKyouN => "+NY",
+			SKeiN => "+NK",
+			SGinN => "+NG",
+			SKakuN => "+UM",
+			SHishaN => "+RY",
+			GFu => "-FU",
+			GKyou => "-KY",
+			GKei => "-KE",
+			GGin => "-GI",
+			GKin => "-KI",
+			GKaku => "-KA",
+			GHisha => "-HI",
+			GOu => "-OU",
+			GFuN => "-TO",
+			GKyouN => "-NY",
+			GKeiN => "-NK",
+			GGinN => "-NG",
+			GKakuN => "-UM",
+			GHishaN => "-RY",
+			Blank => " * ",
+		})
+	}
+}
+impl ToCsa for EndState {
+	fn to_csa(&self) -> String {
+		String::from(match *self {
+			EndState::Toryo => "%TORYO",
+			EndState::Chudan => "%CHUDAN",
+			EndState::Sennichite => "%SENNICHITE",
+			EndState::TimeUp => "%TIME_UP",
+			EndState::IllegalMove => "%ILLEGAL_MOVE",
+			EndState::SIllegalAction => "%+ILLEGAL_ACTION",
+			EndState::GIllegalAction => "%-ILLEGAL_ACTION",
+			EndState::Jishogi => "%JISHOGI",
+			EndState::Kachi => "%KACHI",
+			EndState::Hikiwake => "%HIKIWAKE",
+			EndState::Matta => "%MATTA",
+			EndState::Tsumi => "%TSUMI",
+			EndState::Fuzumi => "%FUZUMI",
+			EndState::Error => "%ERROR",
+			EndState::MaxMoves => "%MAX_MOVES",
+			EndState::SFoul => "%+FOUL",
+			EndState::GFoul => "%-FOUL",
+		})
+	}
+}
+pub struct CsaWriter<W> where W: Write {
+	writer:W,
+}
+impl<W> CsaWriter<W> where W: Write {
+	pub fn new(writer:W) -> CsaWriter<W> {
+		CsaWriter {
+			writer,
+		}
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+
+	pub fn write_all(&mut self,data:&[CsaData]) -> Result<(),CsaWriterError> {
+		for (i,d) in data.iter().enumerate() {
+			if i > 0 {
+				writeln!(self.writer,"/")?;
+			}
+
+			self.write(d)?;
+		}
+
+		Ok(())
+	}
+
+	pub fn write(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		self.write_comments(&data.header_comments)?;
+
+		let v3 = requires_v3(data);
+
+		// V行が無いと局面を読み込めないため、バージョンが無い場合は
+		// V3.0でのみ使える棋譜情報や終局状態があればV3.0、そうでなければV2.2として書き出す
+		match data.version {
+			Some(ref v) if v3 && !is_v3(v) => {
+				return Err(CsaWriterError::InvalidStateError(format!(
+					"The data contains fields that cannot be written as csa version {}.",v
+				)));
+			},
+			Some(ref v) => writeln!(self.writer,"V{}",v)?,
+			None if v3 => writeln!(self.writer,"V3.0")?,
+			None => writeln!(self.writer,"V2.2")?,
+		}
+
+		if let Some(ref info) = data.kifu_info {
+			self.write_info(info)?;
+		}
+
+		self.write_position(&data.initial_position,&data.initial_mochigoma)?;
+
+		writeln!(self.writer,"{}",data.teban_at_start.to_csa())?;
+
+		self.write_comments(&data.position_comments)?;
+
+		self.write_moves(data)?;
+
+		if let Some(ref s) = data.end_state {
+			writeln!(self.writer,"{}",s.to_csa())?;
+		}
+
+		self.write_comments(&data.end_state_comments)?;
+
+		Ok(())
+	}
+
+	fn write_info(&mut self,info:&KifuInfo) -> Result<(),CsaWriterError> {
+		if let Some(ref s) = info.sente_name {
+			writeln!(self.writer,"N+{}",s)?;
+		}
+
+		if let Some(ref s) = info.gote_name {
+			writeln!(self.writer,"N-{}",s)?;
+		}
+
+		if let Some(ref s) = info.event {
+			writeln!(self.writer,"$EVENT:{}",s)?;
+		}
+
+		if let Some(ref s) = info.site {
+			writeln!(self.writer,"$SITE:{}",s)?;
+		}
+
+		if let Some(ref s) = info.start_time {
+			writeln!(self.writer,"$START_TIME:{}",s)?;
+		}
+
+		if let Some(ref s) = info.end_time {
+			writeln!(self.writer,"$END_TIME:{}",s)?;
+		}
+
+		if let Some((m,s)) = info.time_limit {
+			if m / 60 > 99 || s.map(|s| s > 99).unwrap_or(false) {
+				return Err(CsaWriterError::InvalidStateError(String::from(
+					"The time limit cannot be expressed in csa format."
+				)));
+			}
+
+			match s {
+				Some(s) => writeln!(self.writer,"$TIME_LIMIT:{:02}:{:02}+{:02}",m / 60, m % 60, s)?,
+				None => writeln!(self.writer,"$TIME_LIMIT:{:02}:{:02}",m / 60, m % 60)?,
+			}
+		}
+
+		if let Some(ref t) = info.time {
+			self.write_time(t)?;
+		}
+
+		if let Some(m) = info.max_moves {
+			writeln!(self.writer,"$MAX_MOVES:{}",m)?;
+		}
+
+		if let Some(p) = info.jishogi {
+			writeln!(self.writer,"$JISHOGI:{}",p)?;
+		}
+
+		if let Some(ref s) = info.note {
+			writeln!(self.writer,"$NOTE:{}",s)?;
+		}
+
+		if let Some(ref s) = info.opening {
+			writeln!(self.writer,"$OPENING:{}",s)?;
+		}
+
+		Ok(())
+	}
+
+	fn write_time(&mut self,t:&TimeSettings) -> Result<(),CsaWriterError> {
+		let default = TimeSettings::default();
+
+		writeln!(self.writer,"$TIME:{}+{}+{}",format_seconds(t.total_time),
+												format_seconds(t.byoyomi),
+												format_seconds(t.increment))?;
+
+		// 持ち時間+秒読み+加算の形式で表せない項目は、秒単位の値で個別に書き出す
+		if t.least_time_per_move != default.least_time_per_move {
+			writeln!(self.writer,"$TIME:Least_Time_Per_Move:{}",format_seconds(t.least_time_per_move))?;
+		}
+
+		if t.delay != default.delay {
+			writeln!(self.writer,"$TIME:Delay:{}",format_seconds(t.delay))?;
+		}
+
+		if t.time_roundup != default.time_roundup {
+			writeln!(self.writer,"$TIME:Time_Roundup:{}",if t.time_roundup { "YES" } else { "NO" })?;
+		}
+
+		// 単位を先に書き出すと以降の値の解釈が変わるため、最後に書き出す
+		if t.time_unit != default.time_unit {
+			if Duration::from_millis(t.time_unit.as_millis() as u64) != t.time_unit {
+				return Err(CsaWriterError::InvalidStateError(String::from(
+					"The time unit cannot be expressed in csa format."
+				)));
+			}
+
+			writeln!(self.writer,"$TIME:Time_Unit:{}msec",t.time_unit.as_millis())?;
+		}
+
+		Ok(())
+	}
+
+	pub(crate) fn write_position(&mut self,banmen:&Banmen,mc:&MochigomaCollections) -> Result<(),CsaWriterError> {
+		if let Some(removed) = self.removed_from_start_pos(banmen,mc) {
+			writeln!(self.writer,"PI{}",removed)?;
+
+			return Ok(());
+		}
+
+		let (ms,mg) = match *mc {
+			MochigomaCollections::Pair(ref ms,ref mg) => (ms.clone(),mg.clone()),
+			MochigomaCollections::Empty => (Mochigoma::new(),Mochigoma::new()),
+		};
+
+		let board = self.board_lines(banmen,&ms,&mg);
+		let pieces = self.piece_lines(banmen,&ms,&mg);
+
+		// 平手からの駒落ちで表せない局面は、P1～P9とP+/P-のうち短い方の表記を選ぶ
+		let lines = if pieces.iter().map(|l| l.len()).sum::<usize>() < board.iter().map(|l| l.len()).sum::<usize>() {
+			pieces
+		} else {
+			board
+		};
+
+		for l in lines {
+			writeln!(self.writer,"{}",l)?;
+		}
+
+		Ok(())
+	}
+
+	fn removed_from_start_pos(&self,banmen:&Banmen,mc:&MochigomaCollections) -> Option<String> {
+		if !mc.is_empty() {
+			return None;
+		}
+
+		let Banmen(ref kinds) = *banmen;
+		let Banmen(ref start) = BANMEN_START_POS;
+
+		let mut removed = String::new();
+
+		for y in 0..9 {
+			for x in 0..9 {
+				if kinds[y][x] == start[y][x] {
+					continue;
+				}
+
+				match (kinds[y][x],start[y][x]) {
+					(Blank,SOu) | (Blank,GOu) => {
+						return None;
+					},
+					(Blank,k) => {
+						removed.push_str(&format!("{}{}{}",9 - x,y + 1,&k.to_csa()[1..]));
+					},
+					_ => {
+						return None;
+					}
+				}
+			}
+		}
+
+		Some(removed)
+	}
+
+	fn board_lines(&self,banmen:&Banmen,ms:&Mochigoma,mg:&Mochigoma) -> Vec<String> {
+		let Banmen(ref kinds) = *banmen;
+
+		let mut lines = kinds.iter().enumerate().map(|(y,row)| {
+			row.iter().fold(format!("P{}",y + 1), |mut acc,k| {
+				acc.push_str(&k.to_csa());
+				acc
+			})
+		}).collect::<Vec<String>>();
+
+		for &(teban,m) in &[(Teban::Sente,ms),(Teban::Gote,mg)] {
+			if !m.is_empty() {
+				lines.push(m.iter().fold(format!("P{}",teban.to_csa()), |mut acc,(k,c)| {
+					for _ in 0..c {
+						acc.push_str(&format!("00{}",k.to_csa()));
+					}
+					acc
+				}));
+			}
+		}
+
+		lines
+	}
+
+	fn piece_lines(&self,banmen:&Banmen,ms:&Mochigoma,mg:&Mochigoma) -> Vec<String> {
+		let Banmen(ref kinds) = *banmen;
+
+		let mut lines = Vec::new();
+
+		for &(teban,m) in &[(Teban::Sente,ms),(Teban::Gote,mg)] {
+			let mut l = format!("P{}",teban.to_csa());
+
+			for (y,row) in kinds.iter().enumerate() {
+				for (x,&k) in row.iter().enumerate() {
+					if k == Blank {
+						continue;
+					}
+
+					let s = k.to_csa();
+
+					if s[0..1] == teban.to_csa() {
+						l.push_str(&format!("{}{}{}",9 - x,y + 1,&s[1..]));
+					}
+				}
+			}
+
+			for (k,c) in m.iter() {
+				for _ in 0..c {
+					l.push_str(&format!("00{}",k.to_csa()));
+				}
+			}
+
+			if l.len() > 2 {
+				lines.push(l);
+			}
+		}
+
+		lines
+	}
+
+	fn write_comments(&mut self,comments:&[String]) -> Result<(),CsaWriterError> {
+		for c in comments {
+			writeln!(self.writer,"'{}",c)?;
+		}
+
+		Ok(())
+	}
+
+	fn write_moves(&mut self,data:&CsaData) -> Result<(),CsaWriterError> {
+		let Banmen(mut kinds) = data.initial_position.clone();
+		let mut teban = data.teban_at_start;
+
+		for (i,m) in data.moves.iter().enumerate() {
+			match *m {
+				CsaMove::Move(ref m,t) => {
+					writeln!(self.writer,"{}",move_to_csa(&kinds,teban,m)?)?;
+					apply_move(&mut kinds,teban,m);
+					self.write_elapsed(t)?;
+				},
+				CsaMove::Kachi(t) => {
+					writeln!(self.writer,"%KACHI")?;
+					self.write_elapsed(t)?;
+				},
+				CsaMove::Hikiwake(t) => {
+					writeln!(self.writer,"%HIKIWAKE")?;
+					self.write_elapsed(t)?;
+				}
+			}
+
+			self.write_comments(data.moves.comments(i))?;
+
+			teban = teban.opposite();
+		}
+
+		Ok(())
+	}
+
+	fn write_elapsed(&mut self,t:Option<Duration>) -> Result<(),CsaWriterError> {
+		if let Some(t) = t {
+			writeln!(self.writer,"T{}",format_seconds(t))?;
+		}
+
+		Ok(())
+	}
+}
+impl CsaData {
+	pub fn to_csa(&self) -> Result<String,CsaWriterError> {
+		let mut writer = CsaWriter::new(Vec::new());
+
+		writer.write(self)?;
+
+		String::from_utf8(writer.into_inner()).map_err(|_| {
+			CsaWriterError::InvalidStateError(String::from("The output is not valid utf-8."))
+		})
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use CsaParser;
+
+	fn round_trip(s:&str) -> CsaData {
+		let data = CsaParser::parse_str(s).unwrap().remove(0);
+		let written = CsaParser::parse_str(&data.to_csa().unwrap()).unwrap().remove(0);
+
+		assert_eq!(written,data);
+
+		data
+	}
+
+	#[test]
+	fn round_trip_v2() {
+		let data = round_trip("'header\nV2.2\nN+sente\nN-gote\n$EVENT:test\nPI\n+\n'position\n+7776FU\nT12\n'first\n-3334FU\nT3\n%TORYO\n'end\n");
+
+		assert_eq!(data.version,Some(String::from("2.2")));
+		assert_eq!(data.end_state_comments,vec![String::from("end")]);
+	}
+
+	#[test]
+	fn round_trip_v3() {
+		let data = round_trip("V3.0\nN+sente\nN-gote\n$TIME:600+10+0\n$MAX_MOVES:256\n$JISHOGI:24\n$NOTE:note\nPI\n+\n+7776FU\nT1.5\n-3334FU\n%MAX_MOVES\n");
+
+		assert_eq!(data.end_state,Some(EndState::MaxMoves));
+	}
+
+	#[test]
+	fn round_trip_pi() {
+		round_trip("V2.2\nPI82HI22KA\n-\n-3334FU\n+7776FU\n%CHUDAN\n");
+	}
+
+	#[test]
+	fn round_trip_board() {
+		round_trip(concat!(
+			"V2.2\n",
+			"P1-KY-KE-GI-KI-OU-KI-GI-KE-KY\n",
+			"P2 * -HI *  *  *  *  * -KA * \n",
+			"P3-FU-FU-FU-FU-FU-FU-FU-FU-FU\n",
+			"P4 *  *  *  *  *  *  *  *  * \n",
+			"P5 *  *  *  *  *  *  *  *  * \n",
+			"P6 *  *  *  *  *  *  *  *  * \n",
+			"P7+FU+FU * +FU+FU+FU+FU+FU+FU\n",
+			"P8 * +KA *  *  *  *  * +HI * \n",
+			"P9+KY+KE+GI+KI+OU+KI+GI+KE+KY\n",
+			"P+00FU\n",
+			"-\n",
+			"-3334FU\n",
+			"+0055FU\n"
+		));
+	}
+
+	#[test]
+	fn version_follows_content_when_missing() {
+		let mut data = CsaParser::parse_str("V3.0\nPI\n+\n+7776FU\n%+FOUL\n").unwrap().remove(0);
+
+		data.version = None;
+
+		assert!(data.to_csa().unwrap().starts_with("V3.0\n"));
+
+		data.end_state = Some(EndState::Toryo);
+
+		assert!(data.to_csa().unwrap().starts_with("V2.2\n"));
+
+		let mut info = KifuInfo::new();
+
+		info.max_moves = Some(256);
+		data.kifu_info = Some(info);
+
+		assert!(data.to_csa().unwrap().starts_with("V3.0\n"));
+
+		data.version = Some(String::from("2.2"));
+
+		assert!(data.to_csa().is_err());
+	}
+}